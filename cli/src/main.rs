@@ -13,29 +13,41 @@ mod localize;
 
 use anyhow::Context;
 use async_std::{
-    fs::OpenOptions,
-    os::unix::fs::OpenOptionsExt,
+    fs::File,
     path::{Path, PathBuf},
 };
 
 use clap::{builder::Arg, ArgAction, ArgMatches, Command};
-use futures::{
-    channel::{mpsc, oneshot},
-    executor, join,
-    prelude::*,
-};
+use futures::{channel::oneshot, executor, join, prelude::*};
 use i18n_embed::DesktopLanguageRequester;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use pbr::{MultiBar, Pipe, ProgressBar, Units};
-use popsicle::{mnt, Progress, Task};
+use popsicle::{
+    codec::Message,
+    events::{self, EventStream},
+    BackedUp, Backup, BackupOutput, Bmap, CancelHandle, Checksum, Compression, DeviceId, DiskError,
+    Erase, Filesystem, FlashEvent, Image, ImageId, Journal, Native, PartitionTable, Phase, Policy,
+    Progress, Rate, Restore, Sysfs, SystemRoot, Task, Validation, Wipe, WipeMethod,
+};
 use std::{
-    io::{self, Write},
-    process, thread,
+    fs::OpenOptions,
+    io::{self, SeekFrom, Write},
+    os::unix::fs::OpenOptionsExt,
+    process,
+    sync::Arc,
+    thread,
+    time::Duration,
 };
 
 static ARG_IMAGE: Lazy<String> = Lazy::new(|| fl!("arg-image"));
 static ARG_DISKS: Lazy<String> = Lazy::new(|| fl!("arg-disks"));
 
+/// How long to wait for udev to create the partitions of each drive once it is flashed.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Cancels the flashing task when the process is interrupted.
+static CANCEL: OnceCell<CancelHandle> = OnceCell::new();
+
 fn main() {
     translate();
     better_panic::install();
@@ -43,42 +55,132 @@ fn main() {
     let matches = Command::new(env!("CARGO_PKG_NAME"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .version(env!("CARGO_PKG_VERSION"))
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
         .arg(Arg::new(&**ARG_IMAGE).help(&fl!("arg-image-desc")).required(true))
-        .arg(Arg::new(&**ARG_DISKS).help(&fl!("arg-disks-desc")))
+        .args(disk_args())
+        .args(flash_args())
         .arg(
-            Arg::new("all")
-                .help(&fl!("arg-all-desc"))
-                .short('a')
-                .long("all")
-                .action(ArgAction::SetTrue),
+            Arg::new("bmap")
+                .help(&fl!("arg-bmap-desc"))
+                .long("bmap")
+                .value_name("BMAP")
+                .conflicts_with("no-bmap"),
         )
         .arg(
-            Arg::new("check")
-                .help(&fl!("arg-check-desc"))
-                .short('c')
-                .long("check")
+            Arg::new("no-bmap")
+                .help(&fl!("arg-no-bmap-desc"))
+                .long("no-bmap")
                 .action(ArgAction::SetTrue),
         )
         .arg(
-            Arg::new("unmount")
-                .help(&fl!("arg-unmount-desc"))
-                .short('u')
-                .long("unmount")
+            Arg::new("precheck")
+                .help(&fl!("arg-precheck-desc"))
+                .long("precheck")
+                .conflicts_with("no-bmap")
                 .action(ArgAction::SetTrue),
         )
         .arg(
-            Arg::new("yes")
-                .help(&fl!("arg-yes-desc"))
-                .short('y')
-                .long("yes")
+            Arg::new("resume")
+                .help(&fl!("arg-resume-desc"))
+                .long("resume")
                 .action(ArgAction::SetTrue),
         )
+        .subcommand(
+            Command::new("wipe")
+                .about(fl!("wipe-desc"))
+                .args(disk_args())
+                .arg(
+                    Arg::new("method")
+                        .help(&fl!("arg-wipe-method-desc"))
+                        .long("method")
+                        .value_name("METHOD")
+                        .value_parser(["discard", "zero", "random"])
+                        .default_value("discard"),
+                )
+                .arg(
+                    Arg::new("check")
+                        .help(&fl!("arg-wipe-check-desc"))
+                        .short('c')
+                        .long("check")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about(fl!("restore-desc"))
+                .args(disk_args())
+                .arg(
+                    Arg::new("table")
+                        .help(&fl!("arg-restore-table-desc"))
+                        .long("table")
+                        .value_name("TABLE")
+                        .value_parser(["mbr", "gpt"])
+                        .default_value("mbr"),
+                )
+                .arg(
+                    Arg::new("filesystem")
+                        .help(&fl!("arg-restore-filesystem-desc"))
+                        .long("filesystem")
+                        .value_name("FILESYSTEM")
+                        .value_parser(["fat32", "exfat"])
+                        .default_value("fat32"),
+                )
+                .arg(
+                    Arg::new("label")
+                        .help(&fl!("arg-restore-label-desc"))
+                        .long("label")
+                        .value_name("LABEL")
+                        .default_value(""),
+                ),
+        )
+        .subcommand(
+            Command::new("clone")
+                .about(fl!("clone-desc"))
+                .arg(Arg::new("source").help(&fl!("arg-clone-source-desc")).required(true))
+                .args(disk_args())
+                .args(flash_args()),
+        )
+        .subcommand(
+            Command::new("backup")
+                .about(fl!("backup-desc"))
+                .arg(Arg::new("disk").help(&fl!("arg-backup-disk-desc")).required(true))
+                .arg(Arg::new("output").help(&fl!("arg-backup-output-desc")).required(true))
+                .arg(
+                    Arg::new("compress")
+                        .help(&fl!("arg-backup-compress-desc"))
+                        .long("compress")
+                        .value_name("FORMAT")
+                        .value_parser(["xz", "zstd"]),
+                )
+                .arg(
+                    Arg::new("trim")
+                        .help(&fl!("arg-backup-trim-desc"))
+                        .long("trim")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("unmount")
+                        .help(&fl!("arg-unmount-desc"))
+                        .short('u')
+                        .long("unmount")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
         .get_matches();
 
     let (rtx, rrx) = oneshot::channel::<anyhow::Result<()>>();
 
     let result = executor::block_on(async move {
-        match popsicle(rtx, matches).await {
+        let started = match matches.subcommand() {
+            Some(("wipe", matches)) => wipe(rtx, matches.clone()).await,
+            Some(("restore", matches)) => restore(rtx, matches.clone()).await,
+            Some(("backup", matches)) => backup(rtx, matches.clone()).await,
+            Some(("clone", matches)) => popsicle(rtx, matches.clone(), true).await,
+            _ => popsicle(rtx, matches, false).await,
+        };
+
+        match started {
             Err(why) => Err(why),
             _ => match rrx.await {
                 Ok(Err(why)) => Err(why),
@@ -93,74 +195,199 @@ fn main() {
             epintln!("    " (fl!("error-caused-by")) ": " (source))
         }
 
-        process::exit(1);
+        let killed = matches!(why.downcast_ref::<DiskError>(), Some(DiskError::Killed));
+        process::exit(if killed { 130 } else { 1 });
     }
 }
 
+/// The arguments which select the disks to write to, and what may be done to them.
+fn disk_args() -> [Arg; 6] {
+    [
+        Arg::new(&**ARG_DISKS).help(&fl!("arg-disks-desc")),
+        Arg::new("all")
+            .help(&fl!("arg-all-desc"))
+            .short('a')
+            .long("all")
+            .action(ArgAction::SetTrue),
+        Arg::new("unmount")
+            .help(&fl!("arg-unmount-desc"))
+            .short('u')
+            .long("unmount")
+            .action(ArgAction::SetTrue),
+        Arg::new("force-dangerous")
+            .help(&fl!("arg-force-dangerous-desc"))
+            .long("force-dangerous")
+            .action(ArgAction::SetTrue),
+        Arg::new("max-size")
+            .help(&fl!("arg-max-size-desc"))
+            .long("max-size")
+            .value_name("GIB")
+            .value_parser(clap::value_parser!(u64)),
+        Arg::new("yes")
+            .help(&fl!("arg-yes-desc"))
+            .short('y')
+            .long("yes")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+/// The arguments which choose how the disks are written to and checked, whether an image
+/// is flashed to them or a drive is cloned to them.
+fn flash_args() -> [Arg; 7] {
+    [
+        Arg::new("check")
+            .help(&fl!("arg-check-desc"))
+            .short('c')
+            .long("check")
+            .action(ArgAction::SetTrue),
+        Arg::new("hash").help(&fl!("arg-hash-desc")).long("hash").action(ArgAction::SetTrue),
+        Arg::new("expect-hash")
+            .help(&fl!("arg-expect-hash-desc"))
+            .long("expect-hash")
+            .value_name("DIGEST")
+            .value_parser(|digest: &str| {
+                Checksum::parse(digest).ok_or_else(|| fl!("error-expected-hash"))
+            }),
+        Arg::new("skip-zeroes")
+            .help(&fl!("arg-skip-zeroes-desc"))
+            .long("skip-zeroes")
+            .requires("erase")
+            .action(ArgAction::SetTrue),
+        Arg::new("erase")
+            .help(&fl!("arg-erase-desc"))
+            .long("erase")
+            .value_name("MODE")
+            .value_parser(["discard", "zero"]),
+        Arg::new("delta").help(&fl!("arg-delta-desc")).long("delta").action(ArgAction::SetTrue),
+        Arg::new("eject").help(&fl!("arg-eject-desc")).long("eject").action(ArgAction::SetTrue),
+    ]
+}
+
+/// Flashes the image to the disks, or when `clone` is set, clones the source drive to them.
 async fn popsicle(
     rtx: oneshot::Sender<anyhow::Result<()>>,
     matches: ArgMatches,
+    clone: bool,
 ) -> anyhow::Result<()> {
-    let image_path = matches
-        .get_one::<String>(&fl!("arg-image"))
-        .with_context(|| fl!("error-image-not-set"))?
-        .clone();
-
-    let image = OpenOptions::new()
-        .custom_flags(libc::O_SYNC)
-        .read(true)
-        .open(&image_path)
-        .await
-        .with_context(|| fl!("error-image-open", image_path = image_path.clone()))?;
+    // A clone reads the source drive as a raw image, which is never written to.
+    let (image, image_path, master) = if clone {
+        let source = matches.get_one::<String>("source").map(PathBuf::from).unwrap();
+        let (master, file) = popsicle::source_from_arg(source.into(), matches.get_flag("unmount"))
+            .await
+            .with_context(|| fl!("error-opening-source"))?;
 
-    let image_size = image
-        .metadata()
-        .await
-        .map(|x| x.len())
-        .with_context(|| fl!("error-image-metadata", image_path = image_path.clone()))?;
+        let image_path = master.display().to_string();
+        let image = Image::from_device(file)
+            .with_context(|| fl!("error-image-metadata", image_path = image_path.clone()))?;
 
-    let mut disk_args = Vec::new();
-    if matches.get_flag("all") {
-        popsicle::usb_disk_devices(&mut disk_args)
-            .await
-            .with_context(|| fl!("error-disks-fetch"))?;
-    } else if let Some(disks) = matches.get_many::<String>(&fl!("arg-disks")) {
-        disk_args.extend(disks.map(PathBuf::from).map(Box::from));
-    }
+        (image, image_path, Some(master))
+    } else {
+        let image_path = matches
+            .get_one::<String>(&fl!("arg-image"))
+            .with_context(|| fl!("error-image-not-set"))?
+            .clone();
 
-    if disk_args.is_empty() {
-        return Err(anyhow!(fl!("error-no-disks-specified")));
-    }
+        let image = OpenOptions::new()
+            .custom_flags(libc::O_SYNC)
+            .read(true)
+            .open(&image_path)
+            .with_context(|| fl!("error-image-open", image_path = image_path.clone()))?;
 
-    let mounts = mnt::get_submounts(Path::new("/")).with_context(|| fl!("error-reading-mounts"))?;
+        let mut image = Image::new(image)
+            .with_context(|| fl!("error-image-metadata", image_path = image_path.clone()))?;
 
-    let disks =
-        popsicle::disks_from_args(disk_args.into_iter(), &mounts, matches.get_flag("unmount"))
-            .await
-            .with_context(|| fl!("error-opening-disks"))?;
+        // A block map next to the image is used unless one is given, or they are disabled.
+        let bmap_path = match matches.get_one::<String>("bmap") {
+            Some(path) => Some(std::path::PathBuf::from(path)),
+            None if matches.get_flag("no-bmap") => None,
+            None => Bmap::find(std::path::Path::new(&image_path)),
+        };
 
-    let is_tty = atty::is(atty::Stream::Stdout);
+        if let Some(path) = bmap_path {
+            let bmap_path = path.display().to_string();
+            let bmap = Bmap::open(&path)
+                .with_context(|| fl!("error-bmap-open", bmap_path = bmap_path.clone()))?;
 
-    if is_tty && !matches.get_flag("yes") {
-        epint!(
-            (fl!("question", image_path = image_path)) "\n"
-            for (path, _) in &disks {
-                " - " (path.display()) "\n"
-            }
-            (fl!("yn")) ": "
-        );
+            image
+                .set_bmap(bmap)
+                .with_context(|| fl!("error-bmap-open", bmap_path = bmap_path.clone()))?;
+
+            epintln!((fl!("bmap-found", bmap_path = bmap_path)));
+        }
+
+        (image, image_path, None)
+    };
 
-        io::stdout().flush().unwrap();
+    let image_size = image.size();
 
-        let mut confirm = String::new();
-        io::stdin().read_line(&mut confirm).unwrap();
+    let mut disks = open_disks(&matches, master.as_deref()).await?;
 
-        if confirm.trim() != fl!("y") && confirm.trim() != "yes" {
-            return Err(anyhow!(fl!("error-exiting")));
+    // Compare against the decompressed size of the image before anything is written. When
+    // the image does not record it, progress is measured against each disk instead.
+    let mut sizes = Vec::new();
+    for (path, disk) in &mut disks {
+        let size = disk_size(path, disk).await?;
+        if image_size.is_some_and(|image_size| size < image_size) {
+            return Err(anyhow!(fl!(
+                "error-disk-too-small",
+                disk_path = path.display().to_string(),
+                image_path = image_path.clone()
+            )));
         }
+
+        sizes.push(image_size.unwrap_or(size));
     }
 
-    let check = matches.get_flag("check");
+    if image_size.is_none() {
+        epintln!((fl!("image-size-unknown", image_path = image_path.clone())));
+    }
+
+    let is_tty = atty::is(atty::Stream::Stdout);
+
+    if is_tty && !matches.get_flag("yes") {
+        let question = match clone {
+            true => fl!("question-clone", source_path = image_path.clone()),
+            false => fl!("question", image_path = image_path.clone()),
+        };
+
+        confirm(&question, &disks)?;
+    }
+
+    let expected = matches.get_one::<(Checksum, Vec<u8>)>("expect-hash").cloned();
+    let check = matches.get_flag("check") || matches.get_flag("hash") || expected.is_some();
+    let validation = match expected {
+        Some((checksum, expected)) => Validation::Hash { checksum, expected: Some(expected) },
+        None if matches.get_flag("hash") => {
+            Validation::Hash { checksum: Checksum::Sha256, expected: None }
+        }
+        None => Validation::Compare,
+    };
+    let erase = matches.get_one::<String>("erase").map(|mode| match mode.as_str() {
+        "discard" => Erase::Discard,
+        _ => Erase::ZeroFill,
+    });
+
+    // How far each disk is written is always recorded, so that any flash which is
+    // interrupted may be resumed. The contents of a source drive may change, so clones are
+    // neither recorded nor resumed.
+    let resume = !clone && matches.get_flag("resume");
+    let journal = match clone {
+        true => None,
+        false => Journal::default_path()
+            .and_then(|path| Journal::open(path).ok())
+            .zip(ImageId::new(std::path::Path::new(&image_path)).ok()),
+    };
+
+    let options = Options {
+        validation,
+        skip_zeroes: matches.get_flag("skip-zeroes"),
+        erase,
+        precheck: matches.get_flag("precheck"),
+        delta: matches.get_flag("delta"),
+        eject: matches.get_flag("eject"),
+        resume,
+        journal,
+    };
 
     // If this is a TTY, display a progress bar. If not, display machine-readable info.
     if is_tty {
@@ -168,15 +395,20 @@ async fn popsicle(
 
         let mb = MultiBar::new();
         let mut task = Task::new(image, check);
+        configure_task(&mut task, options);
+        cancel_on_interrupt(&task.cancel);
 
-        for (disk_path, disk) in disks {
+        for ((disk_path, disk), size) in disks.into_iter().zip(sizes) {
             let pb = InteractiveProgress::new(cascade! {
-                mb.create_bar(image_size);
+                mb.create_bar(size);
                 ..set_units(Units::Bytes);
-                ..message(&format!("W {}: ", disk_path.display()));
+                // The library's own measurements are shown in the message instead.
+                ..show_speed = false;
+                ..show_time_left = false;
+                ..message(&format!("{} {}: ", phase_label(Phase::Writing), disk_path.display()));
             });
 
-            task.subscribe(disk, disk_path, pb);
+            subscribe(&mut task, disk, disk_path, pb);
         }
 
         thread::spawn(|| {
@@ -188,116 +420,641 @@ async fn popsicle(
 
         mb.listen();
     } else {
-        let (etx, erx) = mpsc::unbounded();
         let mut paths = Vec::new();
         let mut task = Task::new(image, check);
+        configure_task(&mut task, options);
+        cancel_on_interrupt(&task.cancel);
 
         for (disk_path, disk) in disks {
-            let pb = MachineProgress::new(paths.len(), etx.clone());
             paths.push(disk_path.clone());
-            task.subscribe(disk, disk_path, pb);
+            let pb = task.event_progress();
+            subscribe(&mut task, disk, disk_path, pb);
         }
 
-        drop(etx);
-
+        let events = task.events().expect("events of the task were taken");
         let task = async move {
             let buf = &mut [0u8; 64 * 1024];
             let _ = rtx.send(task.process(buf).await);
         };
 
-        join!(machine_output(erx, &paths, image_size), task);
+        let largest = sizes.into_iter().max().unwrap_or(0);
+        join!(machine_output(events, &paths, largest), task);
     }
 
     Ok(())
 }
 
-/// An event for creating a machine-readable output
-pub enum Event {
-    Message(usize, Box<str>),
-    Finished(usize),
-    Set(usize, u64),
+/// How the disks are written to and checked by a flash, or a clone.
+struct Options {
+    validation: Validation,
+    skip_zeroes: bool,
+    erase: Option<Erase>,
+    precheck: bool,
+    delta: bool,
+    eject: bool,
+    resume: bool,
+    journal: Option<(Journal, ImageId)>,
 }
 
-/// Tracks progress
-#[derive(new)]
-pub struct MachineProgress {
-    id: usize,
+/// Applies the options to the task, whichever way its progress is reported.
+fn configure_task<P: Progress>(task: &mut Task<P>, options: Options) {
+    task.validation = options.validation;
+    task.skip_zeroes = options.skip_zeroes;
+    task.erase = options.erase;
+    task.precheck = options.precheck;
+    task.delta = options.delta;
+    task.resume = options.resume;
+    task.settle = Some(SETTLE_TIMEOUT);
 
-    handle: mpsc::UnboundedSender<Event>,
+    // Ejected disks are powered off, so there is no use in reading their partitions.
+    task.reread = !options.eject;
+    if options.eject {
+        task.eject = Some(Arc::new(Sysfs::default()));
+    }
+
+    if let Some((journal, image)) = options.journal {
+        task.journal(journal, image);
+    }
 }
 
-impl Progress for MachineProgress {
-    type Device = Box<Path>;
+/// Wipes the disks, rather than flashing an image to them.
+async fn wipe(rtx: oneshot::Sender<anyhow::Result<()>>, matches: ArgMatches) -> anyhow::Result<()> {
+    let mut disks = open_disks(&matches, None).await?;
 
-    fn message(&mut self, _path: &Box<Path>, kind: &str, message: &str) {
-        let _ = self.handle.unbounded_send(Event::Message(
-            self.id,
-            if message.is_empty() { kind.into() } else { [kind, " ", message].concat().into() },
-        ));
+    let mut sizes = Vec::new();
+    for (path, disk) in &mut disks {
+        sizes.push(disk_size(path, disk).await?);
     }
 
-    fn finish(&mut self) {
-        let _ = self.handle.unbounded_send(Event::Finished(self.id));
+    let is_tty = atty::is(atty::Stream::Stdout);
+
+    if is_tty && !matches.get_flag("yes") {
+        confirm(&fl!("question-wipe"), &disks)?;
     }
 
-    fn set(&mut self, written: u64) {
-        let _ = self.handle.unbounded_send(Event::Set(self.id, written));
+    let method = match matches.get_one::<String>("method").map(String::as_str) {
+        Some("zero") => WipeMethod::Zeroes,
+        Some("random") => WipeMethod::Random,
+        _ => WipeMethod::Discard,
+    };
+
+    let check = matches.get_flag("check");
+
+    // If this is a TTY, display a progress bar. If not, display machine-readable info.
+    if is_tty {
+        println!();
+
+        let mb = MultiBar::new();
+        let mut wipe = Wipe::new(method, check);
+        cancel_on_interrupt(&wipe.cancel);
+
+        for ((disk_path, disk), size) in disks.into_iter().zip(sizes) {
+            let pb = InteractiveProgress::new(cascade! {
+                mb.create_bar(size);
+                ..set_units(Units::Bytes);
+                ..show_speed = false;
+                ..show_time_left = false;
+                ..message(&format!("{} {}: ", phase_label(Phase::Erasing), disk_path.display()));
+            });
+
+            wipe.subscribe(disk, disk_path, pb);
+        }
+
+        thread::spawn(|| {
+            executor::block_on(async move {
+                let _ = rtx.send(wipe.process().await);
+            })
+        });
+
+        mb.listen();
+    } else {
+        let (sender, events) = events::channel();
+        let mut paths = Vec::new();
+        let mut wipe = Wipe::new(method, check);
+        cancel_on_interrupt(&wipe.cancel);
+
+        for (disk_path, disk) in disks {
+            paths.push(disk_path.clone());
+            wipe.subscribe(disk, disk_path, sender.progress());
+        }
+
+        drop(sender);
+
+        let task = async move {
+            let _ = rtx.send(wipe.process().await);
+        };
+
+        // Progress is reported against the largest of the disks.
+        let largest = sizes.into_iter().max().unwrap_or(0);
+        join!(machine_output(events, &paths, largest), task);
     }
+
+    Ok(())
 }
 
-#[derive(new)]
-pub struct InteractiveProgress {
-    pipe: ProgressBar<Pipe>,
+/// Restores the disks to empty drives, with a single partition of a fresh filesystem.
+async fn restore(
+    rtx: oneshot::Sender<anyhow::Result<()>>,
+    matches: ArgMatches,
+) -> anyhow::Result<()> {
+    let table = match matches.get_one::<String>("table").map(String::as_str) {
+        Some("gpt") => PartitionTable::Gpt,
+        _ => PartitionTable::Mbr,
+    };
+
+    let filesystem = match matches.get_one::<String>("filesystem").map(String::as_str) {
+        Some("exfat") => Filesystem::Exfat,
+        _ => Filesystem::Fat32,
+    };
+
+    // The label is checked before any disk is opened.
+    let label = matches.get_one::<String>("label").cloned().unwrap_or_default();
+    filesystem.check_label(&label)?;
+
+    let disks = open_disks(&matches, None).await?;
+
+    let is_tty = atty::is(atty::Stream::Stdout);
+
+    if is_tty && !matches.get_flag("yes") {
+        confirm(&fl!("question-restore", filesystem = filesystem.to_string()), &disks)?;
+    }
+
+    // If this is a TTY, display a progress bar. If not, display machine-readable info.
+    if is_tty {
+        println!();
+
+        // Restoring reports its phases, but not its progress through them.
+        let mb = MultiBar::new();
+        let mut restore = Restore::new(table, filesystem, label);
+        restore.settle = Some(SETTLE_TIMEOUT);
+        cancel_on_interrupt(&restore.cancel);
+
+        for (disk_path, disk) in disks {
+            let pb = InteractiveProgress::new(cascade! {
+                mb.create_bar(1);
+                ..show_counter = false;
+                ..show_speed = false;
+                ..show_time_left = false;
+                ..message(&format!("{} {}: ", phase_label(Phase::Erasing), disk_path.display()));
+            });
+
+            restore.subscribe(disk, disk_path, pb);
+        }
+
+        thread::spawn(|| {
+            executor::block_on(async move {
+                let _ = rtx.send(restore.process().await);
+            })
+        });
+
+        mb.listen();
+    } else {
+        let (sender, events) = events::channel();
+        let mut paths = Vec::new();
+        let mut restore = Restore::new(table, filesystem, label);
+        restore.settle = Some(SETTLE_TIMEOUT);
+        cancel_on_interrupt(&restore.cancel);
+
+        for (disk_path, disk) in disks {
+            paths.push(disk_path.clone());
+            restore.subscribe(disk, disk_path, sender.progress());
+        }
+
+        drop(sender);
+
+        let task = async move {
+            let _ = rtx.send(restore.process().await);
+        };
+
+        join!(machine_output(events, &paths, 0), task);
+    }
+
+    Ok(())
 }
 
-impl Progress for InteractiveProgress {
-    type Device = Box<Path>;
+/// Backs up a disk to an image, saving the block map and the digest of the image next to it.
+async fn backup(
+    rtx: oneshot::Sender<anyhow::Result<()>>,
+    matches: ArgMatches,
+) -> anyhow::Result<()> {
+    let disk_path: Box<Path> = matches.get_one::<String>("disk").map(PathBuf::from).unwrap().into();
+    let disk_path_arg = disk_path.clone();
+    let output_path = matches.get_one::<String>("output").map(std::path::PathBuf::from).unwrap();
 
-    fn message(&mut self, path: &Box<Path>, kind: &str, message: &str) {
-        self.pipe.message(&format!("{} {}: {}", kind, path.display(), message));
+    let (disk_path, disk) = popsicle::source_from_arg(disk_path, matches.get_flag("unmount"))
+        .await
+        .with_context(|| fl!("error-disk-open", disk_path = disk_path_arg.display().to_string()))?;
+
+    let mut disk = File::from(disk);
+    let size = disk_size(&disk_path, &mut disk).await?;
+
+    // An existing image is never overwritten, and the image only takes its path once it
+    // has been saved, so that a failed backup leaves nothing behind.
+    let (output, image) = match output_path.symlink_metadata() {
+        Ok(_) => Err(io::Error::from(io::ErrorKind::AlreadyExists)),
+        Err(_) => BackupOutput::create(&output_path),
     }
+    .with_context(|| fl!("error-backup-create", image_path = output_path.display().to_string()))?;
+
+    let is_tty = atty::is(atty::Stream::Stdout);
 
-    fn finish(&mut self) {
-        self.pipe.finish();
+    // If this is a TTY, display a progress bar. If not, display machine-readable info.
+    if is_tty {
+        println!();
+
+        let mb = MultiBar::new();
+        let pb = InteractiveProgress::new(cascade! {
+            mb.create_bar(size);
+            ..set_units(Units::Bytes);
+            ..show_speed = false;
+            ..show_time_left = false;
+            ..message(&format!("{} {}: ", phase_label(Phase::Reading), disk_path.display()));
+        });
+
+        let mut backup = Backup::new(disk, disk_path, pb);
+        configure_backup(&mut backup, &matches);
+
+        let (btx, brx) = oneshot::channel();
+        thread::spawn(|| {
+            executor::block_on(async move {
+                let _ = btx.send(backup.process(image.into()).await);
+            })
+        });
+
+        mb.listen();
+
+        // The digest is shown once the progress bar has finished.
+        let result = brx.await.unwrap_or_else(|_| Err(anyhow!(fl!("error-backup-stopped"))));
+        let _ = rtx
+            .send(result.and_then(|backed_up| save_backup(output, &backed_up, &output_path, true)));
+    } else {
+        let (sender, events) = events::channel();
+        let paths = [disk_path.clone()];
+        let mut backup = Backup::new(disk, disk_path, sender.progress());
+        configure_backup(&mut backup, &matches);
+
+        let task = async move {
+            let result = backup.process(image.into()).await;
+            let _ = rtx.send(
+                result.and_then(|backed_up| save_backup(output, &backed_up, &output_path, false)),
+            );
+        };
+
+        join!(machine_output(events, &paths, size), task);
     }
 
-    fn set(&mut self, written: u64) {
-        self.pipe.set(written);
+    Ok(())
+}
+
+fn configure_backup<P: Progress>(backup: &mut Backup<P>, matches: &ArgMatches) {
+    backup.compression = match matches.get_one::<String>("compress").map(String::as_str) {
+        Some("xz") => Compression::Xz,
+        Some("zstd") => Compression::Zstd,
+        _ => Compression::None,
+    };
+
+    backup.trim = matches.get_flag("trim");
+    cancel_on_interrupt(&backup.cancel);
+}
+
+/// Saves the block map and the digest next to the image, and shows the digest if asked to.
+fn save_backup(
+    output: BackupOutput,
+    backed_up: &BackedUp,
+    image: &std::path::Path,
+    show: bool,
+) -> anyhow::Result<()> {
+    let image_path = image.display().to_string();
+    output.save(backed_up).with_context(|| fl!("error-backup-save", image_path = image_path))?;
+
+    if show {
+        let digest: String = backed_up.sha256.iter().map(|byte| format!("{:02x}", byte)).collect();
+        println!("{}", fl!("backup-digest", digest = digest));
     }
+
+    Ok(())
 }
 
-/// Writes a machine-friendly output, when this program is being piped into another.
-async fn machine_output(
-    mut rx: mpsc::UnboundedReceiver<Event>,
-    paths: &[Box<Path>],
-    image_size: u64,
-) {
-    let stdout = io::stdout();
-    let stdout = &mut stdout.lock();
+/// Opens the disks which were given, or every USB disk, refusing those which are dangerous
+/// to write to unless forced.
+async fn open_disks(
+    matches: &ArgMatches,
+    master: Option<&Path>,
+) -> anyhow::Result<Vec<(Box<Path>, File)>> {
+    let mut disk_args = Vec::new();
+    if matches.get_flag("all") {
+        popsicle::usb_disk_devices(&mut disk_args)
+            .await
+            .with_context(|| fl!("error-disks-fetch"))?;
 
-    let _ = wite!(
-        stdout,
-        "Size(" (image_size) ")\n"
-        for path in paths {
-            "Device(\"" (path.display()) "\")\n"
+        // The source of a clone is plugged in alongside the disks which it is cloned to.
+        if let Some(master) = master {
+            let mut others = Vec::new();
+            for disk in disk_args {
+                if async_std::fs::canonicalize(&disk).await.ok().as_deref() != Some(master) {
+                    others.push(disk);
+                }
+            }
+
+            disk_args = others;
         }
+    } else if let Some(disks) = matches.get_many::<String>(&fl!("arg-disks")) {
+        disk_args.extend(disks.map(PathBuf::from).map(Box::from));
+    }
+
+    if disk_args.is_empty() {
+        return Err(anyhow!(fl!("error-no-disks-specified")));
+    }
+
+    // System disks, and those which do not look like removable media, must be forced.
+    let mut policy = match matches.get_flag("force-dangerous") {
+        true => Policy::unrestricted(),
+        false => Policy {
+            max_size: matches.get_one::<u64>("max-size").map(|gib| gib * 1024 * 1024 * 1024),
+            ..Policy::default()
+        },
+    };
+
+    // The source of a clone is never written to, even when forced.
+    policy.protected.extend(master.map(|master| std::path::PathBuf::from(master.as_os_str())));
+
+    let unmount = matches.get_flag("unmount");
+    let root = SystemRoot::default();
+    if !matches.get_flag("all") {
+        return popsicle::disks_from_args_in(
+            &root,
+            &policy,
+            &mut Native,
+            disk_args.into_iter(),
+            unmount,
+        )
+        .await
+        .with_context(|| fl!("error-opening-disks"));
+    }
+
+    // Every drive that was found is offered, so those which the policy refuses are passed
+    // over rather than keeping the others from being written to.
+    let mut disks = Vec::new();
+    for disk_arg in disk_args {
+        let disk = std::iter::once(disk_arg.clone());
+        match popsicle::disks_from_args_in(&root, &policy, &mut Native, disk, unmount).await {
+            Ok(opened) => disks.extend(opened),
+            Err(why) if why.is_refused() => {
+                epintln!(
+                    (fl!(
+                        "disk-skipped",
+                        disk_path = disk_arg.display().to_string(),
+                        why = why.to_string()
+                    ))
+                );
+            }
+            Err(why) => return Err(why).with_context(|| fl!("error-opening-disks")),
+        }
+    }
+
+    if disks.is_empty() {
+        return Err(anyhow!(fl!("error-no-disks-permitted")));
+    }
+
+    Ok(disks)
+}
+
+/// The size of the disk, leaving it positioned at its start.
+async fn disk_size(path: &Path, disk: &mut File) -> anyhow::Result<u64> {
+    let disk_path = path.display().to_string();
+
+    let disk_size = disk
+        .seek(SeekFrom::End(0))
+        .await
+        .with_context(|| fl!("error-disk-size", disk_path = disk_path.clone()))?;
+
+    disk.seek(SeekFrom::Start(0))
+        .await
+        .with_context(|| fl!("error-disk-size", disk_path = disk_path.clone()))?;
+
+    Ok(disk_size)
+}
+
+/// Asks whether to go ahead with writing to the disks.
+fn confirm(question: &str, disks: &[(Box<Path>, File)]) -> anyhow::Result<()> {
+    epint!(
+        (question) "\n"
+        for (path, _) in disks {
+            " - " (path.display()) "\n"
+        }
+        (fl!("yn")) ": "
     );
 
-    while let Some(event) = rx.next().await {
+    io::stdout().flush().unwrap();
+
+    let mut confirm = String::new();
+    io::stdin().read_line(&mut confirm).unwrap();
+
+    if confirm.trim() != fl!("y") && confirm.trim() != "yes" {
+        return Err(anyhow!(fl!("error-exiting")));
+    }
+
+    Ok(())
+}
+
+/// Subscribes the disk, identifying it by its serial number and size when it has one, so
+/// that it may be resumed.
+fn subscribe<P: Progress<Device = Box<Path>>>(
+    task: &mut Task<P>,
+    disk: File,
+    path: Box<Path>,
+    pb: P,
+) {
+    match DeviceId::from_block_device(std::path::Path::new(path.as_os_str())) {
+        Ok(identity) => task.subscribe_identified(disk, path, pb, identity),
+        Err(_) => task.subscribe(disk, path, pb),
+    }
+}
+
+/// Cancels the task on the first SIGINT or SIGTERM, leaving the second to terminate the process.
+fn cancel_on_interrupt(cancel: &CancelHandle) {
+    extern "C" fn interrupted(signal: libc::c_int) {
+        if let Some(cancel) = CANCEL.get() {
+            cancel.cancel();
+        }
+
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+    }
+
+    let _ = CANCEL.set(cancel.clone());
+
+    unsafe {
+        libc::signal(libc::SIGINT, interrupted as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, interrupted as libc::sighandler_t);
+    }
+}
+
+#[derive(new)]
+pub struct InteractiveProgress {
+    pipe: ProgressBar<Pipe>,
+
+    #[new(value = "Phase::Writing")]
+    phase: Phase,
+
+    #[new(default)]
+    rewritten: Option<u64>,
+
+    #[new(default)]
+    partitions: Vec<std::path::PathBuf>,
+
+    /// Whether udev was still setting up the partitions when it was no longer waited for.
+    #[new(default)]
+    unsettled: bool,
+
+    #[new(default)]
+    ejected: Option<Result<(), String>>,
+}
+
+impl Progress for InteractiveProgress {
+    type Device = Box<Path>;
+
+    fn event(&mut self, path: &Box<Path>, event: FlashEvent) {
+        let path = path.display();
         match event {
-            Event::Message(id, message) => {
-                let _ = witeln!(stdout, "Message(\"" (paths[id].display()) "\",\"" (message) "\")");
+            FlashEvent::Phase(phase) => {
+                self.phase = phase;
+                self.pipe.set(0);
+                self.pipe.message(&format!("{} {}: ", phase_label(phase), path));
+            }
+            FlashEvent::Failed(why) => {
+                self.pipe.message(&format!("{} {}: {}", fl!("event-failed"), path, why));
+                self.pipe.finish();
+            }
+            FlashEvent::Cancelled => {
+                self.pipe.message(&format!("{} {}: ", fl!("event-cancelled"), path));
+                self.pipe.finish();
+            }
+            FlashEvent::Done => {
+                let mut details = Vec::new();
+                if !self.partitions.is_empty() {
+                    let partitions = self
+                        .partitions
+                        .iter()
+                        .map(|partition| partition.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+
+                    details.push(fl!("event-partitions", partitions = partitions));
+                    if self.unsettled {
+                        details.push(fl!("event-unsettled"));
+                    }
+                }
+
+                match self.ejected.take() {
+                    Some(Ok(())) => details.push(fl!("event-ejected")),
+                    Some(Err(why)) => details.push(fl!("event-eject-failed", why = why)),
+                    None => (),
+                }
+
+                if self.rewritten.is_some() || !details.is_empty() {
+                    let label = match self.rewritten {
+                        Some(bytes) => fl!("event-rewritten", bytes = size_label(bytes)),
+                        None => fl!("event-finished"),
+                    };
+
+                    let mut label = format!("{} {}", label, path);
+                    if !details.is_empty() {
+                        label = format!("{} ({})", label, details.join("; "));
+                    }
+
+                    self.pipe.message(&format!("{}: ", label));
+                }
+
+                self.pipe.finish();
             }
-            Event::Finished(id) => {
-                let _ = witeln!(stdout, "Finished(\"" (paths[id].display()) "\")");
+            FlashEvent::Rewritten(bytes) => self.rewritten = Some(bytes),
+            FlashEvent::Partitions { partitions, timed_out } => {
+                self.partitions = partitions;
+                self.unsettled = timed_out;
             }
-            Event::Set(id, written) => {
-                let _ = witeln!(stdout, "Set(\"" (paths[id].display()) "\"," (written) ")");
+            FlashEvent::Ejected => self.ejected = Some(Ok(())),
+            FlashEvent::EjectFailed(why) => self.ejected = Some(Err(why.to_string())),
+            FlashEvent::Progress { position, rate } => {
+                let label = rate_label(&rate);
+                self.pipe.message(&format!("{} {} ({}): ", phase_label(self.phase), path, label));
+                self.pipe.set(position);
             }
         }
     }
 }
 
+/// Writes a machine-friendly output, when this program is being piped into another.
+async fn machine_output(mut events: EventStream<Box<Path>>, paths: &[Box<Path>], image_size: u64) {
+    let stdout = io::stdout();
+    let stdout = &mut stdout.lock();
+
+    let path = |path: &Path| std::path::PathBuf::from(path.as_os_str());
+
+    let mut messages = vec![Message::Size(image_size)];
+    messages.extend(paths.iter().map(|disk_path| Message::Device(path(disk_path))));
+    for message in messages {
+        let _ = stdout.write_all(message.encode().as_bytes());
+    }
+
+    while let Some((disk_path, event)) = events.next().await {
+        let path = path(&disk_path);
+        let message = match event {
+            FlashEvent::Phase(phase) => Message::Phase(path, phase),
+            FlashEvent::Failed(why) => Message::Failed(path, why.to_string()),
+            FlashEvent::Cancelled => Message::Cancelled(path),
+            FlashEvent::Done => Message::Finished(path),
+            FlashEvent::Rewritten(bytes) => Message::Rewritten(path, bytes),
+            FlashEvent::Partitions { partitions, .. } => Message::Partitions(path, partitions),
+            FlashEvent::Ejected => Message::Ejected(path),
+            FlashEvent::EjectFailed(why) => Message::EjectFailed(path, why.to_string()),
+            FlashEvent::Progress { position, rate } => Message::Set(path, position, rate),
+        };
+
+        let _ = stdout.write_all(message.encode().as_bytes());
+    }
+}
+
+/// The throughput of a device, and the time that its phase has left.
+fn rate_label(rate: &Rate) -> String {
+    let mut label = format!("{}/s", size_label(rate.bytes_per_second));
+    if let Some(eta) = rate.eta {
+        let seconds = eta.as_secs();
+        label.push_str(&format!(", {}:{:02}", seconds / 60, seconds % 60));
+    }
+
+    label
+}
+
+/// A number of bytes, in the largest binary unit that it has at least one of.
+fn size_label(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn phase_label(phase: Phase) -> String {
+    match phase {
+        Phase::Erasing => fl!("phase-erasing"),
+        Phase::Writing => fl!("phase-writing"),
+        Phase::Syncing => fl!("phase-syncing"),
+        Phase::Seeking => fl!("phase-seeking"),
+        Phase::Verifying => fl!("phase-verifying"),
+        Phase::Rereading => fl!("phase-rereading"),
+        Phase::Ejecting => fl!("phase-ejecting"),
+        Phase::Reading => fl!("phase-reading"),
+        Phase::Checking => fl!("phase-checking"),
+    }
+}
+
 fn translate() {
     let requested_languages = DesktopLanguageRequester::requested_languages();
     let localizer = crate::localize::localizer();
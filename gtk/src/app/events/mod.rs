@@ -1,23 +1,26 @@
-use crate::flash::{FlashError, FlashRequest};
+use crate::flash::{FlashError, FlashRequest, Flashed};
 use crate::hash::hasher;
 
 use blake2::Blake2b512;
 use crossbeam_channel::{Receiver, Sender};
 use dbus_udisks2::{DiskDevice, Disks, UDisks2};
 use md5::Md5;
+use popsicle::{Image, ImageId};
 use sha1::Sha1;
 use sha2::Sha256;
 use sha2::Sha512;
 use std::collections::HashMap;
+use std::fs::File;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 
-pub type FlashResult = anyhow::Result<(anyhow::Result<()>, Vec<Result<(), FlashError>>)>;
+/// The result of the flash, followed by that of each device.
+pub type FlashResult = anyhow::Result<(anyhow::Result<()>, Vec<Result<Flashed, FlashError>>)>;
 
 pub enum UiEvent {
-    SetImageLabel(PathBuf),
+    SetImageLabel(PathBuf, Option<u64>, Option<ImageId>),
     RefreshDevices(Box<[Arc<DiskDevice>]>),
     SetHash(io::Result<String>),
     Flash(JoinHandle<FlashResult>),
@@ -26,6 +29,7 @@ pub enum UiEvent {
 
 pub enum BackgroundEvent {
     GenerateHash(PathBuf, &'static str),
+    SizeImage(PathBuf),
     Flash(FlashRequest),
     RefreshDevices,
 }
@@ -66,6 +70,13 @@ pub fn background_thread(events_tx: Sender<UiEvent>, events_rx: Receiver<Backgro
                     // Send this result back to the main thread.
                     let _ = events_tx.send(UiEvent::SetHash(result));
                 }
+                Ok(BackgroundEvent::SizeImage(path)) => {
+                    // Sizing a compressed image may read the header of each of its blocks,
+                    // and identifying it hashes the start of it.
+                    let size = image_size(&path);
+                    let image_id = ImageId::new(&path).ok();
+                    let _ = events_tx.send(UiEvent::SetImageLabel(path, size, image_id));
+                }
                 Ok(BackgroundEvent::RefreshDevices) => {
                     // Fetch the current list of USB devices from popsicle.
                     match refresh_devices() {
@@ -94,6 +105,15 @@ pub fn background_thread(events_tx: Sender<UiEvent>, events_rx: Receiver<Backgro
     });
 }
 
+/// The size that the image expands to on the device, which is not known for compressed
+/// images that do not record it.
+fn image_size(path: &Path) -> Option<u64> {
+    let file = File::open(path).ok()?;
+    file.try_clone()
+        .and_then(Image::new)
+        .map_or_else(|_| file.metadata().ok().map(|m| m.len()), |i| i.size())
+}
+
 fn refresh_devices() -> anyhow::Result<Box<[Arc<DiskDevice>]>> {
     let udisks = UDisks2::new()?;
     let devices = Disks::new(&udisks).devices;
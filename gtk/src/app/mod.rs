@@ -10,7 +10,9 @@ use self::views::*;
 use self::widgets::*;
 
 use crate::fl;
+use crate::flash::FlashSource;
 use gtk::{self, prelude::*};
+use popsicle::{BackupOutput, Bmap, Compression};
 use std::{fs::File, process, rc::Rc, sync::Arc};
 
 const CSS: &str = include_str!("ui.css");
@@ -37,6 +39,7 @@ impl App {
         self.connect_image_chooser();
         self.connect_image_drag_and_drop();
         self.connect_hash();
+        self.connect_modes();
         self.connect_view_ready();
 
         self
@@ -153,15 +156,70 @@ impl GtkUi {
                 &self.content.devices_view.view.container
             }
             ActiveView::Flashing => {
-                match self.errorck(
-                    state,
-                    File::open(&*state.image_path.borrow()),
-                    &fl!("iso-open-failed"),
-                ) {
-                    Ok(file) => *state.image.borrow_mut() = Some(file),
-                    Err(()) => return,
+                let devices_view = &self.content.devices_view;
+                let source = match state.mode.get() {
+                    Mode::Wipe => FlashSource::Wipe(devices_view.wipe_method()),
+                    Mode::Restore => {
+                        let (table, filesystem, label) = devices_view.restore_options();
+                        FlashSource::Restore { table, filesystem, label }
+                    }
+                    Mode::Backup => {
+                        let (compression, trim) = devices_view.backup_options();
+                        let name = match compression {
+                            Compression::Xz => "backup.img.xz",
+                            Compression::Zstd => "backup.img.zst",
+                            _ => "backup.img",
+                        };
+
+                        // The device stays selected if no image is chosen to back it up to.
+                        let path = match SaveDialog::new(name).run() {
+                            Some(path) => path,
+                            None => return,
+                        };
+
+                        // The image only replaces whatever is at the path once it is saved.
+                        let (output, image) = match self.errorck(
+                            state,
+                            BackupOutput::create(&path),
+                            &fl!("backup-create-failed"),
+                        ) {
+                            Ok(created) => created,
+                            Err(()) => return,
+                        };
+
+                        FlashSource::Backup { image, output, compression, trim }
+                    }
+                    Mode::Flash => {
+                        let file = match self.errorck(
+                            state,
+                            File::open(&*state.image_path.borrow()),
+                            &fl!("iso-open-failed"),
+                        ) {
+                            Ok(file) => file,
+                            Err(()) => return,
+                        };
+
+                        // Only the ranges in a block map beside the image need to be written.
+                        *state.bmap.borrow_mut() = match Bmap::find(&state.image_path.borrow()) {
+                            Some(path) => {
+                                match self.errorck(
+                                    state,
+                                    Bmap::open(&path),
+                                    &fl!("bmap-open-failed"),
+                                ) {
+                                    Ok(bmap) => Some(bmap),
+                                    Err(()) => return,
+                                }
+                            }
+                            None => None,
+                        };
+
+                        FlashSource::Image(file)
+                    }
                 };
 
+                *state.source.borrow_mut() = Some(source);
+
                 let all_devices = state.available_devices.borrow();
                 let mut devices = state.selected_devices.borrow_mut();
 
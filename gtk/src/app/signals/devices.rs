@@ -1,9 +1,17 @@
+use crate::app::state::Mode;
 use crate::app::App;
 use gtk::prelude::*;
 
 impl App {
     pub fn connect_view_ready(&self) {
+        let state = self.state.clone();
         let next = self.ui.header.next.clone();
-        self.ui.content.devices_view.connect_view_ready(move |ready| next.set_sensitive(ready));
+        self.ui.content.devices_view.connect_view_ready(move |selected| {
+            // Only one device is backed up at a time.
+            next.set_sensitive(match state.mode.get() {
+                Mode::Backup => selected == 1,
+                _ => selected != 0,
+            });
+        });
     }
 }
@@ -1,5 +1,5 @@
-use crate::app::events::{BackgroundEvent, UiEvent};
-use crate::app::state::State;
+use crate::app::events::BackgroundEvent;
+use crate::app::state::{ActiveView, Mode, State};
 use crate::app::widgets::OpenDialog;
 use crate::app::{App, GtkUi};
 use crate::misc;
@@ -12,12 +12,30 @@ impl App {
         let ui = self.ui.clone();
         self.ui.content.image_view.chooser.connect_clicked(move |_| {
             if let Some(path) = OpenDialog::new(None).run() {
-                let _ = state.ui_event_tx.send(UiEvent::SetImageLabel(path));
+                let _ = state.back_event_tx.send(BackgroundEvent::SizeImage(path));
                 set_hash_widget(&state, &ui);
             }
         });
     }
 
+    pub fn connect_modes(&self) {
+        let image_view = &self.ui.content.image_view;
+        let modes = [
+            (&image_view.wipe, Mode::Wipe),
+            (&image_view.restore, Mode::Restore),
+            (&image_view.backup, Mode::Backup),
+        ];
+        for (button, mode) in modes {
+            let state = self.state.clone();
+            let ui = self.ui.clone();
+            button.connect_clicked(move |_| {
+                state.mode.set(mode);
+                ui.content.devices_view.set_mode(mode);
+                ui.switch_to(&state, ActiveView::Devices);
+            });
+        }
+    }
+
     pub fn connect_hash(&self) {
         let state = self.state.clone();
         let ui = self.ui.clone();
@@ -35,10 +53,10 @@ impl App {
             if let Some(uri) = data.text() {
                 if uri.starts_with("file://") {
                     let path = Path::new(&uri[7..uri.len() - 1]);
-                    if path.extension().map_or(false, |ext| ext == "iso" || ext == "img")
-                        && path.exists()
-                    {
-                        let _ = state.ui_event_tx.send(UiEvent::SetImageLabel(path.to_path_buf()));
+                    if misc::is_image(path) && path.exists() {
+                        let _ = state
+                            .back_event_tx
+                            .send(BackgroundEvent::SizeImage(path.to_path_buf()));
                         set_hash_widget(&state, &ui);
                     }
                 }
@@ -2,17 +2,21 @@ mod devices;
 mod images;
 
 use crate::app::events::{BackgroundEvent, UiEvent};
-use crate::app::state::ActiveView;
+use crate::app::state::{ActiveView, Mode, State};
 use crate::app::App;
 use crate::fl;
-use crate::flash::{FlashRequest, FlashStatus, FlashTask};
+use crate::flash::{
+    DeviceProgress, FlashPhase, FlashRequest, FlashSource, FlashStatus, FlashTask, Flashed,
+};
 use crate::misc;
 use atomic::Atomic;
 use crossbeam_channel::TryRecvError;
+use dbus_udisks2::DiskDevice;
 use gtk::{self, prelude::*};
 use iso9660::ISO9660;
+use popsicle::{CancelHandle, Journal};
 use std::fmt::Write;
-use std::fs::File;
+use std::fs::{self, File};
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
@@ -33,6 +37,8 @@ impl App {
 
             let _ = state.ui_event_tx.send(UiEvent::Reset);
             ui.content.devices_view.reset();
+            ui.content.devices_view.set_mode(Mode::Flash);
+            state.mode.set(Mode::Flash);
 
             ui.switch_to(&state, back);
         });
@@ -61,8 +67,10 @@ impl App {
         let ui = self.ui.clone();
 
         let mut last_device_refresh = Instant::now();
-        let mut flashing_devices: Vec<(gtk::ProgressBar, gtk::Label)> = Vec::new();
+        let mut flashing_devices: Vec<(gtk::ProgressBar, gtk::ProgressBar, gtk::Label)> =
+            Vec::new();
         let flash_status = Arc::new(Atomic::new(FlashStatus::Inactive));
+        let mut flash_cancel: Option<CancelHandle> = None;
         let mut flash_handles = None;
         let mut tasks = None;
 
@@ -79,10 +87,8 @@ impl App {
 
                     ui.content.image_view.chooser_container.set_visible_child_name("chooser");
                 }
-                Ok(UiEvent::SetImageLabel(path)) => {
+                Ok(UiEvent::SetImageLabel(path, image_size, image_id)) => {
                     if let Ok(file) = File::open(&path) {
-                        let image_size = file.metadata().ok().map_or(0, |m| m.len());
-
                         let warning = if is_windows_iso(&file) {
                             Some(fl!("win-isos-not-supported"))
                         } else {
@@ -93,13 +99,23 @@ impl App {
                         ui.content.image_view.set_hash_sensitive(true);
                         ui.header.next.set_sensitive(true);
 
-                        state.image_size.store(image_size, Ordering::SeqCst);
+                        state.image_size.set(image_size);
+                        *state.image_id.borrow_mut() = image_id;
                         *state.image_path.borrow_mut() = path;
                     }
                 }
                 Ok(UiEvent::RefreshDevices(devices)) => {
-                    let size = state.image_size.load(Ordering::SeqCst);
+                    // Devices of any size may be wiped, restored or backed up, and images of
+                    // an unknown size are not compared against them.
+                    let size = match state.mode.get() {
+                        Mode::Flash => state.image_size.get().unwrap_or(0),
+                        Mode::Wipe | Mode::Restore | Mode::Backup => 0,
+                    };
+
                     ui.content.devices_view.refresh(&devices, size);
+                    ui.content.devices_view.set_interrupted(
+                        state.mode.get() == Mode::Flash && is_interrupted(&state, &devices),
+                    );
                     *state.available_devices.borrow_mut() = devices;
                 }
                 Ok(UiEvent::Flash(handle)) => flash_handles = Some(handle),
@@ -111,6 +127,10 @@ impl App {
                         FlashStatus::Inactive | FlashStatus::Killing => (),
                     }
 
+                    if let Some(cancel) = flash_cancel.take() {
+                        cancel.cancel();
+                    }
+
                     flash_handles = None;
                     tasks = None;
                     flashing_devices.clear();
@@ -127,9 +147,17 @@ impl App {
                         let _ = state.back_event_tx.send(BackgroundEvent::RefreshDevices);
                     }
                 }
-                ActiveView::Flashing => match state.image.borrow_mut().take() {
+                ActiveView::Flashing => match state.source.borrow_mut().take() {
                     // When the flashing view is active, and an image has not started flashing.
-                    Some(image) => {
+                    Some(source) => {
+                        let mode = state.mode.get();
+                        ui.content.flash_view.view.topic.set_text(&match mode {
+                            Mode::Flash => fl!("flash-view-title"),
+                            Mode::Wipe => fl!("wipe-progress-title"),
+                            Mode::Restore => fl!("restore-progress-title"),
+                            Mode::Backup => fl!("backup-progress-title"),
+                        });
+
                         let summary_grid = &ui.content.flash_view.progress_list;
                         summary_grid.foreach(|w| summary_grid.remove(w));
                         let mut destinations = Vec::new();
@@ -149,6 +177,13 @@ impl App {
                                 ..style_context().add_class("bold");
                             };
 
+                            // Shown once the device begins to be verified.
+                            let verify_bar = cascade! {
+                                gtk::ProgressBar::new();
+                                ..set_hexpand(true);
+                                ..set_no_show_all(true);
+                            };
+
                             let bar_label = cascade! {
                                 gtk::Label::new(None);
                                 ..set_halign(gtk::Align::Center);
@@ -157,39 +192,67 @@ impl App {
                             let bar_container = cascade! {
                                 gtk::Box::new(gtk::Orientation::Vertical, 0);
                                 ..add(&pbar);
+                                ..add(&verify_bar);
                                 ..add(&bar_label);
                             };
 
                             summary_grid.attach(&label, 0, id, 1, 1);
                             summary_grid.attach(&bar_container, 1, id, 1, 1);
 
-                            flashing_devices.push((pbar, bar_label));
+                            flashing_devices.push((pbar, verify_bar, bar_label));
                             destinations.push(device.clone());
                         }
 
                         summary_grid.show_all();
                         let ndestinations = destinations.len();
                         let progress = Arc::new(
-                            (0..ndestinations).map(|_| Atomic::new(0u64)).collect::<Vec<_>>(),
+                            (0..ndestinations)
+                                .map(|_| Mutex::new(DeviceProgress::default()))
+                                .collect::<Vec<_>>(),
                         );
-                        let finished = Arc::new(
-                            (0..ndestinations).map(|_| Atomic::new(false)).collect::<Vec<_>>(),
+                        let phases = Arc::new(
+                            (0..ndestinations)
+                                .map(|_| Atomic::new(FlashPhase::Writing))
+                                .collect::<Vec<_>>(),
                         );
 
-                        let _ =
-                            state.back_event_tx.send(BackgroundEvent::Flash(FlashRequest::new(
-                                image,
-                                destinations,
-                                flash_status.clone(),
-                                progress.clone(),
-                                finished.clone(),
-                            )));
-
-                        tasks = Some(FlashTask {
-                            previous: Arc::new(Mutex::new(vec![[0; 7]; ndestinations])),
-                            progress,
-                            finished,
-                        });
+                        // A block map records the exact size, which bzip2 images do not. The
+                        // progress of images of an unknown size is measured against each device.
+                        let bmap = state.bmap.borrow_mut().take();
+                        let size = bmap
+                            .as_ref()
+                            .map(|bmap| bmap.image_size)
+                            .or_else(|| state.image_size.get());
+
+                        // Devices are wiped, restored or backed up whole, whatever their size.
+                        let sizes = destinations
+                            .iter()
+                            .map(|device| match mode {
+                                Mode::Flash => size.unwrap_or(device.parent.size),
+                                Mode::Wipe | Mode::Restore | Mode::Backup => device.parent.size,
+                            })
+                            .collect();
+
+                        let cancel = CancelHandle::default();
+                        flash_cancel = Some(cancel.clone());
+
+                        let mut request = FlashRequest::new(
+                            source,
+                            bmap,
+                            destinations,
+                            flash_status.clone(),
+                            cancel,
+                            progress.clone(),
+                            phases.clone(),
+                        );
+                        request.check = ui.content.devices_view.verify.is_active();
+                        request.resume = ui.content.devices_view.resume.is_active();
+                        request.eject = ui.content.devices_view.eject.is_active();
+                        request.image_id = state.image_id.borrow().clone();
+
+                        let _ = state.back_event_tx.send(BackgroundEvent::Flash(request));
+
+                        tasks = Some(FlashTask { sizes, progress, phases });
                     }
                     // When the flashing view is active, and thus an image is flashing.
                     None => {
@@ -201,48 +264,47 @@ impl App {
                             last_device_refresh = now;
 
                             let mut all_tasks_finished = true;
-                            let length = state.image_size.load(Ordering::SeqCst);
                             let tasks = tasks.as_mut().expect("no flash task");
-                            let mut previous = tasks.previous.lock().expect("mutex lock");
-
-                            for (id, (pbar, label)) in flashing_devices.iter().enumerate() {
-                                let prev_values = &mut previous[id];
-                                let progress = &tasks.progress[id];
-                                let finished = &tasks.finished[id];
+                            for (id, (pbar, verify_bar, label)) in
+                                flashing_devices.iter().enumerate()
+                            {
+                                let progress = *tasks.progress[id].lock().expect("mutex lock");
+                                let phase = tasks.phases[id].load(Ordering::SeqCst);
+                                let value = progress.position as f64 / tasks.sizes[id] as f64;
+
+                                match phase {
+                                    FlashPhase::Writing => pbar.set_fraction(value),
+                                    FlashPhase::Verifying => {
+                                        pbar.set_fraction(1.0);
+                                        verify_bar.show();
+                                        verify_bar.set_fraction(value);
+                                    }
+                                    FlashPhase::Finished => {
+                                        pbar.set_fraction(1.0);
+                                        verify_bar.set_fraction(1.0);
+                                    }
+                                }
 
-                                let raw_value = progress.load(Ordering::SeqCst);
-                                let task_is_finished = finished.load(Ordering::SeqCst);
-                                let value = if task_is_finished {
-                                    1.0f64
+                                if phase == FlashPhase::Finished {
+                                    label.set_label(&fl!("task-finished"));
                                 } else {
                                     all_tasks_finished = false;
-                                    raw_value as f64 / length as f64
-                                };
 
-                                pbar.set_fraction(value);
+                                    let per_second = progress.rate.bytes_per_second;
+                                    let mut rate =
+                                        format!("{}/s", bytesize::to_string(per_second, true));
+                                    if let Some(eta) = progress.rate.eta {
+                                        let eta = misc::format_duration(eta);
+                                        rate = fl!("task-remaining", rate = rate, eta = eta);
+                                    }
 
-                                if task_is_finished {
-                                    label.set_label(&fl!("task-finished"));
-                                } else {
-                                    prev_values[1] = prev_values[2];
-                                    prev_values[2] = prev_values[3];
-                                    prev_values[3] = prev_values[4];
-                                    prev_values[4] = prev_values[5];
-                                    prev_values[5] = prev_values[6];
-                                    prev_values[6] = raw_value - prev_values[0];
-                                    prev_values[0] = raw_value;
-
-                                    let sum: u64 = prev_values.iter().skip(1).sum();
-                                    let per_second = sum / 3;
-                                    label.set_label(&format!(
-                                        "{}/s",
-                                        bytesize::to_string(per_second, true)
-                                    ));
+                                    label.set_label(&match phase {
+                                        FlashPhase::Verifying => fl!("task-verifying", rate = rate),
+                                        _ => rate,
+                                    });
                                 }
                             }
 
-                            drop(previous);
-
                             if all_tasks_finished {
                                 eprintln!("all tasks finished");
 
@@ -276,37 +338,75 @@ impl App {
                                 };
 
                                 let mut errors = Vec::new();
+                                let mut flashed = Vec::new();
                                 let mut selected_devices = state.selected_devices.borrow_mut();
                                 let ntasks = selected_devices.len();
 
                                 for (device, result) in
                                     selected_devices.drain(..).zip(results.into_iter())
                                 {
-                                    if let Err(why) = result {
-                                        errors.push((device, why));
+                                    match result {
+                                        Ok(outcome) => {
+                                            if let Some((text, bold)) = flashed_status(&outcome) {
+                                                flashed.push((device, text, bold));
+                                            }
+                                        }
+                                        Err(why) => errors.push((device, why)),
                                     }
                                 }
 
                                 ui.switch_to(&state, ActiveView::Summary);
                                 let list = &ui.content.summary_view.list;
+                                let topic = &ui.content.summary_view.view.topic;
                                 let description = &ui.content.summary_view.view.description;
+                                let mode = state.mode.get();
+
+                                let any_status = !flashed.is_empty();
+                                for (device, text, bold) in flashed {
+                                    list.add(&summary_row(&device, &text, bold));
+                                }
 
                                 if result.is_ok() && errors.is_empty() {
-                                    let desc = fl!("successful-flash", total = ntasks);
+                                    let desc = match mode {
+                                        Mode::Flash => fl!("successful-flash", total = ntasks),
+                                        Mode::Wipe => fl!("successful-wipe", total = ntasks),
+                                        Mode::Restore => fl!("successful-restore", total = ntasks),
+                                        Mode::Backup => fl!("successful-backup"),
+                                    };
+
+                                    topic.set_text(&match mode {
+                                        Mode::Flash => fl!("flashing-completed"),
+                                        Mode::Wipe => fl!("wipe-completed"),
+                                        Mode::Restore => fl!("restore-completed"),
+                                        Mode::Backup => fl!("backup-completed"),
+                                    });
                                     description.set_text(&desc);
-                                    list.hide();
+                                    if any_status {
+                                        list.show_all();
+                                    } else {
+                                        list.hide();
+                                    }
                                 } else {
-                                    ui.content
-                                        .summary_view
-                                        .view
-                                        .topic
-                                        .set_text(&fl!("flashing-completed-with-errors"));
-
-                                    let mut desc = fl!(
-                                        "partial-flash",
-                                        number = { ntasks - errors.len() },
-                                        total = ntasks
-                                    );
+                                    topic.set_text(&match mode {
+                                        Mode::Flash => fl!("flashing-completed-with-errors"),
+                                        Mode::Wipe => fl!("wipe-completed-with-errors"),
+                                        Mode::Restore => fl!("restore-completed-with-errors"),
+                                        Mode::Backup => fl!("backup-completed-with-errors"),
+                                    });
+
+                                    let (number, total) = (ntasks - errors.len(), ntasks);
+                                    let mut desc = match mode {
+                                        Mode::Flash => {
+                                            fl!("partial-flash", number = number, total = total)
+                                        }
+                                        Mode::Wipe => {
+                                            fl!("partial-wipe", number = number, total = total)
+                                        }
+                                        Mode::Restore => {
+                                            fl!("partial-restore", number = number, total = total)
+                                        }
+                                        Mode::Backup => fl!("partial-backup"),
+                                    };
 
                                     if let Err(why) = result {
                                         let _ = write!(desc, ": <b>{}</b>", why);
@@ -315,25 +415,8 @@ impl App {
                                     description.set_markup(&desc);
 
                                     for (device, why) in errors {
-                                        let device =
-                                            gtk::Label::new(Some(&misc::device_label(&device)));
-                                        let why =
-                                            gtk::Label::new(Some(format!("{}", why).as_str()));
-                                        why.style_context().add_class("bold");
-
-                                        let container = cascade! {
-                                            gtk::Box::new(gtk::Orientation::Horizontal, 6);
-                                            ..pack_start(&device, false, false, 0);
-                                            ..pack_start(&why, true, true, 0);
-                                        };
-
-                                        let row = cascade! {
-                                            gtk::ListBoxRow::new();
-                                            ..set_selectable(false);
-                                            ..add(&container);
-                                        };
-
-                                        list.add(&row);
+                                        let why = format!("{}", why);
+                                        list.add(&summary_row(&device, &why, true));
                                     }
 
                                     list.show_all();
@@ -350,6 +433,81 @@ impl App {
     }
 }
 
+/// Describes what became of a device which was flashed, if there is more to it than
+/// being flashed, and whether that needs the user's attention.
+fn flashed_status(flashed: &Flashed) -> Option<(String, bool)> {
+    if let Some(digest) = &flashed.digest {
+        return Some((fl!("backup-digest", digest = digest.as_str()), false));
+    }
+
+    match &flashed.ejected {
+        Some(Ok(())) => return Some((fl!("device-ejected"), false)),
+        Some(Err(why)) => return Some((fl!("eject-failed", why = why.as_str()), true)),
+        None => (),
+    }
+
+    if flashed.partitions.is_empty() {
+        return None;
+    }
+
+    let partitions = flashed
+        .partitions
+        .iter()
+        .map(|partition| partition.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    match flashed.unsettled {
+        true => Some((fl!("partitions-unsettled", partitions = partitions), false)),
+        false => Some((fl!("partitions-ready", partitions = partitions), false)),
+    }
+}
+
+/// A row of the summary, which describes what became of the device.
+fn summary_row(device: &DiskDevice, text: &str, bold: bool) -> gtk::ListBoxRow {
+    let device = gtk::Label::new(Some(&misc::device_label(device)));
+    let text = gtk::Label::new(Some(text));
+    if bold {
+        text.style_context().add_class("bold");
+    }
+
+    let container = cascade! {
+        gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        ..pack_start(&device, false, false, 0);
+        ..pack_start(&text, true, true, 0);
+    };
+
+    cascade! {
+        gtk::ListBoxRow::new();
+        ..set_selectable(false);
+        ..add(&container);
+    }
+}
+
+/// Whether any of the devices was interrupted while being flashed with the image. The
+/// journal is only read again once it has been modified.
+fn is_interrupted(state: &State, devices: &[Arc<DiskDevice>]) -> bool {
+    let image_id = state.image_id.borrow();
+    let (image, path) = match (image_id.as_ref(), Journal::default_path()) {
+        (Some(image), Some(path)) => (image, path),
+        _ => return false,
+    };
+
+    let modified = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+    let mut cached = state.journal.borrow_mut();
+    if cached.as_ref().map(|(at, _)| *at) != Some(modified) {
+        *cached = Journal::open(&path).ok().map(|journal| (modified, journal));
+    }
+
+    match cached.as_ref() {
+        Some((_, journal)) => devices
+            .iter()
+            .filter_map(|device| misc::device_id(device))
+            .any(|device| journal.offset(image, &device).is_some()),
+        None => false,
+    }
+}
+
 fn is_windows_iso(file: &File) -> bool {
     if let Ok(fs) = ISO9660::new(file) {
         return fs.publisher_identifier() == "MICROSOFT CORPORATION";
@@ -1,13 +1,27 @@
 use crate::app::events::{self, BackgroundEvent, UiEvent};
-use atomic::Atomic;
+use crate::flash::FlashSource;
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use dbus_udisks2::DiskDevice;
 use libc;
+use popsicle::{Bmap, ImageId, Journal};
 use std::cell::{Cell, RefCell};
 use std::env;
-use std::fs::File;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::SystemTime;
+
+/// What is done to the devices which are selected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// The image is flashed to them.
+    Flash,
+    /// Every block of them is wiped.
+    Wipe,
+    /// They are restored to empty data drives.
+    Restore,
+    /// The one which is selected is read into an image.
+    Backup,
+}
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ActiveView {
@@ -25,9 +39,17 @@ pub struct State {
 
     pub active_view: Cell<ActiveView>,
 
-    pub image: RefCell<Option<File>>,
+    /// What is to be written to the devices, until it begins to be written.
+    pub source: RefCell<Option<FlashSource>>,
+    pub mode: Cell<Mode>,
     pub image_path: RefCell<PathBuf>,
-    pub image_size: Arc<Atomic<u64>>,
+    /// The size that the image expands to, which some compressed images do not record.
+    pub image_size: Cell<Option<u64>>,
+    pub bmap: RefCell<Option<Bmap>>,
+    /// Identifies the image in the journal of interrupted flashes.
+    pub image_id: RefCell<Option<ImageId>>,
+    /// The journal of interrupted flashes, along with when it was last modified.
+    pub journal: RefCell<Option<(Option<SystemTime>, Journal)>>,
 
     pub available_devices: RefCell<Box<[Arc<DiskDevice>]>>,
     pub selected_devices: RefCell<Vec<Arc<DiskDevice>>>,
@@ -56,9 +78,13 @@ impl State {
             ui_event_tx,
             back_event_tx,
             active_view: Cell::new(ActiveView::Images),
-            image: RefCell::new(None),
+            source: RefCell::new(None),
+            mode: Cell::new(Mode::Flash),
             image_path: RefCell::new(PathBuf::new()),
-            image_size: Arc::new(Atomic::new(0u64)),
+            image_size: Cell::new(None),
+            bmap: RefCell::new(None),
+            image_id: RefCell::new(None),
+            journal: RefCell::new(None),
             available_devices: RefCell::new(Box::new([])),
             selected_devices: RefCell::new(Vec::new()),
         }
@@ -1,21 +1,39 @@
 use super::View;
+use crate::app::state::Mode;
 use crate::fl;
 use crate::misc;
 use dbus_udisks2::DiskDevice;
 use gtk;
 use gtk::prelude::*;
+use popsicle::{Compression, Filesystem, PartitionTable, WipeMethod};
 use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use std::sync::Arc;
 
 use bytesize;
 
-type ViewReadySignal = Rc<RefCell<Box<dyn Fn(bool)>>>;
+type ViewReadySignal = Rc<RefCell<Box<dyn Fn(usize)>>>;
 
 pub struct DevicesView {
     pub view: View,
     pub list: gtk::ListBox,
     pub select_all: gtk::CheckButton,
+    pub verify: gtk::CheckButton,
+    /// Powers off each device once it is flashed, so that it may be unplugged.
+    pub eject: gtk::CheckButton,
+    /// Offered when a device which was being flashed with the image reappears.
+    pub resume: gtk::CheckButton,
+    /// How the devices are wiped, when they are wiped instead of being flashed.
+    pub wipe_method: gtk::ComboBoxText,
+    /// How the devices are restored, when they are restored to empty data drives.
+    pub restore_options: gtk::Box,
+    pub table: gtk::ComboBoxText,
+    pub filesystem: gtk::ComboBoxText,
+    pub label: gtk::Entry,
+    /// How the device is backed up, when it is read into an image.
+    pub backup_options: gtk::Box,
+    pub compression: gtk::ComboBoxText,
+    pub trim: gtk::CheckButton,
     view_ready: ViewReadySignal,
 }
 
@@ -62,16 +80,123 @@ impl DevicesView {
             ..add(&list_box);
         };
 
+        let verify = cascade! {
+            gtk::CheckButton::with_label(&fl!("verify-after-writing"));
+            ..set_margin_start(4);
+            ..set_margin_top(6);
+        };
+
+        let eject = cascade! {
+            gtk::CheckButton::with_label(&fl!("eject-when-done"));
+            ..set_margin_start(4);
+        };
+
+        let resume = cascade! {
+            gtk::CheckButton::with_label(&fl!("resume-flash"));
+            ..set_margin_start(4);
+        };
+
+        let wipe_method = cascade! {
+            gtk::ComboBoxText::new();
+            ..append_text(&fl!("wipe-discard"));
+            ..append_text(&fl!("wipe-zeroes"));
+            ..append_text(&fl!("wipe-random"));
+            ..set_active(Some(0));
+            ..set_margin_top(6);
+            ..set_no_show_all(true);
+        };
+
+        let table = cascade! {
+            gtk::ComboBoxText::new();
+            ..append_text("MBR");
+            ..append_text("GPT");
+            ..set_active(Some(0));
+            ..show();
+        };
+
+        let filesystem = cascade! {
+            gtk::ComboBoxText::new();
+            ..append_text("FAT32");
+            ..append_text("exFAT");
+            ..set_active(Some(0));
+            ..show();
+        };
+
+        let label = cascade! {
+            gtk::Entry::new();
+            ..set_placeholder_text(Some(&fl!("restore-label")));
+            ..set_max_length(11);
+            ..set_hexpand(true);
+            ..show();
+        };
+
+        // The options are shown with their box, once the devices are to be restored.
+        let restore_options = cascade! {
+            gtk::Box::new(gtk::Orientation::Horizontal, 6);
+            ..add(&table);
+            ..add(&filesystem);
+            ..add(&label);
+            ..set_margin_top(6);
+            ..set_no_show_all(true);
+        };
+
+        let compression = cascade! {
+            gtk::ComboBoxText::new();
+            ..append_text(&fl!("backup-uncompressed"));
+            ..append_text("xz");
+            ..append_text("Zstandard");
+            ..set_active(Some(0));
+            ..show();
+        };
+
+        let trim = cascade! {
+            gtk::CheckButton::with_label(&fl!("backup-trim"));
+            ..show();
+        };
+
+        // The options are shown with their box, once the device is to be backed up.
+        let backup_options = cascade! {
+            gtk::Box::new(gtk::Orientation::Horizontal, 6);
+            ..add(&compression);
+            ..add(&trim);
+            ..set_margin_top(6);
+            ..set_no_show_all(true);
+        };
+
         let view = View::new(
             "drive-removable-media-usb",
             &fl!("devices-view-title"),
             &fl!("devices-view-description"),
-            |right_panel| right_panel.add(&select_scroller),
+            |right_panel| {
+                right_panel.add(&select_scroller);
+                right_panel.add(&wipe_method);
+                right_panel.add(&restore_options);
+                right_panel.add(&backup_options);
+                right_panel.add(&verify);
+                right_panel.add(&eject);
+                right_panel.add(&resume);
+            },
         );
 
         let view_ready: ViewReadySignal = Rc::new(RefCell::new(Box::new(|_| ())));
 
-        DevicesView { view, list, select_all, view_ready }
+        DevicesView {
+            view,
+            list,
+            select_all,
+            verify,
+            eject,
+            resume,
+            wipe_method,
+            restore_options,
+            table,
+            filesystem,
+            label,
+            backup_options,
+            compression,
+            trim,
+            view_ready,
+        }
     }
 
     pub fn get_buttons(&self) -> impl Iterator<Item = gtk::CheckButton> {
@@ -124,7 +249,7 @@ impl DevicesView {
                         nselected.set(nselected.get() - 1);
                     }
 
-                    (*view_ready.borrow())(nselected.get() != 0);
+                    (*view_ready.borrow())(nselected.get());
                 });
             };
             self.list.insert(&row, -1);
@@ -133,12 +258,76 @@ impl DevicesView {
         self.list.show_all();
     }
 
+    /// Selects the option to resume when any device has a checkpoint for the image. How
+    /// far each device is written is only recorded when it is selected.
+    pub fn set_interrupted(&self, interrupted: bool) {
+        if interrupted {
+            self.resume.set_active(true);
+        }
+    }
+
+    /// Offers the options for what is to be done to the devices.
+    pub fn set_mode(&self, mode: Mode) {
+        let (topic, description) = match mode {
+            Mode::Flash => (fl!("devices-view-title"), fl!("devices-view-description")),
+            Mode::Wipe => (fl!("wipe-view-title"), fl!("wipe-view-description")),
+            Mode::Restore => (fl!("restore-view-title"), fl!("restore-view-description")),
+            Mode::Backup => (fl!("backup-view-title"), fl!("backup-view-description")),
+        };
+
+        self.view.topic.set_text(&topic);
+        self.view.description.set_text(&description);
+        self.wipe_method.set_visible(mode == Mode::Wipe);
+        self.restore_options.set_visible(mode == Mode::Restore);
+        self.backup_options.set_visible(mode == Mode::Backup);
+        self.select_all.set_visible(mode != Mode::Backup);
+        self.verify.set_visible(matches!(mode, Mode::Flash | Mode::Wipe));
+        self.eject.set_visible(mode == Mode::Flash);
+        self.resume.set_visible(mode == Mode::Flash);
+        if mode != Mode::Flash {
+            self.eject.set_active(false);
+            self.resume.set_active(false);
+        }
+    }
+
+    pub fn wipe_method(&self) -> WipeMethod {
+        match self.wipe_method.active() {
+            Some(1) => WipeMethod::Zeroes,
+            Some(2) => WipeMethod::Random,
+            _ => WipeMethod::Discard,
+        }
+    }
+
+    pub fn restore_options(&self) -> (PartitionTable, Filesystem, String) {
+        let table = match self.table.active() {
+            Some(1) => PartitionTable::Gpt,
+            _ => PartitionTable::Mbr,
+        };
+
+        let filesystem = match self.filesystem.active() {
+            Some(1) => Filesystem::Exfat,
+            _ => Filesystem::Fat32,
+        };
+
+        (table, filesystem, self.label.text().to_string())
+    }
+
+    pub fn backup_options(&self) -> (Compression, bool) {
+        let compression = match self.compression.active() {
+            Some(1) => Compression::Xz,
+            Some(2) => Compression::Zstd,
+            _ => Compression::None,
+        };
+
+        (compression, self.trim.is_active())
+    }
+
     pub fn reset(&self) {
         self.select_all.set_active(false);
         self.get_buttons().for_each(|c| c.set_active(false));
     }
 
-    pub fn connect_view_ready<F: Fn(bool) + 'static>(&self, func: F) {
+    pub fn connect_view_ready<F: Fn(usize) + 'static>(&self, func: F) {
         *self.view_ready.borrow_mut() = Box::new(func);
     }
 }
@@ -11,6 +11,10 @@ pub struct ImageView {
     pub check: Button,
     pub chooser_container: Stack,
     pub chooser: Button,
+    /// Skip choosing an image, to wipe, restore or back up the devices instead.
+    pub wipe: Button,
+    pub restore: Button,
+    pub backup: Button,
     pub image_path: Label,
     pub hash: ComboBoxText,
     pub hash_label: Entry,
@@ -33,10 +37,25 @@ impl ImageView {
             ..set_ellipsize(EllipsizeMode::End);
         };
 
+        let wipe = Button::with_label(&fl!("wipe-devices-button"));
+        let restore = Button::with_label(&fl!("restore-devices-button"));
+        let backup = Button::with_label(&fl!("backup-device-button"));
+
+        let modes = cascade! {
+            Box::new(Orientation::Horizontal, 0);
+            ..add(&wipe);
+            ..add(&restore);
+            ..add(&backup);
+            ..set_halign(Align::Center);
+            ..set_margin_top(12);
+            ..style_context().add_class("linked");
+        };
+
         let button_box = cascade! {
             Box::new(Orientation::Vertical, 0);
             ..pack_start(&chooser, false, false, 0);
             ..pack_start(&image_path, false, false, 0);
+            ..pack_start(&modes, false, false, 0);
         };
 
         let spinner = Spinner::new();
@@ -124,7 +143,18 @@ impl ImageView {
             },
         );
 
-        ImageView { view, check, chooser_container, chooser, image_path, hash, hash_label }
+        ImageView {
+            view,
+            check,
+            chooser_container,
+            chooser,
+            wipe,
+            restore,
+            backup,
+            image_path,
+            hash,
+            hash_label,
+        }
     }
 
     pub fn set_hash_sensitive(&self, sensitive: bool) {
@@ -147,8 +177,12 @@ impl ImageView {
         }
     }
 
-    pub fn set_image(&self, path: &Path, size: u64, warning: Option<&str>) {
-        let size_str = bytesize::to_string(size, true);
+    pub fn set_image(&self, path: &Path, size: Option<u64>, warning: Option<&str>) {
+        let size_str = match size {
+            Some(size) => bytesize::to_string(size, true),
+            None => fl!("image-size-unknown"),
+        };
+
         let mut label: String = match path.file_name() {
             Some(name) => format!("<b>{}</b>\n{}", name.to_string_lossy(), size_str),
             None => format!("<b>{}</b>", fl!("cannot-select-directories")),
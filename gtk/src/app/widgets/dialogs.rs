@@ -1,4 +1,5 @@
 use crate::fl;
+use crate::misc::{COMPRESSED_EXTENSIONS, IMAGE_EXTENSIONS};
 use gtk::{prelude::*, *};
 use std::path::PathBuf;
 
@@ -16,11 +17,7 @@ impl OpenDialog {
                 Some(&fl!("open")),
                 Some(&fl!("cancel")),
             );
-            ..set_filter(&cascade! {
-                FileFilter::new();
-                ..add_pattern("*.[Ii][Ss][Oo]");
-                ..add_pattern("*.[Ii][Mm][Gg]");
-            });
+            ..set_filter(&image_filter());
             if let Some(p) = path {
                 dialog.set_current_folder(p);
             };
@@ -41,3 +38,56 @@ impl Drop for OpenDialog {
         self.0.destroy();
     }
 }
+
+/// A wrapped FileChooserNative for choosing where to save an image, which automatically
+/// destroys itself upon being dropped.
+pub struct SaveDialog(FileChooserNative);
+
+impl SaveDialog {
+    pub fn new(name: &str) -> SaveDialog {
+        SaveDialog(cascade! {
+            FileChooserNative::new(
+                Some(&fl!("save")),
+                Some(&Window::new(WindowType::Popup)),
+                FileChooserAction::Save,
+                Some(&fl!("save")),
+                Some(&fl!("cancel")),
+            );
+            ..set_do_overwrite_confirmation(true);
+            ..set_current_name(name);
+        })
+    }
+
+    pub fn run(&self) -> Option<PathBuf> {
+        if self.0.run() == ResponseType::Accept {
+            self.0.filename()
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for SaveDialog {
+    fn drop(&mut self) {
+        self.0.destroy();
+    }
+}
+
+/// Matches image files, along with their compressed variants.
+fn image_filter() -> FileFilter {
+    let filter = FileFilter::new();
+
+    for extension in IMAGE_EXTENSIONS {
+        // Glob patterns are case sensitive, so each letter matches either case.
+        let pattern: String =
+            extension.chars().map(|c| format!("[{}{}]", c.to_ascii_uppercase(), c)).collect();
+        let pattern = format!("*.{}", pattern);
+
+        filter.add_pattern(&pattern);
+        for extension in COMPRESSED_EXTENSIONS {
+            filter.add_pattern(&format!("{}.{}", pattern, extension));
+        }
+    }
+
+    filter
+}
@@ -1,15 +1,22 @@
 use crate::app::events::FlashResult;
+use crate::fl;
+use crate::misc;
 use atomic::Atomic;
 use dbus::arg::{OwnedFd, RefArg, Variant};
 use dbus::blocking::{Connection, Proxy};
 use dbus_udisks2::DiskDevice;
 use futures::executor;
-use popsicle::{Progress, Task};
+use popsicle::{
+    Backup, BackupOutput, Bmap, CancelHandle, Compression, Filesystem, FlashEvent, Image, ImageId,
+    Journal, PartitionTable, Phase, PowerOff, Progress, Rate, Restore, Task, Wipe, WipeMethod,
+};
 use std::cell::Cell;
 use std::collections::HashMap;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::fs::File;
+use std::io;
 use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
@@ -17,6 +24,9 @@ use std::time::Duration;
 
 type UDisksOptions = HashMap<&'static str, Variant<Box<dyn RefArg>>>;
 
+/// How long to wait for udev to create the partitions of each device once it is flashed.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum FlashStatus {
     Inactive,
@@ -26,66 +36,198 @@ pub enum FlashStatus {
 
 unsafe impl bytemuck::NoUninit for FlashStatus {}
 
+/// The phase that a device is in, which the progress of the device is a measure of.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlashPhase {
+    Writing,
+    Verifying,
+    Finished,
+}
+
+unsafe impl bytemuck::NoUninit for FlashPhase {}
+
+/// The latest progress of a device through its current phase.
+#[derive(Clone, Copy, Default)]
+pub struct DeviceProgress {
+    pub position: u64,
+    pub rate: Rate,
+}
+
+/// What is written to the devices.
+pub enum FlashSource {
+    Image(File),
+    /// Every block of the devices is wiped, rather than an image being written to them.
+    Wipe(WipeMethod),
+    /// The devices are restored to empty data drives.
+    Restore {
+        table: PartitionTable,
+        filesystem: Filesystem,
+        label: String,
+    },
+    /// The device is read into the image of `output`, which is saved along with its block
+    /// map and digest.
+    Backup {
+        image: File,
+        output: BackupOutput,
+        compression: Compression,
+        trim: bool,
+    },
+}
+
 pub struct FlashRequest {
-    source: Option<File>,
+    source: Option<FlashSource>,
+    bmap: Option<Bmap>,
     destinations: Vec<Arc<DiskDevice>>,
     status: Arc<Atomic<FlashStatus>>,
-    progress: Arc<Vec<Atomic<u64>>>,
-    finished: Arc<Vec<Atomic<bool>>>,
+    cancel: CancelHandle,
+    progress: Arc<Vec<Mutex<DeviceProgress>>>,
+    phases: Arc<Vec<Atomic<FlashPhase>>>,
+    /// Reads each device back after writing, to verify it.
+    pub check: bool,
+    /// Continues writing devices which were interrupted while being flashed with the image.
+    pub resume: bool,
+    /// Identifies the image in the journal, which records how far each device is written.
+    pub image_id: Option<ImageId>,
+    /// Powers off each device once it is finished.
+    pub eject: bool,
+}
+
+/// What became of a device which was flashed.
+#[derive(Clone, Debug, Default)]
+pub struct Flashed {
+    /// The partitions which appeared on the device.
+    pub partitions: Vec<PathBuf>,
+    /// Whether udev was still setting up the partitions when it was no longer waited for.
+    pub unsettled: bool,
+    /// Whether the device was powered off, when it was to be ejected.
+    pub ejected: Option<Result<(), String>>,
+    /// The SHA-256 digest of the image, when the device was backed up.
+    pub digest: Option<String>,
 }
 
 pub struct FlashTask {
-    pub progress: Arc<Vec<Atomic<u64>>>,
-    pub previous: Arc<Mutex<Vec<[u64; 7]>>>,
-    pub finished: Arc<Vec<Atomic<bool>>>,
+    /// How many bytes each device has to go through in each phase.
+    pub sizes: Vec<u64>,
+    pub progress: Arc<Vec<Mutex<DeviceProgress>>>,
+    pub phases: Arc<Vec<Atomic<FlashPhase>>>,
 }
 
 struct FlashProgress<'a> {
     request: &'a FlashRequest,
     id: usize,
-    errors: &'a [Cell<Result<(), FlashError>>],
+    results: &'a [Cell<Result<Flashed, FlashError>>],
 }
 
 #[derive(Clone, Debug)]
-pub struct FlashError {
-    kind: String,
-    message: String,
+pub enum FlashError {
+    Failed { phase: FlashPhase, why: String },
+    Cancelled,
 }
 
 impl Display for FlashError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{}: {}", self.kind, self.message)
+        match self {
+            FlashError::Failed { phase: FlashPhase::Verifying, why } => {
+                f.write_str(&fl!("verify-error", why = why.as_str()))
+            }
+            FlashError::Failed { why, .. } => f.write_str(&fl!("write-error", why = why.as_str())),
+            FlashError::Cancelled => f.write_str(&fl!("flash-cancelled")),
+        }
     }
 }
 
 impl std::error::Error for FlashError {}
 
-impl<'a> Progress for FlashProgress<'a> {
-    type Device = ();
+impl<'a> FlashProgress<'a> {
+    /// Updates what became of the device, unless it already failed.
+    fn flashed(&self, update: impl FnOnce(&mut Flashed)) {
+        let cell = &self.results[self.id];
+        let mut result = cell.replace(Ok(Flashed::default()));
+        if let Ok(flashed) = &mut result {
+            update(flashed);
+        }
 
-    fn message(&mut self, _device: &(), kind: &str, message: &str) {
-        self.errors[self.id]
-            .set(Err(FlashError { kind: kind.to_string(), message: message.to_string() }));
+        cell.set(result);
     }
+}
 
-    fn finish(&mut self) {
-        self.request.finished[self.id].store(true, Ordering::SeqCst);
-    }
+impl<'a> Progress for FlashProgress<'a> {
+    type Device = ();
 
-    fn set(&mut self, value: u64) {
-        self.request.progress[self.id].store(value, Ordering::SeqCst);
+    fn event(&mut self, _device: &(), event: FlashEvent) {
+        let phase = &self.request.phases[self.id];
+        let error = match event {
+            FlashEvent::Phase(Phase::Verifying) => {
+                *self.request.progress[self.id].lock().expect("mutex lock") =
+                    DeviceProgress::default();
+                phase.store(FlashPhase::Verifying, Ordering::SeqCst);
+                return;
+            }
+            // Images which are prechecked are read through once before they are written.
+            FlashEvent::Phase(Phase::Writing) => {
+                *self.request.progress[self.id].lock().expect("mutex lock") =
+                    DeviceProgress::default();
+                return;
+            }
+            FlashEvent::Phase(_) | FlashEvent::Rewritten(_) => return,
+            FlashEvent::Partitions { partitions, timed_out } => {
+                self.flashed(|flashed| {
+                    flashed.partitions = partitions;
+                    flashed.unsettled = timed_out;
+                });
+                return;
+            }
+            FlashEvent::Ejected => {
+                self.flashed(|flashed| flashed.ejected = Some(Ok(())));
+                return;
+            }
+            FlashEvent::EjectFailed(why) => {
+                self.flashed(|flashed| flashed.ejected = Some(Err(why.to_string())));
+                return;
+            }
+            FlashEvent::Failed(why) => {
+                FlashError::Failed { phase: phase.load(Ordering::SeqCst), why: why.to_string() }
+            }
+            FlashEvent::Cancelled => FlashError::Cancelled,
+            FlashEvent::Done => {
+                phase.store(FlashPhase::Finished, Ordering::SeqCst);
+                return;
+            }
+            FlashEvent::Progress { position, rate } => {
+                *self.request.progress[self.id].lock().expect("mutex lock") =
+                    DeviceProgress { position, rate };
+                return;
+            }
+        };
+
+        self.results[self.id].set(Err(error));
+        phase.store(FlashPhase::Finished, Ordering::SeqCst);
     }
 }
 
 impl FlashRequest {
     pub fn new(
-        source: File,
+        source: FlashSource,
+        bmap: Option<Bmap>,
         destinations: Vec<Arc<DiskDevice>>,
         status: Arc<Atomic<FlashStatus>>,
-        progress: Arc<Vec<Atomic<u64>>>,
-        finished: Arc<Vec<Atomic<bool>>>,
+        cancel: CancelHandle,
+        progress: Arc<Vec<Mutex<DeviceProgress>>>,
+        phases: Arc<Vec<Atomic<FlashPhase>>>,
     ) -> FlashRequest {
-        FlashRequest { source: Some(source), destinations, status, progress, finished }
+        FlashRequest {
+            source: Some(source),
+            bmap,
+            destinations,
+            status,
+            cancel,
+            progress,
+            phases,
+            check: false,
+            resume: false,
+            image_id: None,
+            eject: false,
+        }
     }
 
     pub fn write(mut self) -> FlashResult {
@@ -94,8 +236,8 @@ impl FlashRequest {
         let source = self.source.take().unwrap();
         let res = self.write_inner(source);
 
-        for atomic in self.finished.iter() {
-            atomic.store(true, Ordering::SeqCst);
+        for atomic in self.phases.iter() {
+            atomic.store(FlashPhase::Finished, Ordering::SeqCst);
         }
 
         self.status.store(FlashStatus::Inactive, Ordering::SeqCst);
@@ -103,7 +245,7 @@ impl FlashRequest {
         res
     }
 
-    fn write_inner(&self, source: File) -> FlashResult {
+    fn write_inner(&self, source: FlashSource) -> FlashResult {
         // Unmount the devices beforehand.
         for device in &self.destinations {
             let _ = udisks_unmount(&device.parent.path);
@@ -112,28 +254,130 @@ impl FlashRequest {
             }
         }
 
-        // Then open them for writing to.
+        // Then open them for writing to, or only for reading when one is backed up.
+        let mode = match source {
+            FlashSource::Backup { .. } => "r",
+            _ => "rw",
+        };
+
         let mut files = Vec::new();
         for device in &self.destinations {
-            let file = udisks_open(&device.parent.path)?;
-            files.push(file);
+            let file = udisks_open(&device.parent.path, mode, libc::O_SYNC | libc::O_EXCL)?;
+            popsicle::lock_disk(&file)?;
+
+            // Devices which are written to are read back through a descriptor of their own,
+            // as they may not be opened again with `O_DIRECT` without udisks.
+            let reader = match mode {
+                "rw" => udisks_open(&device.parent.path, "r", libc::O_DIRECT).ok(),
+                _ => None,
+            };
+
+            files.push((file, reader));
         }
 
-        let mut errors = vec![Ok(()); files.len()];
-        let errors_cells = Cell::from_mut(&mut errors as &mut [_]).as_slice_of_cells();
+        let mut results = vec![Ok(Flashed::default()); files.len()];
+        let results_cells = Cell::from_mut(&mut results as &mut [_]).as_slice_of_cells();
+
+        let source = match source {
+            FlashSource::Image(source) => source,
+            FlashSource::Wipe(method) => {
+                let mut wipe = Wipe::new(method, self.check);
+                wipe.cancel = self.cancel.clone();
+                for (i, (file, reader)) in files.into_iter().enumerate() {
+                    let progress = FlashProgress { request: self, results: results_cells, id: i };
+                    wipe.subscribe(file.into(), (), progress);
+                    if let Some(reader) = reader {
+                        wipe.direct_reader(reader);
+                    }
+                }
+
+                let res = executor::block_on(wipe.process());
+                return Ok((res, results));
+            }
+            FlashSource::Restore { table, filesystem, label } => {
+                let mut restore = Restore::new(table, filesystem, label);
+                restore.cancel = self.cancel.clone();
+                restore.settle = Some(SETTLE_TIMEOUT);
+                for (i, (file, _)) in files.into_iter().enumerate() {
+                    let progress = FlashProgress { request: self, results: results_cells, id: i };
+                    restore.subscribe(file.into(), (), progress);
+                }
+
+                let res = executor::block_on(restore.process());
+                return Ok((res, results));
+            }
+            FlashSource::Backup { image, output, compression, trim } => {
+                let file = files
+                    .into_iter()
+                    .next()
+                    .map(|(file, _)| file)
+                    .ok_or_else(|| anyhow::anyhow!("no device to back up"))?;
+                let progress = FlashProgress { request: self, results: results_cells, id: 0 };
+                let mut backup = Backup::new(file.into(), (), progress);
+                backup.compression = compression;
+                backup.trim = trim;
+                backup.cancel = self.cancel.clone();
+
+                let mut digest = None;
+                let res = executor::block_on(backup.process(image.into())).and_then(|backed_up| {
+                    output.save(&backed_up)?;
+                    digest =
+                        Some(backed_up.sha256.iter().map(|byte| format!("{:02x}", byte)).collect());
+                    Ok(())
+                });
+
+                if let Some(Ok(flashed)) = results.first_mut() {
+                    flashed.digest = digest;
+                }
+
+                return Ok((res, results));
+            }
+        };
 
         // How many bytes to write at a given time.
         let mut bucket = [0u8; 64 * 1024];
 
-        let mut task = Task::new(source.into(), false);
-        for (i, file) in files.into_iter().enumerate() {
-            let progress = FlashProgress { request: self, errors: errors_cells, id: i };
-            task.subscribe(file.into(), (), progress);
+        let mut image = Image::new(source)?;
+        if let Some(bmap) = self.bmap.clone() {
+            image.set_bmap(bmap)?;
+        }
+
+        let mut task = Task::new(image, self.check);
+        task.cancel = self.cancel.clone();
+        task.resume = self.resume;
+        task.reread = !self.eject;
+        task.settle = Some(SETTLE_TIMEOUT);
+        if self.eject {
+            let drives = self
+                .destinations
+                .iter()
+                .map(|device| (device.parent.device.clone(), device.drive.path.clone()))
+                .collect();
+
+            task.eject = Some(Arc::new(UDisksPowerOff { drives }));
+        }
+
+        // How far each device is written is always recorded, so that it may be resumed.
+        let journal = Journal::default_path().and_then(|path| Journal::open(path).ok());
+        if let (Some(journal), Some(image)) = (journal, self.image_id.clone()) {
+            task.journal(journal, image);
+        }
+
+        for (i, ((file, reader), device)) in files.into_iter().zip(&self.destinations).enumerate() {
+            let progress = FlashProgress { request: self, results: results_cells, id: i };
+            match misc::device_id(device) {
+                Some(identity) => task.subscribe_identified(file.into(), (), progress, identity),
+                None => task.subscribe(file.into(), (), progress),
+            };
+
+            if let Some(reader) = reader {
+                task.direct_reader(reader);
+            }
         }
 
         let res = executor::block_on(task.process(&mut bucket));
 
-        Ok((res, errors))
+        Ok((res, results))
     }
 }
 
@@ -158,7 +402,39 @@ fn udisks_unmount(dbus_path: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn udisks_open(dbus_path: &str) -> anyhow::Result<File> {
+/// Powers off devices through udisks, which may do so without the user being an
+/// administrator.
+struct UDisksPowerOff {
+    /// The node of each device, and the object path of its drive.
+    drives: Vec<(PathBuf, String)>,
+}
+
+impl PowerOff for UDisksPowerOff {
+    fn power_off(&self, disk: &Path) -> io::Result<()> {
+        let drive = self
+            .drives
+            .iter()
+            .find(|(device, _)| device == disk)
+            .map(|(_, drive)| drive.as_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no drive for the device"))?;
+
+        udisks_power_off(drive).map_err(|why| io::Error::new(io::ErrorKind::Other, why.to_string()))
+    }
+}
+
+fn udisks_power_off(dbus_path: &str) -> anyhow::Result<()> {
+    let connection = Connection::new_system()?;
+
+    let dbus_path = ::dbus::strings::Path::new(dbus_path).map_err(anyhow::Error::msg)?;
+
+    let proxy = Proxy::new("org.freedesktop.UDisks2", dbus_path, Duration::new(25, 0), &connection);
+
+    proxy.method_call("org.freedesktop.UDisks2.Drive", "PowerOff", (UDisksOptions::new(),))?;
+
+    Ok(())
+}
+
+fn udisks_open(dbus_path: &str, mode: &str, flags: libc::c_int) -> anyhow::Result<File> {
     let connection = Connection::new_system()?;
 
     let dbus_path = ::dbus::strings::Path::new(dbus_path).map_err(anyhow::Error::msg)?;
@@ -167,9 +443,9 @@ fn udisks_open(dbus_path: &str) -> anyhow::Result<File> {
         Proxy::new("org.freedesktop.UDisks2", &dbus_path, Duration::new(25, 0), &connection);
 
     let mut options = UDisksOptions::new();
-    options.insert("flags", Variant(Box::new(libc::O_SYNC)));
+    options.insert("flags", Variant(Box::new(flags)));
     let res: (OwnedFd,) =
-        proxy.method_call("org.freedesktop.UDisks2.Block", "OpenDevice", ("rw", options))?;
+        proxy.method_call("org.freedesktop.UDisks2.Block", "OpenDevice", (mode, options))?;
 
     Ok(unsafe { File::from_raw_fd(res.0.into_fd()) })
 }
@@ -10,7 +10,7 @@ mod hash;
 mod localize;
 mod misc;
 
-use crate::app::events::UiEvent;
+use crate::app::events::BackgroundEvent;
 use crate::app::state::State;
 use crate::app::App;
 use i18n_embed::DesktopLanguageRequester;
@@ -36,12 +36,8 @@ fn main() {
 
     if let Some(iso_argument) = env::args().nth(1) {
         let path = PathBuf::from(iso_argument);
-        if path.extension().map_or(false, |ext| {
-            let lower_ext = ext.to_str().expect("Could not convert CStr to Str").to_lowercase();
-            lower_ext == "iso" || lower_ext == "img"
-        }) && path.exists()
-        {
-            let _ = app.state.ui_event_tx.send(UiEvent::SetImageLabel(path));
+        if misc::is_image(&path) && path.exists() {
+            let _ = app.state.back_event_tx.send(BackgroundEvent::SizeImage(path));
         }
     }
 
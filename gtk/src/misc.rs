@@ -1,5 +1,11 @@
 use dbus_udisks2::DiskDevice;
 use gtk::{self, prelude::*, SelectionData};
+use popsicle::DeviceId;
+use std::path::Path;
+use std::time::Duration;
+
+/// Extensions of compressed images which popsicle decompresses while flashing.
+pub const COMPRESSED_EXTENSIONS: &[&str] = &["bz2", "gz", "xz", "zst"];
 
 // Implements drag and drop support for a GTK widget.
 pub fn drag_and_drop<W, F>(widget: &W, action: F)
@@ -40,3 +46,37 @@ pub fn device_label(device: &DiskDevice) -> String {
         )
     }
 }
+
+/// Identifies the device in the journal of interrupted flashes, if it has a serial number.
+pub fn device_id(device: &DiskDevice) -> Option<DeviceId> {
+    if device.drive.serial.is_empty() {
+        return None;
+    }
+
+    Some(DeviceId { serial: device.drive.serial.clone(), size: device.parent.size })
+}
+
+/// Extensions of the images which popsicle can flash.
+pub const IMAGE_EXTENSIONS: &[&str] = &["img", "iso", "simg", "qcow2", "vhd", "vhdx", "vmdk"];
+
+/// Whether the path names an image file, optionally compressed.
+pub fn is_image(path: &Path) -> bool {
+    let extension =
+        |path: &Path| path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase());
+
+    let path = match extension(path) {
+        Some(ext) if COMPRESSED_EXTENSIONS.contains(&ext.as_str()) => path.with_extension(""),
+        _ => path.to_path_buf(),
+    };
+
+    extension(&path).map_or(false, |ext| IMAGE_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Formats a duration as minutes and seconds, or hours when there are any.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds / 3600 {
+        0 => format!("{}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{}:{:02}:{:02}", hours, seconds / 60 % 60, seconds % 60),
+    }
+}
@@ -1,4 +1,10 @@
 question = Are you sure you want to flash '{$image_path}' to the following drives?
+bmap-found = using the block map at '{$bmap_path}'
+image-size-unknown = the decompressed size of '{$image_path}' is not recorded in it, so drives are not checked to be large enough
+disk-skipped = skipping '{$disk_path}': {$why}
+question-wipe = Are you sure you want to wipe the following drives? Everything on them will be lost.
+question-clone = Are you sure you want to clone '{$source_path}' to the following drives?
+question-restore = Are you sure you want to restore the following drives to empty {$filesystem} drives? Everything on them will be lost.
 
 yn = y/N
 y = y
@@ -12,17 +18,80 @@ arg-disks-desc = Output disk devices
 
 arg-all-desc = Flash all detected USB drives
 arg-check-desc = Check if written image matches source image
+arg-hash-desc = Check the drives against a SHA-256 digest taken while writing, rather than reading the image again
+arg-expect-hash-desc = Check the drives by hash, and require the decompressed image to match this SHA-256 or SHA-512 digest
+arg-delta-desc = Read each block of the drives first, and only write the blocks which differ from the image
+arg-force-dangerous-desc = Write to drives which hold system filesystems or are not reported as removable, such as many USB enclosures
+arg-max-size-desc = Refuse to write to drives larger than this many GiB, unless forced
+arg-eject-desc = Flush and power off each drive once it is finished, so that it may be unplugged
+arg-resume-desc = Continue from where an interrupted flash of the same image left off
+arg-bmap-desc = Write only the ranges mapped by this block map file
+arg-no-bmap-desc = Ignore any block map found next to the image
+arg-precheck-desc = Read the image through once before writing, to check it against the checksums of its block map
+arg-skip-zeroes-desc = Seek past blocks of zeroes in the image instead of writing them, which requires --erase
+arg-erase-desc = Discard or zero the drives before writing, so that skipped blocks read back as zeroes
 arg-unmount-desc = Unmount mounted devices
 arg-yes-desc = Continue without confirmation
 
+# Wiping
+wipe-desc = Wipe drives, discarding or overwriting every block of them
+arg-wipe-method-desc = Discard the blocks of the drives, falling back to writing zeroes, or write zeroes or random data over them
+arg-wipe-check-desc = Read the drives back to check that they were wiped
+
+# Restoring
+restore-desc = Restore drives to empty data drives, with a single partition spanning each of them
+arg-restore-table-desc = The partition table to write: MBR, which every system can read, or GPT
+arg-restore-filesystem-desc = The filesystem to format the partition with
+arg-restore-label-desc = The label to give the filesystem
+
+# Backing up
+backup-desc = Back up a drive to an image, saving its block map and its SHA-256 digest next to it
+arg-backup-disk-desc = The drive to read
+arg-backup-output-desc = The image file to create
+arg-backup-compress-desc = Compress the image as it is written
+arg-backup-trim-desc = Leave the zeroes at the end of the drive out of the image
+backup-digest = SHA-256 of the image: {$digest}
+
+# Cloning
+clone-desc = Clone a drive to other drives, which it is never written to itself
+arg-clone-source-desc = The drive to clone
+
+# events
+phase-erasing = Erasing
+phase-writing = Writing
+phase-syncing = Syncing
+phase-seeking = Seeking
+phase-verifying = Verifying
+phase-rereading = Reading partitions of
+phase-ejecting = Ejecting
+phase-reading = Reading
+phase-checking = Checking the image for
+event-failed = Failed
+event-cancelled = Cancelled
+event-rewritten = Rewrote {$bytes} of
+event-finished = Finished
+event-partitions = partitions: {$partitions}
+event-unsettled = udev had not finished setting them up
+event-ejected = safe to unplug
+event-eject-failed = not powered off: {$why}
+
 # errors
 error-caused-by = caused by
 error-image-not-set = {arg-image} not set
 error-image-open = unable to open image at '{$image_path}'
 error-image-metadata = unable to fetch image metadata at '{$image_path}'
+error-bmap-open = unable to read the block map at '{$bmap_path}'
+error-expected-hash = expected a SHA-256 or SHA-512 digest in hexadecimal
 error-disks-fetch = failed to fetch list of USB disks
 error-no-disks-specified = no disks specified
+error-no-disks-permitted = none of the drives that were found may be written to without --force-dangerous
 error-fetching-mounts = failed to fetch list of mounts
 error-opening-disks = failed to open disks
+error-opening-source = failed to open the drive to clone
+error-disk-open = unable to open '{$disk_path}'
+error-disk-size = unable to determine the size of '{$disk_path}'
+error-disk-too-small = '{$disk_path}' is smaller than the decompressed image at '{$image_path}'
 error-exiting = exiting without flashing
-error-reading-mounts = error reading mounts
+error-backup-create = unable to create image at '{$image_path}'
+error-backup-save = unable to save the block map and digest of '{$image_path}'
+error-backup-stopped = backing up stopped unexpectedly
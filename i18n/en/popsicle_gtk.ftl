@@ -11,21 +11,49 @@ image-view-title = Choose an Image
 no-image-selected = No image selected
 none = None
 warning = Warning:
+wipe-devices-button = Wipe Drives
+restore-devices-button = Restore Drives
+backup-device-button = Back Up a Drive
 
 # Devices View
 device-too-small = Device too small
+image-size-unknown = Size unknown until decompressed
 devices-view-description = Flashing will erase all data on the selected drives.
 devices-view-title = Select Drives
 select-all = Select all
+verify-after-writing = Verify after writing
+eject-when-done = Eject when done
+resume-flash = Record progress, and resume any interrupted flash of this image
+wipe-view-description = Wiping will permanently erase all data on the selected drives.
+wipe-view-title = Select Drives to Wipe
+wipe-discard = Discard every block, or write zeroes where unsupported
+wipe-zeroes = Write zeroes
+wipe-random = Write random data
+restore-view-description = Restoring will erase all data on the selected drives, leaving each with a single empty partition.
+restore-view-title = Select Drives to Restore
+restore-label = Label
+backup-view-description = The selected drive will be read into an image, saved along with its block map and its SHA-256 digest.
+backup-view-title = Select a Drive to Back Up
+backup-uncompressed = Uncompressed
+backup-trim = Leave out the zeroes at the end
 
 # Flashing View
 flash-view-description = Do not unplug devices while they are being flashed.
 flash-view-title = Flashing Devices
+wipe-progress-title = Wiping Devices
+restore-progress-title = Restoring Devices
+backup-progress-title = Backing Up Device
 
 # Summary View
 flashing-completed = Flashing Completed
 flashing-completed-with-errors = Flashing Completed with Errors
 flash-again = Flash Again
+wipe-completed = Wiping Completed
+wipe-completed-with-errors = Wiping Completed with Errors
+restore-completed = Restoring Completed
+restore-completed-with-errors = Restoring Completed with Errors
+backup-completed = Backup Completed
+backup-completed-with-errors = Backup Failed
 
 # Error View
 critical-error = Critical Error Occurred
@@ -36,14 +64,33 @@ close = Close
 done = Done
 next = Next
 open = Open
+save = Save
 task-finished = Complete
+task-remaining = {$rate}, {$eta} remaining
+task-verifying = Verifying: {$rate}
 
 # Events
 error = error: {$why}
+flash-cancelled = Cancelled
 partial-flash = {$number} of {$total} devices successfully flashed
 successful-flash = {$total} devices successfully flashed
+partial-wipe = {$number} of {$total} devices successfully wiped
+successful-wipe = {$total} devices successfully wiped
+partial-restore = {$number} of {$total} devices successfully restored
+successful-restore = {$total} devices successfully restored
+partial-backup = The device could not be backed up
+successful-backup = The device was backed up, and its image saved
+backup-digest = SHA-256: {$digest}
+partitions-ready = Partitions ready: {$partitions}
+partitions-unsettled = Partitions found, but not yet set up: {$partitions}
+device-ejected = Safe to unplug
+eject-failed = Could not power off: {$why}
+verify-error = Verification error: {$why}
 win-isos-not-supported = Windows ISOs are not currently supported
+write-error = Write error: {$why}
 
 # Errors
 iso-open-failed = Failed to open ISO
+bmap-open-failed = Failed to read the block map
+backup-create-failed = Failed to create the image
 no-value-found = no value found
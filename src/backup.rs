@@ -0,0 +1,405 @@
+//! Backing up devices to images, which may be compressed, along with the SHA-256 digest
+//! and the block map of each image.
+
+use crate::{
+    bmap::{self, Bmap, BmapRange},
+    image::Compression,
+    rate::Meter,
+    task::{CancelHandle, FlashEvent, Phase, Progress},
+    DiskError,
+};
+use async_compression::futures::write::{BzEncoder, GzipEncoder, XzEncoder, ZstdEncoder};
+use async_std::{fs::File, task::spawn_blocking};
+use futures::{
+    executor,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{self, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// How much of the device is read at a time.
+const CHUNK: u64 = 4 * 1024 * 1024;
+
+/// The size of the blocks of the block map, which blocks of zeroes are trimmed in.
+const BLOCK: u64 = 4096;
+
+/// Compresses the image into a buffer, which is taken to be written to the image after
+/// each chunk is compressed. As the buffer is always ready to be written to, compressing
+/// never waits, and is driven to completion within a blocking task.
+enum Encoder {
+    Gzip(GzipEncoder<Vec<u8>>),
+    Xz(XzEncoder<Vec<u8>>),
+    Zstd(ZstdEncoder<Vec<u8>>),
+    Bzip2(BzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(compression: Compression) -> Option<Self> {
+        match compression {
+            Compression::None => None,
+            Compression::Gzip => Some(Encoder::Gzip(GzipEncoder::new(Vec::new()))),
+            Compression::Xz => Some(Encoder::Xz(XzEncoder::new(Vec::new()))),
+            Compression::Zstd => Some(Encoder::Zstd(ZstdEncoder::new(Vec::new()))),
+            Compression::Bzip2 => Some(Encoder::Bzip2(BzEncoder::new(Vec::new()))),
+        }
+    }
+
+    /// Compresses `data`, or finishes the compressed stream if there is none, and takes
+    /// what has been compressed so far.
+    fn compress(&mut self, data: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        executor::block_on(async {
+            match (&mut *self, data) {
+                (Encoder::Gzip(encoder), Some(data)) => encoder.write_all(data).await?,
+                (Encoder::Gzip(encoder), None) => encoder.close().await?,
+                (Encoder::Xz(encoder), Some(data)) => encoder.write_all(data).await?,
+                (Encoder::Xz(encoder), None) => encoder.close().await?,
+                (Encoder::Zstd(encoder), Some(data)) => encoder.write_all(data).await?,
+                (Encoder::Zstd(encoder), None) => encoder.close().await?,
+                (Encoder::Bzip2(encoder), Some(data)) => encoder.write_all(data).await?,
+                (Encoder::Bzip2(encoder), None) => encoder.close().await?,
+            }
+
+            Ok(mem::take(self.buffer()))
+        })
+    }
+
+    fn buffer(&mut self) -> &mut Vec<u8> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.get_mut(),
+            Encoder::Xz(encoder) => encoder.get_mut(),
+            Encoder::Zstd(encoder) => encoder.get_mut(),
+            Encoder::Bzip2(encoder) => encoder.get_mut(),
+        }
+    }
+}
+
+/// Writes the image, compressing it as it is written. Compressing takes long enough to
+/// hold up the other tasks, so each chunk is compressed in a blocking task.
+struct Writer {
+    file: File,
+    encoder: Option<Encoder>,
+}
+
+impl Writer {
+    fn new(file: File, compression: Compression) -> Self {
+        Writer { file, encoder: Encoder::new(compression) }
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.encoder.take() {
+            Some(encoder) => self.compress(encoder, Some(data.to_vec())).await,
+            None => self.file.write_all(data).await,
+        }
+    }
+
+    /// Finishes the compressed stream, and closes the image.
+    async fn close(&mut self) -> io::Result<()> {
+        if let Some(encoder) = self.encoder.take() {
+            self.compress(encoder, None).await?;
+        }
+
+        self.file.close().await
+    }
+
+    async fn compress(&mut self, mut encoder: Encoder, data: Option<Vec<u8>>) -> io::Result<()> {
+        let (encoder, result) = spawn_blocking(move || {
+            let result = encoder.compress(data.as_deref());
+            (encoder, result)
+        })
+        .await;
+
+        self.encoder = Some(encoder);
+        self.file.write_all(&result?).await
+    }
+}
+
+/// What was written to the image of a device which was backed up.
+#[derive(Clone, Debug)]
+pub struct BackedUp {
+    /// How the image was compressed.
+    pub compression: Compression,
+    /// The SHA-256 digest of the image, once it is decompressed.
+    pub sha256: [u8; 32],
+    /// The block map of the image, which maps each block that is not all zeroes.
+    pub bmap: Bmap,
+}
+
+impl BackedUp {
+    /// Saves the block map and the digest next to the image, syncing each of them. The
+    /// block map is saved as `image.bmap`. As the digest is of the decompressed image, it
+    /// is saved next to the image that decompressing it gives, which it names, so that
+    /// `image.xz` has its digest saved as `image.sha256`.
+    pub fn save(&self, image: &Path) -> io::Result<()> {
+        self.sidecars(image).iter().try_for_each(|(path, contents)| write_synced(path, contents))
+    }
+
+    /// The paths of the block map and the digest of the image, and what they hold.
+    fn sidecars(&self, image: &Path) -> [(PathBuf, Vec<u8>); 2] {
+        let decompressed = match self.compression {
+            Compression::None => image.to_owned(),
+            _ => image.with_extension(""),
+        };
+
+        let name = decompressed.file_name().unwrap_or_default().to_string_lossy();
+        let digest = format!("{}  {}\n", bmap::hex(&self.sha256), name);
+        [
+            (sidecar(image, ".bmap"), self.bmap.to_xml().into_bytes()),
+            (sidecar(&decompressed, ".sha256"), digest.into_bytes()),
+        ]
+    }
+}
+
+/// Writes the file, and syncs it before returning.
+fn write_synced(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Syncs the directory holding `path`, so that the names of the files in it are kept.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    fs::File::open(parent)?.sync_all()
+}
+
+/// The path of the file saved next to `image` with `extension` appended to its name.
+fn sidecar(image: &Path, extension: &str) -> PathBuf {
+    let mut path = image.as_os_str().to_owned();
+    path.push(extension);
+    path.into()
+}
+
+/// The image which a device is backed up to. It is written as `path.partial`, and only
+/// takes its path once it is saved along with its block map and digest. If it is never
+/// saved, as the backup failed or was cancelled, the partial image is removed when this
+/// is dropped, so that nothing is left behind to be mistaken for a whole image.
+pub struct BackupOutput {
+    path: PathBuf,
+    partial: PathBuf,
+    saved: bool,
+}
+
+impl BackupOutput {
+    /// Creates the partial image which is saved at `path`, returning the file to pass
+    /// to `Backup::process`. A partial image which is already there, which may be that of
+    /// another backup, is never replaced.
+    pub fn create(path: &Path) -> io::Result<(Self, fs::File)> {
+        let partial = sidecar(path, ".partial");
+        let file = fs::OpenOptions::new().write(true).create_new(true).open(&partial)?;
+        Ok((BackupOutput { path: path.to_owned(), partial, saved: false }, file))
+    }
+
+    /// Saves the block map and the digest as partial files next to the partial image, and
+    /// then renames the image and its files to their paths, replacing any that were there.
+    /// These are synced before they are renamed, and the directory after, so that a crash
+    /// cannot leave a partly written image under its path. If saving fails before the
+    /// image is renamed, the partial files are removed, and any image which was already
+    /// there keeps its own.
+    pub fn save(mut self, backed_up: &BackedUp) -> io::Result<()> {
+        let sidecars = backed_up.sidecars(&self.path);
+        let partials: Vec<PathBuf> =
+            sidecars.iter().map(|(path, _)| sidecar(path, ".partial")).collect();
+        let remove_partials = || {
+            for partial in &partials {
+                let _ = fs::remove_file(partial);
+            }
+        };
+
+        let result = fs::File::open(&self.partial)
+            .and_then(|image| image.sync_all())
+            .and_then(|()| {
+                let mut written = sidecars.iter().zip(&partials);
+                written.try_for_each(|((_, contents), partial)| write_synced(partial, contents))
+            })
+            .and_then(|()| fs::rename(&self.partial, &self.path));
+
+        if let Err(why) = result {
+            remove_partials();
+            return Err(why);
+        }
+
+        self.saved = true;
+        let mut renamed = sidecars.iter().zip(&partials);
+        if let Err(why) = renamed.try_for_each(|((path, _), partial)| fs::rename(partial, path)) {
+            remove_partials();
+            return Err(why);
+        }
+
+        sync_parent(&self.path)
+    }
+}
+
+impl Drop for BackupOutput {
+    fn drop(&mut self) {
+        if !self.saved {
+            let _ = fs::remove_file(&self.partial);
+        }
+    }
+}
+
+#[derive(new)]
+pub struct Backup<P: Progress> {
+    source: File,
+    device: P::Device,
+    pb: P,
+
+    #[new(value = "Compression::None")]
+    pub compression: Compression,
+
+    /// Leaves the blocks of zeroes at the end of the device out of the image.
+    #[new(default)]
+    pub trim: bool,
+
+    #[new(value = "125")]
+    pub millis_between: u64,
+
+    /// When cancelled, the device is sent a `Cancelled` event, and `process` returns
+    /// `DiskError::Killed`.
+    #[new(default)]
+    pub cancel: CancelHandle,
+}
+
+impl<P: Progress> Backup<P> {
+    /// Reads the device into `image` in the `Reading` phase, hashing it and mapping the
+    /// blocks which hold data as it goes. Zeroes are only written once data follows them,
+    /// so that those at the end of the device may be trimmed.
+    pub async fn process(mut self, image: File) -> anyhow::Result<BackedUp> {
+        match self.backup(image).await {
+            Ok(backed_up) => {
+                self.pb.event(&self.device, FlashEvent::Done);
+                Ok(backed_up)
+            }
+            Err(DiskError::Killed) => {
+                self.pb.event(&self.device, FlashEvent::Cancelled);
+                Err(DiskError::Killed.into())
+            }
+            Err(why) => {
+                let message = why.to_string();
+                self.pb.event(&self.device, FlashEvent::Failed(why));
+                Err(anyhow!(message))
+            }
+        }
+    }
+
+    async fn backup(&mut self, image: File) -> Result<BackedUp, DiskError> {
+        let size = self.source.seek(SeekFrom::End(0)).await;
+        let size = size.map_err(|why| DiskError::Seek { offset: 0, why })?;
+        let start = self.source.seek(SeekFrom::Start(0)).await;
+        start.map_err(|why| DiskError::Seek { offset: 0, why })?;
+
+        self.pb.event(&self.device, FlashEvent::Phase(Phase::Reading));
+
+        let mut writer = Writer::new(image, self.compression);
+        let mut hasher = Sha256::new();
+        let mut ranges = Vec::new();
+
+        // The first block of the range which is being read, and its digest so far.
+        let mut range: Option<(u64, Sha256)> = None;
+
+        // Zeroes which were read, but which have not been followed by data yet.
+        let mut zeroes = 0;
+
+        let mut buf = vec![0; CHUNK as usize];
+        let empty = vec![0; CHUNK as usize];
+        let mut offset = 0;
+        let mut meter = Meter::new(Some(size));
+        let mut last_update = Instant::now();
+        while offset < size {
+            if self.cancel.is_cancelled() {
+                return Err(DiskError::Killed);
+            }
+
+            let length = (size - offset).min(CHUNK) as usize;
+            let chunk = &mut buf[..length];
+            self.source.read_exact(chunk).await.map_err(|why| DiskError::Read { why })?;
+
+            // The end of the last block of the chunk which holds data.
+            let mut end = 0;
+            for (index, block) in chunk.chunks(BLOCK as usize).enumerate() {
+                let number = offset / BLOCK + index as u64;
+                if block.iter().all(|&byte| byte == 0) {
+                    if let Some((start, digest)) = range.take() {
+                        let checksum = Some(digest.finalize().into());
+                        ranges.push(BmapRange { start, end: number - 1, checksum });
+                    }
+                } else {
+                    range.get_or_insert_with(|| (number, Sha256::new())).1.update(block);
+                    end = index * BLOCK as usize + block.len();
+                }
+            }
+
+            if end != 0 {
+                write_zeroes(&mut writer, &mut hasher, &empty, zeroes).await?;
+                writer.write_all(&chunk[..end]).await.map_err(|why| DiskError::Backup { why })?;
+                hasher.update(&chunk[..end]);
+                zeroes = 0;
+            }
+
+            zeroes += (length - end) as u64;
+            offset += length as u64;
+            self.report(&mut meter, &mut last_update, offset);
+        }
+
+        if let Some((start, digest)) = range.take() {
+            let checksum = Some(digest.finalize().into());
+            ranges.push(BmapRange { start, end: (size + BLOCK - 1) / BLOCK - 1, checksum });
+        }
+
+        if !self.trim {
+            write_zeroes(&mut writer, &mut hasher, &empty, zeroes).await?;
+            zeroes = 0;
+        }
+
+        writer.close().await.map_err(|why| DiskError::Backup { why })?;
+
+        let image_size = size - zeroes;
+        let bmap = Bmap {
+            image_size,
+            block_size: BLOCK,
+            blocks_count: (image_size + BLOCK - 1) / BLOCK,
+            ranges,
+        };
+
+        Ok(BackedUp { compression: self.compression, sha256: hasher.finalize().into(), bmap })
+    }
+
+    /// Informs the device of how far it has been read, at most once every
+    /// `millis_between`, and always at the end.
+    fn report(&mut self, meter: &mut Meter, last: &mut Instant, at: u64) {
+        let now = Instant::now();
+        if Some(at) == meter.total()
+            || now.duration_since(*last).as_millis() > self.millis_between as u128
+        {
+            *last = now;
+            let rate = meter.update(at);
+            self.pb.event(&self.device, FlashEvent::Progress { position: at, rate });
+        }
+    }
+}
+
+/// Writes `length` zeroes to the image, from the chunk of zeroes in `empty`.
+async fn write_zeroes(
+    writer: &mut Writer,
+    hasher: &mut Sha256,
+    empty: &[u8],
+    mut length: u64,
+) -> Result<(), DiskError> {
+    while length != 0 {
+        let data = &empty[..length.min(empty.len() as u64) as usize];
+        writer.write_all(data).await.map_err(|why| DiskError::Backup { why })?;
+        hasher.update(data);
+        length -= data.len() as u64;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,135 @@
+//! Operations on block devices which are performed through ioctls.
+
+use crate::Erase;
+use libc::{c_int, c_ulong};
+use std::{io, os::unix::io::RawFd};
+
+// The direction bits of `_IO` are not zero on these architectures.
+#[cfg(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc64"
+))]
+const IOC_NONE: c_ulong = 0x2000_0000;
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc64"
+)))]
+const IOC_NONE: c_ulong = 0;
+
+const BLKRRPART: c_ulong = IOC_NONE | 0x125F;
+const BLKFLSBUF: c_ulong = IOC_NONE | 0x1261;
+const BLKSSZGET: c_ulong = IOC_NONE | 0x1268;
+const BLKDISCARD: c_ulong = IOC_NONE | 0x1277;
+const BLKSECDISCARD: c_ulong = IOC_NONE | 0x127D;
+const BLKZEROOUT: c_ulong = IOC_NONE | 0x127F;
+
+/// The logical sector size of the device, which ranges must be aligned to.
+pub fn sector_size(fd: RawFd) -> io::Result<u64> {
+    let mut size: c_int = 0;
+    if unsafe { libc::ioctl(fd, BLKSSZGET as _, &mut size) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(size as u64)
+}
+
+/// Writes back and drops the buffer cache of the device.
+pub fn flush_buffers(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd, BLKFLSBUF as _, 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Asks the kernel to read the partition table of the device again.
+pub fn reread_partitions(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd, BLKRRPART as _, 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Takes an exclusive BSD lock on the device without waiting for it. udev does not probe
+/// devices which are locked, and releases the lock when the device is closed.
+pub fn lock(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Releases the BSD lock on the device while keeping it open, so that udev may probe it.
+pub fn unlock(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::flock(fd, libc::LOCK_UN) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Takes a shared BSD lock on the device without waiting for it, which is held while the
+/// device is only read. Those who take an exclusive lock to write to it are refused.
+pub fn lock_shared(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::flock(fd, libc::LOCK_SH | libc::LOCK_NB) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Erases the first `length` bytes of the device, rounded up to a whole sector. Regular
+/// files, which images may be written to as well, have the range punched out instead.
+pub fn erase(fd: RawFd, erase: Erase, length: u64) -> io::Result<()> {
+    if length == 0 {
+        return Ok(());
+    }
+
+    let sector = match sector_size(fd) {
+        Ok(sector) => sector,
+        Err(why) if why.raw_os_error() == Some(libc::ENOTTY) => return punch(fd, length),
+        Err(why) => return Err(why),
+    };
+    let range: [u64; 2] = [0, (length + sector - 1) / sector * sector];
+
+    let request = match erase {
+        Erase::Discard => BLKDISCARD,
+        Erase::ZeroFill => BLKZEROOUT,
+    };
+
+    if unsafe { libc::ioctl(fd, request as _, range.as_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Deallocates the first `length` bytes of a regular file, which then read back as zeroes.
+fn punch(fd: RawFd, length: u64) -> io::Result<()> {
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    if unsafe { libc::fallocate(fd, mode, 0, length as libc::off_t) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Discards `length` bytes of the device from `offset`, which are aligned to a sector.
+/// Secure discards also erase any copies of the blocks which the device has made.
+pub fn discard(fd: RawFd, secure: bool, offset: u64, length: u64) -> io::Result<()> {
+    let range: [u64; 2] = [offset, length];
+    let request = if secure { BLKSECDISCARD } else { BLKDISCARD };
+    if unsafe { libc::ioctl(fd, request as _, range.as_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
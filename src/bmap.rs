@@ -0,0 +1,346 @@
+//! Support for the block maps produced by `bmaptool create`.
+//!
+//! A block map records which blocks of a raw image contain data. Only those ranges
+//! need to be written to a device, and each range carries a checksum of its contents.
+
+use crate::{
+    image::{Extent, Reader},
+    ImageError,
+};
+use futures::{
+    io::AsyncRead,
+    ready,
+    task::{Context, Poll},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+use thiserror::Error;
+
+/// Errors that may occur when reading a block map.
+#[derive(Debug, Error)]
+#[rustfmt::skip]
+pub enum BmapError {
+    #[error("unable to read block map at '{}': {}", path.display(), why)]
+    Read { path: Box<Path>, why: io::Error },
+    #[error("block map is not valid XML: {}", _0)]
+    Xml(roxmltree::Error),
+    #[error("block map is missing the <{}> element", _0)]
+    Missing(&'static str),
+    #[error("block map has an invalid value for <{}>: '{}'", element, value)]
+    Invalid { element: &'static str, value: Box<str> },
+    #[error("block map uses the unsupported {} checksum type", _0)]
+    ChecksumType(Box<str>),
+    #[error("block map range {}-{} lies outside of the image", start, end)]
+    OutOfBounds { start: u64, end: u64 },
+    #[error("block map range {}-{} overlaps with the previous range", start, end)]
+    Overlap { start: u64, end: u64 },
+    #[error("block map checksum does not match its contents")]
+    Corrupt,
+}
+
+/// A contiguous run of mapped blocks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BmapRange {
+    /// The first block of the range.
+    pub start: u64,
+    /// The last block of the range, inclusive.
+    pub end: u64,
+    /// The SHA-256 digest of the data in the range, if one was recorded.
+    pub checksum: Option<[u8; 32]>,
+}
+
+/// A parsed block map file.
+#[derive(Clone, Debug)]
+pub struct Bmap {
+    pub image_size: u64,
+    pub block_size: u64,
+    pub blocks_count: u64,
+    pub ranges: Vec<BmapRange>,
+}
+
+impl Bmap {
+    /// Locates a block map stored next to the image, following the naming used by
+    /// `bmaptool`: `disk.img.xz` may be paired with `disk.img.xz.bmap`, `disk.img.bmap`,
+    /// or `disk.bmap`.
+    pub fn find(image: &Path) -> Option<PathBuf> {
+        let mut candidate = image.to_path_buf();
+
+        loop {
+            let mut bmap = candidate.clone().into_os_string();
+            bmap.push(".bmap");
+
+            let bmap = PathBuf::from(bmap);
+            if bmap.is_file() {
+                return Some(bmap);
+            }
+
+            candidate.extension()?;
+            candidate.set_extension("");
+        }
+    }
+
+    /// Reads and parses the block map at the given path.
+    pub fn open(path: &Path) -> Result<Self, BmapError> {
+        fs::read_to_string(path)
+            .map_err(|why| BmapError::Read { path: path.into(), why })
+            .and_then(|xml| Self::parse(&xml))
+    }
+
+    /// Parses a block map from its XML source.
+    pub fn parse(xml: &str) -> Result<Self, BmapError> {
+        let document = roxmltree::Document::parse(xml).map_err(BmapError::Xml)?;
+        let root = document.root_element();
+
+        let element = |name: &'static str| {
+            root.children()
+                .find(|node| node.has_tag_name(name))
+                .and_then(|node| node.text())
+                .map(str::trim)
+                .ok_or(BmapError::Missing(name))
+        };
+
+        let number = |name: &'static str| {
+            element(name).and_then(|value| {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| BmapError::Invalid { element: name, value: value.into() })
+            })
+        };
+
+        let image_size = number("ImageSize")?;
+        let block_size = number("BlockSize")?;
+        let blocks_count = number("BlocksCount")?;
+
+        if block_size == 0 {
+            return Err(BmapError::Invalid { element: "BlockSize", value: "0".into() });
+        }
+
+        // Version 1 block maps predate checksums, and those before 2.0 use SHA-1.
+        let checksum_type = element("ChecksumType").ok();
+        if let Some(kind) = checksum_type {
+            if !kind.eq_ignore_ascii_case("sha256") {
+                return Err(BmapError::ChecksumType(kind.into()));
+            }
+        }
+
+        if let Ok(value) = element("BmapFileChecksum") {
+            let expected = parse_digest(value)
+                .ok_or(BmapError::Invalid { element: "BmapFileChecksum", value: value.into() })?;
+
+            // The file checksum is calculated with its own value replaced by zeroes.
+            let zeroed = xml.replacen(value, ZEROED, 1);
+            if Sha256::digest(zeroed.as_bytes()).as_slice() != expected {
+                return Err(BmapError::Corrupt);
+            }
+        }
+
+        let block_map = root
+            .children()
+            .find(|node| node.has_tag_name("BlockMap"))
+            .ok_or(BmapError::Missing("BlockMap"))?;
+
+        let mut ranges = Vec::new();
+        for node in block_map.children().filter(|node| node.has_tag_name("Range")) {
+            let value = node.text().unwrap_or("").trim();
+            let invalid = || BmapError::Invalid { element: "Range", value: value.into() };
+
+            let (start, end) = match value.split_once('-') {
+                Some((start, end)) => (start.trim().parse(), end.trim().parse()),
+                None => (value.parse(), value.parse()),
+            };
+
+            let (start, end): (u64, u64) = match (start, end) {
+                (Ok(start), Ok(end)) if start <= end => (start, end),
+                _ => return Err(invalid()),
+            };
+
+            // The offsets of the range in bytes must also be countable.
+            if end >= blocks_count || (end + 1).checked_mul(block_size).is_none() {
+                return Err(BmapError::OutOfBounds { start, end });
+            }
+
+            let checksum = match node.attribute("chksum") {
+                Some(digest) if checksum_type.is_some() => {
+                    Some(parse_digest(digest).ok_or_else(invalid)?)
+                }
+                _ => None,
+            };
+
+            ranges.push(BmapRange { start, end, checksum });
+        }
+
+        ranges.sort_by_key(|range| range.start);
+
+        if let Some(pair) = ranges.windows(2).find(|pair| pair[0].end >= pair[1].start) {
+            return Err(BmapError::Overlap { start: pair[1].start, end: pair[1].end });
+        }
+
+        Ok(Bmap { image_size, block_size, blocks_count, ranges })
+    }
+
+    /// The byte offset and length of a range within the image.
+    pub fn extent(&self, range: &BmapRange) -> (u64, u64) {
+        let offset = range.start * self.block_size;
+        let end = ((range.end + 1) * self.block_size).min(self.image_size);
+        (offset, end.saturating_sub(offset))
+    }
+
+    /// The number of bytes that will be written when flashing with this map.
+    pub fn mapped_size(&self) -> u64 {
+        self.ranges.iter().map(|range| self.extent(range).1).sum()
+    }
+
+    /// Writes the block map out in the layout of `bmaptool create`, signed with its file
+    /// checksum.
+    pub fn to_xml(&self) -> String {
+        let mapped: u64 = self.ranges.iter().map(|range| range.end - range.start + 1).sum();
+
+        let mut xml = String::from(concat!(
+            "<?xml version=\"1.0\" ?>\n",
+            "<!-- This file contains the block map for an image file, which is basically\n",
+            "     a list of useful (mapped) block numbers in the image file. -->\n",
+            "<bmap version=\"2.0\">\n",
+        ));
+
+        xml.push_str(&format!("    <ImageSize> {} </ImageSize>\n", self.image_size));
+        xml.push_str(&format!("    <BlockSize> {} </BlockSize>\n", self.block_size));
+        xml.push_str(&format!("    <BlocksCount> {} </BlocksCount>\n", self.blocks_count));
+        xml.push_str(&format!("    <MappedBlocksCount> {} </MappedBlocksCount>\n", mapped));
+        xml.push_str("    <ChecksumType> sha256 </ChecksumType>\n");
+        xml.push_str(&format!("    <BmapFileChecksum> {} </BmapFileChecksum>\n", ZEROED));
+        xml.push_str("    <BlockMap>\n");
+        for range in &self.ranges {
+            let checksum = match range.checksum {
+                Some(checksum) => format!(" chksum=\"{}\"", hex(&checksum)),
+                None => String::new(),
+            };
+
+            let blocks = match range.start == range.end {
+                true => range.start.to_string(),
+                false => format!("{}-{}", range.start, range.end),
+            };
+
+            xml.push_str(&format!("        <Range{}> {} </Range>\n", checksum, blocks));
+        }
+        xml.push_str("    </BlockMap>\n</bmap>\n");
+
+        // The file checksum is calculated while its own value is still zeroes.
+        let checksum = hex(&Sha256::digest(xml.as_bytes()));
+        xml.replacen(ZEROED, &checksum, 1)
+    }
+}
+
+/// The value of the file checksum while the checksum is calculated.
+const ZEROED: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Formats a digest in lowercase hexadecimal.
+pub(crate) fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_digest(input: &str) -> Option<[u8; 32]> {
+    let input = input.trim().as_bytes();
+    if input.len() != 64 {
+        return None;
+    }
+
+    let mut digest = [0u8; 32];
+    for (byte, pair) in digest.iter_mut().zip(input.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(digest)
+}
+
+/// Produces the extents of an image which are mapped by a block map, verifying the
+/// checksum of each range as it is read.
+pub(crate) struct Mapping {
+    bmap: Bmap,
+    /// The index of the next range to be read.
+    next: usize,
+    /// The position in the image that the reader will be at after reading the current range.
+    position: u64,
+    /// The number of bytes left to read from the current range.
+    remaining: u64,
+    hasher: Option<Sha256>,
+}
+
+impl Mapping {
+    pub fn new(bmap: Bmap) -> Self {
+        Mapping { bmap, next: 0, position: 0, remaining: 0, hasher: None }
+    }
+
+    pub fn has_checksums(&self) -> bool {
+        self.bmap.ranges.iter().any(|range| range.checksum.is_some())
+    }
+
+    pub fn reset(&mut self) {
+        self.next = 0;
+        self.position = 0;
+        self.remaining = 0;
+        self.hasher = None;
+    }
+
+    pub async fn next_extent(&mut self, reader: &mut Reader) -> io::Result<Option<Extent>> {
+        if self.remaining != 0 {
+            // A range which was not read in full cannot be verified.
+            reader.skip(self.remaining).await?;
+            self.remaining = 0;
+            self.hasher = None;
+        }
+
+        if let Some(hasher) = self.hasher.take() {
+            let range = &self.bmap.ranges[self.next - 1];
+            if Some(<[u8; 32]>::from(hasher.finalize())) != range.checksum {
+                let why = ImageError::BmapChecksum { start: range.start, end: range.end };
+                return Err(io::Error::new(io::ErrorKind::InvalidData, why));
+            }
+        }
+
+        let range = match self.bmap.ranges.get(self.next) {
+            Some(range) => range,
+            None => return Ok(None),
+        };
+
+        let (offset, length) = self.bmap.extent(range);
+        reader.skip(offset - self.position).await?;
+
+        self.next += 1;
+        self.position = offset + length;
+        self.remaining = length;
+        self.hasher = range.checksum.map(|_| Sha256::new());
+
+        Ok(Some(Extent { offset, length }))
+    }
+
+    pub fn poll_read(
+        &mut self,
+        reader: &mut Reader,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let max = self.remaining.min(buf.len() as u64) as usize;
+        if max == 0 {
+            return Poll::Ready(Ok(0));
+        }
+
+        let read = ready!(Pin::new(reader).poll_read(cx, &mut buf[..max]))?;
+        if read == 0 {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "image ended before the end of the block map",
+            )));
+        }
+
+        self.remaining -= read as u64;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..read]);
+        }
+
+        Poll::Ready(Ok(read))
+    }
+}
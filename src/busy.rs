@@ -0,0 +1,298 @@
+//! Finding what is using a disk, or any of its partitions, before it is written to.
+
+use crate::DiskError;
+use std::{
+    ffi::{OsStr, OsString},
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+/// How many symbolic links are followed while resolving a path, as with the kernel.
+const MAX_LINKS: usize = 40;
+
+/// The root that sysfs and procfs are read beneath, which is `/` unless a copy of them
+/// is being inspected instead.
+#[derive(Clone, Debug)]
+pub struct SystemRoot(PathBuf);
+
+impl Default for SystemRoot {
+    fn default() -> Self {
+        SystemRoot(PathBuf::from("/"))
+    }
+}
+
+impl SystemRoot {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        SystemRoot(root.into())
+    }
+
+    pub(crate) fn block(&self, name: &str) -> PathBuf {
+        self.0.join("sys/class/block").join(name)
+    }
+
+    /// The directory of the disk, or of one of its partitions, which are found beneath it.
+    pub(crate) fn member(&self, disk: &str, member: &str) -> PathBuf {
+        match disk == member {
+            true => self.block(disk),
+            false => self.block(disk).join(member),
+        }
+    }
+
+    /// The directory of the block device with the given device number.
+    pub(crate) fn device(&self, rdev: u64) -> PathBuf {
+        let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+        let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+        self.0.join(format!("sys/dev/block/{}:{}", major, minor))
+    }
+
+    /// The node that udev creates for the device.
+    pub(crate) fn node(&self, name: &str) -> PathBuf {
+        self.0.join("dev").join(name)
+    }
+
+    /// Exists while udev has events queued which it has not finished processing.
+    pub(crate) fn udev_queue(&self) -> PathBuf {
+        self.0.join("run/udev/queue")
+    }
+
+    pub(crate) fn mounts(&self) -> PathBuf {
+        self.0.join("proc/self/mounts")
+    }
+
+    pub(crate) fn swaps(&self) -> PathBuf {
+        self.0.join("proc/swaps")
+    }
+
+    /// Resolves the symbolic links of the absolute `path` as they are found beneath the
+    /// root, such as those in `/dev/disk` and `/dev/mapper`, returning it as it appears
+    /// within the root. Paths which are not absolute, or which have too many links to
+    /// resolve, are returned as they are.
+    pub(crate) fn canonicalize(&self, path: &Path) -> PathBuf {
+        self.resolve(path).unwrap_or_else(|| path.to_path_buf())
+    }
+
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        if !path.is_absolute() {
+            return None;
+        }
+
+        // The components which are yet to be resolved, with the next of them last.
+        let mut pending = Vec::new();
+        push_components(&mut pending, path);
+
+        let mut resolved = PathBuf::from("/");
+        let mut links = 0;
+        while let Some(component) = pending.pop() {
+            if component == ".." {
+                resolved.pop();
+                continue;
+            }
+
+            // Device nodes which are missing are taken as they are, as they may not have
+            // been copied along with sysfs and procfs.
+            let next = resolved.join(&component);
+            let beneath = self.0.join(next.strip_prefix("/").ok()?);
+            match fs::symlink_metadata(&beneath) {
+                Ok(metadata) if metadata.file_type().is_symlink() => (),
+                _ => {
+                    resolved = next;
+                    continue;
+                }
+            }
+
+            links += 1;
+            if links > MAX_LINKS {
+                return None;
+            }
+
+            // Absolute targets are resolved from the root, rather than from the link.
+            let target = fs::read_link(&beneath).ok()?;
+            if target.is_absolute() {
+                resolved = PathBuf::from("/");
+            }
+
+            push_components(&mut pending, &target);
+        }
+
+        Some(resolved)
+    }
+}
+
+/// Pushes the names of the components of `path` in reverse, with `..` for each parent.
+fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(name) => pending.push(name.to_owned()),
+            Component::ParentDir => pending.push(OsString::from("..")),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+        }
+    }
+}
+
+/// What is using a disk, or any of its partitions.
+#[derive(Debug, Default)]
+pub struct Usage {
+    /// The mounts of the disk and its partitions, as their source and destination, which
+    /// may be unmounted.
+    pub mounts: Vec<(PathBuf, PathBuf)>,
+    /// The partitions of the disk which are in use as swap, which may be deactivated.
+    pub swaps: Vec<PathBuf>,
+    /// Everything else that is using the disk, which is not undone before writing to it.
+    pub blockers: Vec<DiskError>,
+}
+
+/// Determines what is using the disk at `disk`, which was given as `arg`, from its mounts,
+/// swap, and the devices which hold it or its partitions: RAID arrays, LVM volume groups,
+/// LUKS mappings and other device mapper targets.
+pub fn usage(root: &SystemRoot, arg: &Path, disk: &Path) -> Result<Usage, DiskError> {
+    let inspect = |why| DiskError::Inspect { arg: boxed(arg), why };
+    let name = match disk.file_name().and_then(OsStr::to_str) {
+        Some(name) => name,
+        None => return Err(DiskError::NotABlock { arg: boxed(arg) }),
+    };
+
+    let names = names(root, name).map_err(inspect)?;
+    let mut usage = Usage {
+        mounts: mounts_of(root, &names).map_err(inspect)?,
+        swaps: swaps_of(root, &names).map_err(inspect)?,
+        blockers: Vec::new(),
+    };
+
+    for member in &names {
+        for holder in holders(&root.member(name, member)).map_err(inspect)? {
+            usage.blockers.push(held(root, arg, member, holder).map_err(inspect)?);
+        }
+    }
+
+    Ok(usage)
+}
+
+/// The kernel names of the disk, followed by those of its partitions.
+pub(crate) fn names(root: &SystemRoot, name: &str) -> io::Result<Vec<String>> {
+    let mut names = vec![name.to_owned()];
+    for entry in fs::read_dir(root.block(name))? {
+        let path = entry?.path();
+        if path.join("partition").exists() {
+            if let Some(partition) = path.file_name().and_then(OsStr::to_str) {
+                names.push(partition.to_owned());
+            }
+        }
+    }
+
+    names[1..].sort();
+    Ok(names)
+}
+
+/// The source and destination of each mount of the devices.
+pub(crate) fn mounts_of(
+    root: &SystemRoot,
+    names: &[String],
+) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mounts = mount_table(root)?
+        .into_iter()
+        .filter(|(source, _)| is_device(&root.canonicalize(source), names));
+
+    Ok(mounts.collect())
+}
+
+/// The devices which are in use as swap.
+pub(crate) fn swaps_of(root: &SystemRoot, names: &[String]) -> io::Result<Vec<PathBuf>> {
+    let swaps = match fs::read_to_string(root.swaps()) {
+        Ok(swaps) => swaps,
+        Err(why) if why.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(why) => return Err(why),
+    };
+
+    // The first line of the table is its header.
+    let swaps = swaps.lines().skip(1).filter_map(|line| line.split_whitespace().next());
+    Ok(swaps.map(unescape).filter(|swap| is_device(swap, names)).collect())
+}
+
+/// The source and destination of each mounted filesystem.
+pub(crate) fn mount_table(root: &SystemRoot) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mounts = fs::read_to_string(root.mounts())?;
+    Ok(mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((unescape(fields.next()?), unescape(fields.next()?)))
+        })
+        .collect())
+}
+
+/// Decodes the octal escapes that the kernel writes in place of whitespace in its tables.
+fn unescape(field: &str) -> PathBuf {
+    let mut path = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(at) = rest.find('\\') {
+        path.push_str(&rest[..at]);
+        let code = rest.get(at + 1..at + 4).and_then(|code| u8::from_str_radix(code, 8).ok());
+        match code {
+            Some(byte) => {
+                path.push(byte as char);
+                rest = &rest[at + 4..];
+            }
+            None => {
+                path.push('\\');
+                rest = &rest[at + 1..];
+            }
+        }
+    }
+
+    path.push_str(rest);
+    PathBuf::from(path)
+}
+
+/// Whether the path is that of the disk, or one of its partitions, within `/dev`.
+fn is_device(path: &Path, names: &[String]) -> bool {
+    path.parent() == Some(Path::new("/dev"))
+        && path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| names.iter().any(|n| n == name))
+}
+
+/// The kernel names of the devices which are built on top of the device.
+pub(crate) fn holders(device: &Path) -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(device.join("holders")) {
+        Ok(entries) => entries,
+        Err(why) if why.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(why) => return Err(why),
+    };
+
+    let mut holders = Vec::new();
+    for entry in entries {
+        holders.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+
+    holders.sort();
+    Ok(holders)
+}
+
+/// Describes why the device is held by the holder.
+fn held(root: &SystemRoot, arg: &Path, member: &str, holder: String) -> io::Result<DiskError> {
+    let (arg, member) = (boxed(arg), member.to_owned());
+    if holder.starts_with("md") {
+        return Ok(DiskError::RaidMember { arg, member, array: holder });
+    }
+
+    let dm = root.block(&holder).join("dm");
+    if !dm.exists() {
+        return Ok(DiskError::Held { arg, member, holder });
+    }
+
+    let uuid = fs::read_to_string(dm.join("uuid"))?;
+    let mapping = fs::read_to_string(dm.join("name"))?.trim().to_owned();
+    Ok(if uuid.starts_with("CRYPT-") {
+        DiskError::Encrypted { arg, member, mapping }
+    } else if uuid.starts_with("LVM-") {
+        DiskError::LogicalVolume { arg, member, volume: mapping }
+    } else {
+        DiskError::DeviceMapper { arg, member, mapping }
+    })
+}
+
+/// Errors refer to paths in the form that the rest of the crate uses.
+pub(crate) fn boxed(path: &Path) -> Box<async_std::path::Path> {
+    async_std::path::PathBuf::from(path.to_path_buf()).into_boxed_path()
+}
@@ -0,0 +1,66 @@
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+
+/// A digest which the image, and the devices that it is written to, are hashed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    Sha256,
+    Sha512,
+}
+
+impl Checksum {
+    /// Parses a hexadecimal digest, such as those published alongside images. The
+    /// algorithm is determined by the length of the digest.
+    pub fn parse(hex: &str) -> Option<(Checksum, Vec<u8>)> {
+        let hex = hex.trim();
+        let checksum = match hex.len() {
+            64 => Checksum::Sha256,
+            128 => Checksum::Sha512,
+            _ => return None,
+        };
+
+        let digest = (0..hex.len())
+            .step_by(2)
+            .map(|at| hex.get(at..at + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+            .collect::<Option<Vec<u8>>>()?;
+
+        Some((checksum, digest))
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Checksum::Sha256 => "SHA-256",
+            Checksum::Sha512 => "SHA-512",
+        })
+    }
+}
+
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub fn new(checksum: Checksum) -> Self {
+        match checksum {
+            Checksum::Sha256 => Hasher::Sha256(Sha256::new()),
+            Checksum::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
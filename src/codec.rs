@@ -1,3 +1,4 @@
+use crate::{Phase, Rate};
 use futures_codec::{BytesMut, Decoder};
 use memchr::memchr;
 use serde::{Deserialize, Serialize};
@@ -14,15 +15,36 @@ pub enum Error {
 }
 
 /// Popsicle's IPC protocol
+///
+/// Each device ends with one of `Finished`, `Failed` or `Cancelled`.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub enum Message {
     Device(PathBuf),
+    Phase(PathBuf, Phase),
+    Failed(PathBuf, String),
+    Cancelled(PathBuf),
     Finished(PathBuf),
-    Message(PathBuf, String),
-    Set(PathBuf, u64),
+    /// The number of bytes written to the device, when only differing blocks are written.
+    Rewritten(PathBuf, u64),
+    /// The partitions which the kernel found on the device once it was written.
+    Partitions(PathBuf, Vec<PathBuf>),
+    /// The device was powered off once it was finished.
+    Ejected(PathBuf),
+    /// The device was finished, but could not be powered off.
+    EjectFailed(PathBuf, String),
+    Set(PathBuf, u64, Rate),
     Size(u64),
 }
 
+impl Message {
+    /// Encodes the message as a line of the IPC stream.
+    pub fn encode(&self) -> String {
+        let mut line = ron::to_string(self).expect("IPC messages are always serializable");
+        line.push('\n');
+        line
+    }
+}
+
 /// A decoder for creating a stream of messages from a reader
 ///
 /// ```ignore
@@ -0,0 +1,37 @@
+//! Powering off disks once they are flashed, so that they may be unplugged safely.
+
+use crate::busy::SystemRoot;
+use std::{ffi::OsStr, fs, io, path::Path};
+
+/// Powers off a disk once it has been flushed and closed, which may be substituted by
+/// frontends which have a better way of doing so, such as udisks. It is called from a
+/// blocking thread, for several disks at once.
+pub trait PowerOff: Send + Sync {
+    /// Powers off the disk whose node is at `disk`.
+    fn power_off(&self, disk: &Path) -> io::Result<()>;
+}
+
+/// Powers off disks through sysfs. USB disks are removed from the port that they are
+/// plugged into, which cuts their power, and other SCSI disks are deleted.
+#[derive(Clone, Debug, Default)]
+pub struct Sysfs {
+    pub root: SystemRoot,
+}
+
+impl PowerOff for Sysfs {
+    fn power_off(&self, disk: &Path) -> io::Result<()> {
+        let name = disk.file_name().and_then(OsStr::to_str).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "disk has no name in sysfs")
+        })?;
+
+        let device = fs::canonicalize(self.root.block(name).join("device"))?;
+        let usb = device
+            .ancestors()
+            .find(|parent| parent.join("idVendor").exists() && parent.join("remove").exists());
+
+        match usb {
+            Some(usb) => fs::write(usb.join("remove"), "1"),
+            None => fs::write(device.join("delete"), "1"),
+        }
+    }
+}
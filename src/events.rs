@@ -0,0 +1,62 @@
+//! A stream of the events of each device, for use in place of implementing `Progress`.
+//!
+//! A `Task` provides its own stream through `Task::events`, when its devices are
+//! subscribed with an `EventProgress`; those of a `Task` of any other `Progress` are not
+//! sent to it. Other workers, such as `Wipe` and `Backup`, send their events to a stream
+//! made with `channel`.
+
+use crate::{FlashEvent, Progress};
+use futures::{
+    channel::mpsc,
+    stream::{Stream, StreamExt},
+    task::{Context, Poll},
+};
+use std::pin::Pin;
+
+/// Creates a stream of the events of every device which is subscribed with the sender.
+///
+/// The stream ends once the sender, and the task which the devices were subscribed to,
+/// have both been dropped.
+pub fn channel<D: Clone>() -> (EventSender<D>, EventStream<D>) {
+    let (tx, rx) = mpsc::unbounded();
+    (EventSender { tx }, EventStream { rx })
+}
+
+#[derive(Clone)]
+pub struct EventSender<D> {
+    tx: mpsc::UnboundedSender<(D, FlashEvent)>,
+}
+
+impl<D: Clone> EventSender<D> {
+    /// The progress of a device, whose events are sent to the stream along with the device
+    /// that it is subscribed as.
+    pub fn progress(&self) -> EventProgress<D> {
+        EventProgress { tx: self.tx.clone() }
+    }
+}
+
+/// Sends the events of a device to its stream.
+pub struct EventProgress<D> {
+    tx: mpsc::UnboundedSender<(D, FlashEvent)>,
+}
+
+impl<D: Clone> Progress for EventProgress<D> {
+    type Device = D;
+
+    fn event(&mut self, device: &D, event: FlashEvent) {
+        // The stream may have been dropped, which leaves no one to inform.
+        let _ = self.tx.unbounded_send((device.clone(), event));
+    }
+}
+
+pub struct EventStream<D> {
+    rx: mpsc::UnboundedReceiver<(D, FlashEvent)>,
+}
+
+impl<D> Stream for EventStream<D> {
+    type Item = (D, FlashEvent);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
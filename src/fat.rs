@@ -0,0 +1,301 @@
+//! Formatting partitions with the FAT32 and exFAT filesystems, without the help of any
+//! `mkfs` program.
+
+use crate::restore::{Write, ALIGN};
+
+/// How many characters the label of each filesystem may hold.
+pub(crate) const FAT32_LABEL: usize = 11;
+pub(crate) const EXFAT_LABEL: usize = 11;
+
+/// How many clusters a FAT32 filesystem may have, beyond which it would be FAT16, or be
+/// out of range of its FAT.
+const FAT32_CLUSTERS: (u64, u64) = (65_525, 0x0FFF_FFF5);
+
+/// How many clusters an exFAT filesystem may have. It needs at least one for each of its
+/// allocation bitmap, its up-case table and its root directory.
+const EXFAT_CLUSTERS: (u64, u64) = (3, 0xFFFF_FFF5);
+
+/// The media descriptor of fixed disks, which the first entry of a FAT repeats.
+const MEDIA: u8 = 0xF8;
+
+/// Formats the partition at `offset`, of `sectors` sectors of `sector` bytes, as FAT32.
+/// `None` if it has too few sectors to hold the filesystem, or too many to be counted.
+pub(crate) fn fat32(
+    offset: u64,
+    sectors: u64,
+    sector: u64,
+    label: &str,
+    serial: u32,
+) -> Option<Vec<Write>> {
+    // The boot sector counts the sectors of the partition, and those before it, in 32 bits.
+    if sectors > u64::from(u32::MAX) || offset / sector > u64::from(u32::MAX) {
+        return None;
+    }
+
+    // The cluster sizes which Windows chooses by default.
+    let bytes = sectors * sector;
+    let mut cluster = match bytes {
+        _ if bytes <= 260 * 1024 * 1024 => 512,
+        _ if bytes <= 8 << 30 => 4 * 1024,
+        _ if bytes <= 16 << 30 => 8 * 1024,
+        _ if bytes <= 32 << 30 => 16 * 1024,
+        _ => 32 * 1024,
+    };
+
+    // Smaller partitions need smaller clusters to have enough of them.
+    let (per_cluster, reserved, fat_sectors, clusters) = loop {
+        let per_cluster = (cluster / sector).max(1);
+
+        // Every entry of the FAT takes four bytes, including the two which are reserved.
+        let fat_sectors = ((sectors / per_cluster + 2) * 4 + sector - 1) / sector;
+
+        // Padding the reserved sectors aligns the clusters to their own size.
+        let metadata = 32 + 2 * fat_sectors;
+        let reserved = 32 + (per_cluster - metadata % per_cluster) % per_cluster;
+        let clusters = sectors.checked_sub(reserved + 2 * fat_sectors)? / per_cluster;
+
+        if clusters >= FAT32_CLUSTERS.0 || per_cluster == 1 {
+            break (per_cluster, reserved, fat_sectors, clusters);
+        }
+
+        cluster /= 2;
+    };
+
+    if clusters < FAT32_CLUSTERS.0 || clusters >= FAT32_CLUSTERS.1 {
+        return None;
+    }
+
+    let volume_label = match label {
+        "" => *b"NO NAME    ",
+        label => fat32_label(label),
+    };
+
+    let mut boot = vec![0; sector as usize];
+    boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    boot[11..13].copy_from_slice(&(sector as u16).to_le_bytes());
+    boot[13] = per_cluster as u8;
+    boot[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+    boot[16] = 2;
+    boot[21] = MEDIA;
+    boot[24..26].copy_from_slice(&32u16.to_le_bytes());
+    boot[26..28].copy_from_slice(&64u16.to_le_bytes());
+    boot[28..32].copy_from_slice(&((offset / sector) as u32).to_le_bytes());
+    boot[32..36].copy_from_slice(&(sectors as u32).to_le_bytes());
+    boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+    // The root directory is the first cluster, and is followed by the FSInfo sector and
+    // then by the backup of the boot sector.
+    boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+    boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+    boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    boot[67..71].copy_from_slice(&serial.to_le_bytes());
+    boot[71..82].copy_from_slice(&volume_label);
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    // Every cluster is free, but for the root directory.
+    let mut info = vec![0; sector as usize];
+    info[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    info[488..492].copy_from_slice(&((clusters - 1) as u32).to_le_bytes());
+    info[492..496].copy_from_slice(&3u32.to_le_bytes());
+    info[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+
+    let mut fat = vec![0; sector as usize];
+    fat[0..4].copy_from_slice(&(0x0FFF_FF00 | u32::from(MEDIA)).to_le_bytes());
+    fat[4..8].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+    fat[8..12].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+
+    let root = reserved + 2 * fat_sectors;
+    let at = |sector_index: u64| offset + sector_index * sector;
+    let mut writes = vec![
+        Write::Zeroes { offset, length: (root + per_cluster) * sector },
+        Write::Data { offset: at(0), data: boot.clone() },
+        Write::Data { offset: at(1), data: info.clone() },
+        Write::Data { offset: at(6), data: boot },
+        Write::Data { offset: at(7), data: info },
+        Write::Data { offset: at(reserved), data: fat.clone() },
+        Write::Data { offset: at(reserved + fat_sectors), data: fat },
+    ];
+
+    // The label is also given as an entry of the root directory, which is where it is
+    // read from by most systems.
+    if !label.is_empty() {
+        let mut entry = vec![0; 32];
+        entry[0..11].copy_from_slice(&volume_label);
+        entry[11] = 0x08;
+        writes.push(Write::Data { offset: at(root), data: entry });
+    }
+
+    Some(writes)
+}
+
+/// Formats the partition at `offset`, of `sectors` sectors of `sector` bytes, as exFAT.
+/// `None` if it has too few sectors to hold the filesystem.
+pub(crate) fn exfat(
+    offset: u64,
+    sectors: u64,
+    sector: u64,
+    label: &str,
+    serial: u32,
+) -> Option<Vec<Write>> {
+    // The cluster sizes which Windows chooses by default.
+    let bytes = sectors * sector;
+    let cluster = match bytes {
+        _ if bytes <= 256 * 1024 * 1024 => 4 * 1024,
+        _ if bytes <= 32 << 30 => 32 * 1024,
+        _ => 128 * 1024,
+    }
+    .max(sector);
+    let per_cluster = cluster / sector;
+
+    // The FAT and the cluster heap are aligned to a mebibyte, leaving room before the FAT
+    // for the main and the backup boot regions.
+    let align = (ALIGN / sector).max(per_cluster);
+    let fat_offset = align;
+    let fat_length = ((sectors / per_cluster + 2) * 4 + sector - 1) / sector;
+    let heap_offset = (fat_offset + fat_length + align - 1) / align * align;
+    let clusters = sectors.checked_sub(heap_offset)? / per_cluster;
+    if clusters < EXFAT_CLUSTERS.0 || clusters > EXFAT_CLUSTERS.1 {
+        return None;
+    }
+
+    // The allocation bitmap, the up-case table and the root directory take up the first
+    // clusters of the heap, in that order.
+    let bitmap_length = (clusters + 7) / 8;
+    let upcase = upcase_table();
+    let chains =
+        [(bitmap_length + cluster - 1) / cluster, (upcase.len() as u64 + cluster - 1) / cluster, 1];
+
+    let used: u64 = chains.iter().sum();
+    if used > clusters {
+        return None;
+    }
+
+    let mut fat = vec![0; ((used + 2) * 4) as usize];
+    fat[0..4].copy_from_slice(&(0xFFFF_FF00 | u32::from(MEDIA)).to_le_bytes());
+    fat[4..8].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+    let mut firsts = [0; 3];
+    let mut next = 2;
+    for (first, length) in firsts.iter_mut().zip(chains) {
+        *first = next;
+        for cluster in next..next + length {
+            let link = if cluster + 1 == next + length { 0xFFFF_FFFF } else { cluster + 1 };
+            let entry = cluster as usize * 4;
+            fat[entry..entry + 4].copy_from_slice(&(link as u32).to_le_bytes());
+        }
+
+        next += length;
+    }
+
+    let mut bitmap = vec![0; (used as usize + 7) / 8];
+    for cluster in 0..used as usize {
+        bitmap[cluster / 8] |= 1 << (cluster % 8);
+    }
+
+    let [bitmap_cluster, upcase_cluster, root_cluster] = firsts;
+    let mut root = Vec::new();
+    if !label.is_empty() {
+        let mut entry = [0; 32];
+        entry[0] = 0x83;
+        for (index, unit) in label.encode_utf16().take(EXFAT_LABEL).enumerate() {
+            entry[1] += 1;
+            entry[2 + index * 2..4 + index * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+
+        root.extend_from_slice(&entry);
+    }
+
+    let mut entry = [0; 32];
+    entry[0] = 0x81;
+    entry[20..24].copy_from_slice(&(bitmap_cluster as u32).to_le_bytes());
+    entry[24..32].copy_from_slice(&bitmap_length.to_le_bytes());
+    root.extend_from_slice(&entry);
+
+    let mut entry = [0; 32];
+    entry[0] = 0x82;
+    entry[4..8].copy_from_slice(&checksum(&upcase, |_| false).to_le_bytes());
+    entry[20..24].copy_from_slice(&(upcase_cluster as u32).to_le_bytes());
+    entry[24..32].copy_from_slice(&(upcase.len() as u64).to_le_bytes());
+    root.extend_from_slice(&entry);
+
+    let mut boot = vec![0; sector as usize];
+    boot[0..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+    boot[3..11].copy_from_slice(b"EXFAT   ");
+    boot[64..72].copy_from_slice(&(offset / sector).to_le_bytes());
+    boot[72..80].copy_from_slice(&sectors.to_le_bytes());
+    boot[80..84].copy_from_slice(&(fat_offset as u32).to_le_bytes());
+    boot[84..88].copy_from_slice(&(fat_length as u32).to_le_bytes());
+    boot[88..92].copy_from_slice(&(heap_offset as u32).to_le_bytes());
+    boot[92..96].copy_from_slice(&(clusters as u32).to_le_bytes());
+    boot[96..100].copy_from_slice(&(root_cluster as u32).to_le_bytes());
+    boot[100..104].copy_from_slice(&serial.to_le_bytes());
+    boot[104..106].copy_from_slice(&0x0100u16.to_le_bytes());
+    boot[108] = sector.trailing_zeros() as u8;
+    boot[109] = per_cluster.trailing_zeros() as u8;
+    boot[110] = 1;
+    boot[111] = 0x80;
+    boot[112] = (used * 100 / clusters) as u8;
+    boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    // The boot sector is followed by eight extended boot sectors, the OEM parameters, a
+    // reserved sector, and a sector which repeats the checksum of all of them.
+    let mut region = boot;
+    for index in 1..11 {
+        let mut extended = vec![0; sector as usize];
+        if index < 9 {
+            let end = sector as usize;
+            extended[end - 4..end].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+        }
+
+        region.extend_from_slice(&extended);
+    }
+
+    // The flags and the percentage in use change while the volume is mounted, so they
+    // are not covered by the checksum.
+    let sum = checksum(&region, |index| matches!(index, 106 | 107 | 112));
+    region.extend(sum.to_le_bytes().iter().cycle().take(sector as usize));
+
+    let at = |sector_index: u64| offset + sector_index * sector;
+    let heap = |cluster: u64| at(heap_offset + (cluster - 2) * per_cluster);
+    Some(vec![
+        Write::Zeroes { offset, length: (heap_offset + used * per_cluster) * sector },
+        Write::Data { offset: at(0), data: region.clone() },
+        Write::Data { offset: at(12), data: region },
+        Write::Data { offset: at(fat_offset), data: fat },
+        Write::Data { offset: heap(bitmap_cluster), data: bitmap },
+        Write::Data { offset: heap(upcase_cluster), data: upcase },
+        Write::Data { offset: heap(root_cluster), data: root },
+    ])
+}
+
+/// Pads the label with spaces, in the upper case which FAT labels are stored in.
+fn fat32_label(label: &str) -> [u8; 11] {
+    let mut padded = [b' '; 11];
+    for (byte, character) in padded.iter_mut().zip(label.bytes()) {
+        *byte = character.to_ascii_uppercase();
+    }
+
+    padded
+}
+
+/// The compressed up-case table of an exFAT filesystem, which maps the lower case ASCII
+/// letters to upper case, and every other character to itself.
+fn upcase_table() -> Vec<u8> {
+    // A run of characters which map to themselves is given by 0xFFFF and its length.
+    let mut table = vec![0xFFFF, u16::from(b'a')];
+    table.extend(u16::from(b'A')..=u16::from(b'Z'));
+    table.extend([0xFFFF, (0x1_0000 - u32::from(b'z') - 1) as u16]);
+    table.iter().flat_map(|unit| unit.to_le_bytes()).collect()
+}
+
+/// The checksum that exFAT keeps of its boot region and its up-case table, which skips
+/// the bytes that `skip` returns true for.
+fn checksum(data: &[u8], skip: impl Fn(usize) -> bool) -> u32 {
+    data.iter()
+        .enumerate()
+        .filter(|&(index, _)| !skip(index))
+        .fold(0u32, |sum, (_, &byte)| sum.rotate_right(1).wrapping_add(u32::from(byte)))
+}
@@ -0,0 +1,637 @@
+use crate::{
+    bmap::Mapping,
+    sparse::{Sparse, SparseHeader, SPARSE_HEADER_SIZE, SPARSE_MAGIC},
+    vdisk::{self, VirtualDisk},
+    Bmap, ImageError,
+};
+use async_compression::futures::bufread::{BzDecoder, GzipDecoder, XzDecoder, ZstdDecoder};
+use async_std::fs::File;
+use futures::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader, Cursor},
+    task::{Context, Poll},
+    FutureExt,
+};
+use memchr::memmem;
+use std::{
+    fmt, fs,
+    io::{self, Seek, SeekFrom},
+    os::unix::fs::{FileExt, FileTypeExt, MetadataExt},
+    pin::Pin,
+};
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const XZ_MAGIC: &[u8] = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+const BZIP2_MAGIC: &[u8] = b"BZh";
+
+/// How much of a compressed image is decompressed to find the format of what it holds,
+/// which is enough for a whole bzip2 block.
+const PEEK: u64 = 2 * 1024 * 1024;
+
+/// The compression format that an image is stored in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    /// Determines the compression format from the first bytes of an image.
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else if magic.starts_with(&ZSTD_MAGIC.to_le_bytes()) {
+            Compression::Zstd
+        } else if magic.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if magic.starts_with(BZIP2_MAGIC) {
+            Compression::Bzip2
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "raw",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+            Compression::Bzip2 => "bzip2",
+        })
+    }
+}
+
+/// The container format of the image, once it has been decompressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Raw,
+    AndroidSparse,
+    Qcow2,
+    Vhd,
+    Vhdx,
+    Vmdk,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Format::Raw => "raw",
+            Format::AndroidSparse => "Android sparse",
+            Format::Qcow2 => "qcow2",
+            Format::Vhd => "VHD",
+            Format::Vhdx => "VHDX",
+            Format::Vmdk => "VMDK",
+        })
+    }
+}
+
+/// A region of the expanded image that holds data, which must be read from
+/// the image and written at `offset` on each device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Identifies what an image is read from: devices by their device number, as a device may
+/// have several nodes, and files by their inode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Origin {
+    Device(u64),
+    File(u64, u64),
+}
+
+impl Origin {
+    pub fn of(metadata: &fs::Metadata) -> Self {
+        match metadata.file_type().is_block_device() {
+            true => Origin::Device(metadata.rdev()),
+            false => Origin::File(metadata.dev(), metadata.ino()),
+        }
+    }
+}
+
+pub(crate) enum Reader {
+    Raw(File),
+    Gzip(GzipDecoder<BufReader<File>>),
+    Xz(XzDecoder<BufReader<File>>),
+    Zstd(ZstdDecoder<BufReader<File>>),
+    Bzip2(BzDecoder<BufReader<File>>),
+}
+
+impl Reader {
+    fn new(file: File, compression: Compression) -> Self {
+        match compression {
+            Compression::None => Reader::Raw(file),
+            Compression::Gzip => {
+                let mut decoder = GzipDecoder::new(BufReader::new(file));
+                decoder.multiple_members(true);
+                Reader::Gzip(decoder)
+            }
+            Compression::Xz => {
+                let mut decoder = XzDecoder::new(BufReader::new(file));
+                decoder.multiple_members(true);
+                Reader::Xz(decoder)
+            }
+            Compression::Zstd => {
+                let mut decoder = ZstdDecoder::new(BufReader::new(file));
+                decoder.multiple_members(true);
+                Reader::Zstd(decoder)
+            }
+            Compression::Bzip2 => {
+                let mut decoder = BzDecoder::new(BufReader::new(file));
+                decoder.multiple_members(true);
+                Reader::Bzip2(decoder)
+            }
+        }
+    }
+
+    fn into_inner(self) -> File {
+        match self {
+            Reader::Raw(file) => file,
+            Reader::Gzip(decoder) => decoder.into_inner().into_inner(),
+            Reader::Xz(decoder) => decoder.into_inner().into_inner(),
+            Reader::Zstd(decoder) => decoder.into_inner().into_inner(),
+            Reader::Bzip2(decoder) => decoder.into_inner().into_inner(),
+        }
+    }
+
+    /// Advances the reader by `length` bytes without returning them.
+    pub async fn skip(&mut self, length: u64) -> io::Result<()> {
+        let skipped = match self {
+            Reader::Raw(file) => {
+                file.seek(SeekFrom::Current(length as i64)).await?;
+                length
+            }
+            _ => futures::io::copy(self.take(length), &mut futures::io::sink()).await?,
+        };
+
+        if skipped < length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "image ended prematurely"));
+        }
+
+        Ok(())
+    }
+
+    /// Seeks to an absolute position, which is only possible in uncompressed images.
+    pub async fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        match self {
+            Reader::Raw(file) => file.seek(SeekFrom::Start(offset)).await.map(|_| ()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot seek within a compressed image",
+            )),
+        }
+    }
+}
+
+impl AsyncRead for Reader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Reader::Raw(file) => Pin::new(file).poll_read(cx, buf),
+            Reader::Gzip(decoder) => Pin::new(decoder).poll_read(cx, buf),
+            Reader::Xz(decoder) => Pin::new(decoder).poll_read(cx, buf),
+            Reader::Zstd(decoder) => Pin::new(decoder).poll_read(cx, buf),
+            Reader::Bzip2(decoder) => Pin::new(decoder).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Determines which regions of the image are written, and where.
+enum Layout {
+    /// The whole image is written as it is read.
+    Raw { started: bool },
+    /// Only the ranges recorded in a block map are written.
+    Mapped(Mapping),
+    /// The image is expanded from the chunks of an Android sparse image.
+    Sparse(Sparse),
+    /// The allocated clusters of a virtual machine disk are read from wherever they are stored,
+    /// and the clusters between them are skipped, or read as zeroes when they are filled.
+    Virtual(VirtualDisk),
+}
+
+impl Layout {
+    fn reset(&mut self) {
+        match self {
+            Layout::Raw { started } => *started = false,
+            Layout::Mapped(mapping) => mapping.reset(),
+            Layout::Sparse(sparse) => sparse.reset(),
+            Layout::Virtual(disk) => disk.reset(),
+        }
+    }
+}
+
+/// An image source which transparently decompresses and expands the image as it is read.
+pub struct Image {
+    reader: Option<Reader>,
+    layout: Layout,
+    compression: Compression,
+    format: Format,
+    size: Option<u64>,
+    file_size: u64,
+    origin: Origin,
+}
+
+impl Image {
+    /// Detects the compression and container format of the image from its magic bytes,
+    /// and determines the size of the image once it has been expanded, where it is known.
+    pub fn new(file: fs::File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        let file_size = metadata.len();
+
+        let mut magic = [0u8; 8];
+        let read = read_at_most(&file, &mut magic, 0)?;
+        let compression = Compression::detect(&magic[..read]);
+
+        let (format, layout, size) = match compression {
+            Compression::None if magic.starts_with(&SPARSE_MAGIC.to_le_bytes()) => {
+                let sparse = Sparse::new(SparseHeader::read(&file)?);
+                let size = sparse.size();
+                (Format::AndroidSparse, Layout::Sparse(sparse), Some(size))
+            }
+            Compression::None => match vdisk::probe(&file, file_size, &magic[..read])? {
+                Some((format, disk)) => {
+                    let size = disk.size();
+                    (format, Layout::Virtual(disk), Some(size))
+                }
+                None => (Format::Raw, Layout::Raw { started: false }, Some(file_size)),
+            },
+            // Sparse images are expanded as they are decompressed, as they are only ever
+            // read through from the start. The clusters of virtual machine disks are read
+            // from wherever they are stored, which a decompressor cannot seek to.
+            _ => {
+                let mut header = [0u8; SPARSE_HEADER_SIZE];
+                let peeked = &header[..peek(&file, file_size, compression, &mut header)?];
+                if let Some(format) = vdisk::detect(peeked) {
+                    let why = ImageError::CompressedDisk { format, compression };
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, why));
+                } else if peeked.starts_with(&SPARSE_MAGIC.to_le_bytes()) {
+                    let sparse = Sparse::new(SparseHeader::parse(peeked)?);
+                    let size = sparse.size();
+                    (Format::AndroidSparse, Layout::Sparse(sparse), Some(size))
+                } else {
+                    // Sizes are only taken from what the container records, where it can be
+                    // found. Those which cannot be, such as when data trails the last frame,
+                    // may still be decompressed as they are read.
+                    let size = match compression {
+                        Compression::Gzip => gzip_size(&file, file_size),
+                        Compression::Xz => xz_size(&file, file_size),
+                        Compression::Zstd => zstd_size(&file, file_size),
+                        _ => Ok(None),
+                    };
+
+                    let size = size.ok().flatten();
+
+                    (Format::Raw, Layout::Raw { started: false }, size)
+                }
+            }
+        };
+
+        Ok(Image {
+            reader: Some(Reader::new(File::from(file), compression)),
+            layout,
+            compression,
+            format,
+            size,
+            file_size,
+            origin: Origin::of(&metadata),
+        })
+    }
+
+    /// Reads a device, such as a drive which is cloned to others, as a raw image which
+    /// spans the whole of it. Nothing is detected from its contents, which may be anything.
+    pub fn from_device(mut file: fs::File) -> io::Result<Self> {
+        let origin = Origin::of(&file.metadata()?);
+        let size = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        Ok(Image {
+            reader: Some(Reader::new(File::from(file), Compression::None)),
+            layout: Layout::Raw { started: false },
+            compression: Compression::None,
+            format: Format::Raw,
+            size: Some(size),
+            file_size: size,
+            origin,
+        })
+    }
+
+    /// The compression format that was detected.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// The container format that was detected.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// The number of bytes that the image expands to when it is written.
+    ///
+    /// This is `None` if the size cannot be known without decompressing the image, as is
+    /// the case with bzip2, large gzip files, and zstd frames which do not declare it.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// The size of the image file as it is stored on disk.
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// What the image is read from.
+    pub(crate) fn origin(&self) -> Origin {
+        self.origin
+    }
+
+    /// Restricts writing and validation to the ranges recorded in the block map.
+    pub fn set_bmap(&mut self, bmap: Bmap) -> Result<(), ImageError> {
+        if self.format != Format::Raw {
+            return Err(ImageError::BmapFormat { format: self.format });
+        }
+
+        // The block map records the exact size, which some compression formats do not.
+        self.size = Some(bmap.image_size);
+        self.layout = Layout::Mapped(Mapping::new(bmap));
+        Ok(())
+    }
+
+    /// Reads the unallocated clusters of virtual machine disks as extents of zeroes, rather
+    /// than skipping them, for devices which are not erased before they are written.
+    pub fn set_fill_unallocated(&mut self, fill: bool) {
+        if let Layout::Virtual(disk) = &mut self.layout {
+            disk.fill = fill;
+        }
+    }
+
+    /// Whether the image is checked against the checksums of a block map as it is read.
+    /// A mismatch is only found once the range which holds it has been read in full.
+    pub(crate) fn has_checksums(&self) -> bool {
+        match &self.layout {
+            Layout::Mapped(mapping) => mapping.has_checksums(),
+            _ => false,
+        }
+    }
+
+    /// Seeks back to the start of the image, and resets the decompressor.
+    pub async fn rewind(&mut self) -> io::Result<()> {
+        let mut file = self.reader.take().expect("image reader missing").into_inner();
+        let result = file.seek(SeekFrom::Start(0)).await;
+        self.reader = Some(Reader::new(file, self.compression));
+        self.layout.reset();
+        result.map(|_| ())
+    }
+
+    /// Moves on to the next region of the image that should be written. The data
+    /// of that region is then read from the image, which ends with the region.
+    pub async fn next_extent(&mut self) -> io::Result<Option<Extent>> {
+        let reader = self.reader.as_mut().expect("image reader missing");
+        match &mut self.layout {
+            Layout::Raw { started: true } => Ok(None),
+            Layout::Raw { started } => {
+                *started = true;
+                Ok(Some(Extent { offset: 0, length: u64::MAX }))
+            }
+            Layout::Mapped(mapping) => mapping.next_extent(reader).await,
+            Layout::Sparse(sparse) => sparse.next_extent(reader).await,
+            Layout::Virtual(disk) => disk.next_extent(reader).await,
+        }
+    }
+}
+
+impl AsyncRead for Image {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let reader = this.reader.as_mut().expect("image reader missing");
+        match &mut this.layout {
+            Layout::Raw { .. } => Pin::new(reader).poll_read(cx, buf),
+            Layout::Mapped(mapping) => mapping.poll_read(reader, cx, buf),
+            Layout::Sparse(sparse) => sparse.poll_read(reader, cx, buf),
+            Layout::Virtual(disk) => disk.poll_read(reader, cx, buf),
+        }
+    }
+}
+
+fn read_at_most(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64)? {
+            0 => break,
+            count => read += count,
+        }
+    }
+
+    Ok(read)
+}
+
+/// Decompresses the start of the image into `buf`, returning how many bytes it gave. As
+/// the compressed data is all in memory, decompressing it never waits. Data which cannot
+/// be decompressed gives nothing, leaving the error to be found once the image is read.
+fn peek(
+    file: &fs::File,
+    file_size: u64,
+    compression: Compression,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let mut data = vec![0; file_size.min(PEEK) as usize];
+    file.read_exact_at(&mut data, 0)?;
+
+    let input = Cursor::new(data);
+    let mut decoder: Box<dyn AsyncRead + Unpin> = match compression {
+        Compression::None => return Ok(0),
+        Compression::Gzip => Box::new(GzipDecoder::new(input)),
+        Compression::Xz => Box::new(XzDecoder::new(input)),
+        Compression::Zstd => Box::new(ZstdDecoder::new(input)),
+        Compression::Bzip2 => Box::new(BzDecoder::new(input)),
+    };
+
+    let mut read = 0;
+    while read < buf.len() {
+        match decoder.read(&mut buf[read..]).now_or_never() {
+            Some(Ok(count)) if count != 0 => read += count,
+            _ => break,
+        }
+    }
+
+    Ok(read)
+}
+
+fn read_array<const N: usize>(file: &fs::File, offset: u64) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf)
+}
+
+fn invalid_data(why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, why)
+}
+
+/// Gzip records the size of each member modulo 2^32 in its trailer, which is only the size
+/// of the image when the file holds a single member that cannot have expanded past 4 GiB.
+/// Deflate expands data at most 1032 times, so only files of a few MiB are sized, and these
+/// are searched for the header of a second member. Other files are not sized at all.
+fn gzip_size(file: &fs::File, file_size: u64) -> io::Result<Option<u64>> {
+    const MAX_RATIO: u64 = 1032;
+
+    if file_size < 18 || file_size * MAX_RATIO >= 1 << 32 {
+        return Ok(None);
+    }
+
+    let mut data = vec![0; file_size as usize];
+    file.read_exact_at(&mut data, 0)?;
+
+    // Members begin with the magic, followed by the deflate method.
+    if memmem::find(&data[1..], &[GZIP_MAGIC[0], GZIP_MAGIC[1], 8]).is_some() {
+        return Ok(None);
+    }
+
+    let trailer = &data[data.len() - 4..];
+    Ok(Some(u64::from(u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]))))
+}
+
+/// Sums the uncompressed sizes recorded in the index of every stream in the file,
+/// walking backwards from the final stream footer.
+fn xz_size(file: &fs::File, file_size: u64) -> io::Result<Option<u64>> {
+    let mut end = file_size;
+    let mut total = 0u64;
+
+    while end > 0 {
+        // Streams may be followed by padding made of null bytes, in multiples of four.
+        while end >= 4 && read_array::<4>(file, end - 4)? == [0; 4] {
+            end -= 4;
+        }
+
+        if end < 24 {
+            return Err(invalid_data("xz stream is truncated"));
+        }
+
+        let footer = read_array::<12>(file, end - 12)?;
+        if &footer[10..] != b"YZ" {
+            return Err(invalid_data("xz stream footer is missing"));
+        }
+
+        let backward_size = u32::from_le_bytes([footer[4], footer[5], footer[6], footer[7]]);
+        let index_size = (u64::from(backward_size) + 1) * 4;
+        let index_start = (end - 12)
+            .checked_sub(index_size)
+            .ok_or_else(|| invalid_data("xz index is larger than the stream"))?;
+
+        let mut index = vec![0u8; index_size as usize];
+        file.read_exact_at(&mut index, index_start)?;
+
+        if index[0] != 0 {
+            return Err(invalid_data("xz index indicator is invalid"));
+        }
+
+        let mut pos = 1;
+        let records = xz_varint(&index, &mut pos)?;
+        let mut blocks_size = 0u64;
+        for _ in 0..records {
+            let unpadded = xz_varint(&index, &mut pos)?;
+            total += xz_varint(&index, &mut pos)?;
+            blocks_size += (unpadded + 3) & !3;
+        }
+
+        let stream_size = 12 + blocks_size + index_size + 12;
+        end = end
+            .checked_sub(stream_size)
+            .ok_or_else(|| invalid_data("xz blocks are larger than the stream"))?;
+    }
+
+    Ok(Some(total))
+}
+
+fn xz_varint(buf: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..63).step_by(7) {
+        let byte = *buf.get(*pos).ok_or_else(|| invalid_data("xz index is truncated"))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(invalid_data("xz index contains an invalid integer"))
+}
+
+/// Sums the content size declared in the header of every frame. Returns `None` if any
+/// frame was written without declaring its content size.
+fn zstd_size(file: &fs::File, file_size: u64) -> io::Result<Option<u64>> {
+    let mut pos = 0;
+    let mut total = 0u64;
+
+    while pos < file_size {
+        let magic = u32::from_le_bytes(read_array(file, pos)?);
+        if magic & 0xFFFF_FFF0 == 0x184D_2A50 {
+            let skip = u32::from_le_bytes(read_array(file, pos + 4)?);
+            pos += 8 + u64::from(skip);
+            continue;
+        } else if magic != ZSTD_MAGIC {
+            return Err(invalid_data("zstd frame magic is invalid"));
+        }
+
+        let [descriptor] = read_array::<1>(file, pos + 4)?;
+        pos += 5;
+
+        let single_segment = descriptor & 0x20 != 0;
+        let has_checksum = descriptor & 0x04 != 0;
+        let dictionary_id_size = [0, 1, 2, 4][usize::from(descriptor & 0x03)];
+        let content_size_size = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => return Ok(None),
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+
+        if !single_segment {
+            pos += 1;
+        }
+
+        pos += dictionary_id_size;
+
+        let mut content_size = [0u8; 8];
+        file.read_exact_at(&mut content_size[..content_size_size], pos)?;
+        pos += content_size_size as u64;
+
+        total += match content_size_size {
+            2 => u64::from_le_bytes(content_size) + 256,
+            _ => u64::from_le_bytes(content_size),
+        };
+
+        loop {
+            let [a, b, c] = read_array::<3>(file, pos)?;
+            let header = u32::from_le_bytes([a, b, c, 0]);
+            let block_size = match (header >> 1) & 0x03 {
+                1 => 1,
+                _ => u64::from(header >> 3),
+            };
+
+            pos += 3 + block_size;
+
+            if header & 0x01 != 0 {
+                break;
+            }
+        }
+
+        if has_checksum {
+            pos += 4;
+        }
+    }
+
+    Ok(Some(total))
+}
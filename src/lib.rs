@@ -1,3 +1,10 @@
+//! Flashes images to many devices at once, and wipes, restores and backs them up.
+//!
+//! The events of each device are given to the `Progress` that it is subscribed with. A
+//! `Task` can only provide them as a stream, through `Task::events`, when that progress is
+//! an `EventProgress`. Otherwise, the progress may pass them on to an `EventProgress` of a
+//! stream made with `events::channel`.
+
 #[macro_use]
 extern crate anyhow;
 #[macro_use]
@@ -5,27 +12,62 @@ extern crate derive_new;
 #[macro_use]
 extern crate thiserror;
 
-pub extern crate mnt;
+/// Popsicle no longer reads the mount table with `mnt`, so this is only kept for those
+/// who used it through popsicle, and will be removed in the next release.
+#[deprecated(since = "1.3.3", note = "depend on the `mnt` crate directly")]
+pub mod mnt {
+    pub use ::mnt::*;
+}
 
 pub mod codec;
+pub mod events;
 
+mod backup;
+mod blockdev;
+mod bmap;
+mod busy;
+mod checksum;
+mod eject;
+mod fat;
+mod image;
+mod partitions;
+mod policy;
+mod rate;
+mod restore;
+mod resume;
+mod sparse;
+mod table;
 mod task;
+mod unmount;
+mod vdisk;
+mod verify;
+mod wipe;
 
-pub use self::task::{Progress, Task};
+pub use self::backup::{BackedUp, Backup, BackupOutput};
+pub use self::bmap::{Bmap, BmapError, BmapRange};
+pub use self::busy::{usage, SystemRoot, Usage};
+pub use self::checksum::Checksum;
+pub use self::eject::{PowerOff, Sysfs};
+pub use self::image::{Compression, Extent, Format, Image};
+pub use self::partitions::{reread_partitions, settle_partitions};
+pub use self::policy::Policy;
+pub use self::rate::Rate;
+pub use self::restore::{Filesystem, PartitionTable, Restore, RestoreError};
+pub use self::resume::{DeviceId, ImageId, Journal};
+pub use self::task::{CancelHandle, Erase, FlashEvent, Phase, Progress, Task, Validation};
+pub use self::unmount::{release, MountBackend, Native, Unmount};
+pub use self::wipe::{Wipe, WipeMethod};
 
 use anyhow::Context;
-use as_result::MapResult;
 use async_std::{
     fs::{self, File, OpenOptions},
     os::unix::fs::OpenOptionsExt,
     path::{Path, PathBuf},
 };
 use futures::{executor, prelude::*};
-use mnt::MountEntry;
 use std::{
     io,
-    os::unix::{ffi::OsStrExt, fs::FileTypeExt},
-    process::Command,
+    os::unix::{fs::FileTypeExt, io::AsRawFd},
 };
 use usb_disk_probe::stream::UsbDiskProbe;
 
@@ -42,6 +84,16 @@ pub enum ImageError {
     ReadError { why: io::Error },
     #[error("reached EOF prematurely")]
     Eof,
+    #[error("image does not match the block map checksum for blocks {}-{}", start, end)]
+    BmapChecksum { start: u64, end: u64 },
+    #[error("block maps cannot be used with {} images", format)]
+    BmapFormat { format: Format },
+    #[error("sparse image checksum mismatch at offset {}", offset)]
+    SparseChecksum { offset: u64 },
+    #[error("image does not match the expected {} checksum", checksum)]
+    Checksum { checksum: Checksum },
+    #[error("{} images cannot be read through {} compression, and must be decompressed first", format, compression)]
+    CompressedDisk { format: Format, compression: Compression },
 }
 
 #[derive(Debug, Error)]
@@ -58,31 +110,90 @@ pub enum DiskError {
     #[error("unable to find disk '{}': {}", disk.display(), why)]
     NoDisk { disk: Box<Path>, why: io::Error },
     #[error("failed to unmount {}: {}", path.display(), why)]
-    UnmountCommand { path: Box<Path>, why: io::Error },
+    Unmount { path: Box<Path>, why: io::Error },
+    #[error("failed to deactivate swap on {}: {}", path.display(), why)]
+    SwapOff { path: Box<Path>, why: io::Error },
+    #[error("error using disk '{}': {} kept being mounted again", arg.display(), path.display())]
+    Remounted { arg: Box<Path>, path: Box<Path> },
     #[error("error using disk '{}': {} already mounted at {}", arg.display(), source_.display(), dest.display())]
     AlreadyMounted { arg: Box<Path>, source_: Box<Path>, dest: Box<Path> },
+    #[error("unable to determine whether disk '{}' is in use: {}", arg.display(), why)]
+    Inspect { arg: Box<Path>, why: io::Error },
+    #[error("error using disk '{}': {} is in use as swap", arg.display(), swap.display())]
+    Swap { arg: Box<Path>, swap: Box<Path> },
+    #[error("error using disk '{}': {} is a member of the RAID array {}", arg.display(), member, array)]
+    RaidMember { arg: Box<Path>, member: String, array: String },
+    #[error("error using disk '{}': {} is a physical volume of the LVM volume {}", arg.display(), member, volume)]
+    LogicalVolume { arg: Box<Path>, member: String, volume: String },
+    #[error("error using disk '{}': {} is unlocked as the encrypted device {}", arg.display(), member, mapping)]
+    Encrypted { arg: Box<Path>, member: String, mapping: String },
+    #[error("error using disk '{}': {} is mapped by the device mapper to {}", arg.display(), member, mapping)]
+    DeviceMapper { arg: Box<Path>, member: String, mapping: String },
+    #[error("error using disk '{}': {} is held by {}", arg.display(), member, holder)]
+    Held { arg: Box<Path>, member: String, holder: String },
+    #[error("refusing to write to disk '{}': it holds the {} filesystem", arg.display(), mount.display())]
+    SystemDisk { arg: Box<Path>, mount: Box<Path> },
+    #[error("refusing to write to disk '{}': it is not removable", arg.display())]
+    NotRemovable { arg: Box<Path> },
+    #[error("refusing to write to disk '{}': its size of {} bytes is over the limit of {} bytes", arg.display(), size, max)]
+    TooLarge { arg: Box<Path>, size: u64, max: u64 },
+    #[error("refusing to write to disk '{}': it is the source of the clone", arg.display())]
+    Protected { arg: Box<Path> },
+    #[error("refusing to write to the disk which the image is read from")]
+    Source,
     #[error("'{}' is not a block device", arg.display())]
     NotABlock { arg: Box<Path> },
     #[error("unable to get metadata of disk '{}': {}", arg.display(), why)]
     Metadata { arg: Box<Path>, why: io::Error },
     #[error("unable to open disk '{}': {}", disk.display(), why)]
     Open { disk: Box<Path>, why: io::Error },
-    #[error("error writing disk '{}': {}", disk.display(), why)]
-    Write { disk: Box<Path>, why: io::Error },
-    #[error("error writing disk '{}': reached EOF", disk.display())]
-    WriteEOF { disk: Box<Path> },
-    #[error("unable to flush disk '{}': {}", disk.display(), why)]
-    Flush { disk: Box<Path>, why: io::Error },
-    #[error("error seeking disk '{}': seeked to {} instead of 0", disk.display(), invalid)]
-    SeekInvalid { disk: Box<Path>, invalid: u64 },
-    #[error("error seeking disk '{}': {}", disk.display(), why)]
-    Seek { disk: Box<Path>, why: io::Error },
-    #[error("error verifying disk '{}': {}", disk.display(), why)]
-    Verify { disk: Box<Path>, why: io::Error },
-    #[error("error verifying disk '{}': reached EOF", disk.display())]
-    VerifyEOF { disk: Box<Path> },
-    #[error("error verifying disk '{}': mismatch at {}:{}", disk.display(), x, y)]
-    VerifyMismatch { disk: Box<Path>, x: usize, y: usize },
+    #[error("unable to open disk '{}': it is in use by the kernel", disk.display())]
+    Exclusive { disk: Box<Path> },
+    #[error("unable to lock disk '{}': it is being used by another program", disk.display())]
+    Locked { disk: Box<Path> },
+    #[error("unable to erase disk: {}", why)]
+    Erase { why: io::Error },
+    #[error("error reading disk: {}", why)]
+    Read { why: io::Error },
+    #[error("error writing disk: {}", why)]
+    Write { why: io::Error },
+    #[error("error writing disk: reached EOF")]
+    WriteEOF,
+    #[error("unable to flush disk: {}", why)]
+    Flush { why: io::Error },
+    #[error("error seeking disk to {}: {}", offset, why)]
+    Seek { offset: u64, why: io::Error },
+    #[error("error verifying disk: {}", why)]
+    Verify { why: io::Error },
+    #[error("error verifying disk: reached EOF")]
+    VerifyEOF,
+    #[error("error verifying disk: mismatch at byte {}", offset)]
+    VerifyMismatch { offset: u64 },
+    #[error("error verifying disk: does not match the {} digest of the image", checksum)]
+    VerifyDigest { checksum: Checksum },
+    #[error("unable to reread partition table: {}", why)]
+    Reread { why: io::Error },
+    #[error("unable to power off disk: {}", why)]
+    Eject { why: io::Error },
+    #[error("unable to restore disk: {}", _0)]
+    Restore(RestoreError),
+    #[error("unable to write backup image: {}", why)]
+    Backup { why: io::Error },
+    #[error("{}", _0)]
+    Image(ImageError),
+}
+
+impl DiskError {
+    /// Whether the policy refused the disk, rather than the disk being unusable.
+    pub fn is_refused(&self) -> bool {
+        matches!(
+            self,
+            DiskError::SystemDisk { .. }
+                | DiskError::NotRemovable { .. }
+                | DiskError::TooLarge { .. }
+                | DiskError::Protected { .. }
+        )
+    }
 }
 
 pub async fn usb_disk_devices(disks: &mut Vec<Box<Path>>) -> anyhow::Result<()> {
@@ -107,63 +218,158 @@ pub fn get_disk_args(disks: &mut Vec<Box<Path>>) -> Result<(), DiskError> {
     )
 }
 
+/// Opens the disks, refusing those which the default policy does not permit. The mounts of
+/// the disks are read from the system, so `_mounts` is unused, and is only kept until the
+/// next release for those who pass them.
 pub async fn disks_from_args<D: Iterator<Item = Box<Path>>>(
     disk_args: D,
-    mounts: &[MountEntry],
+    _mounts: &[::mnt::MountEntry],
+    unmount: bool,
+) -> Result<Vec<(Box<Path>, File)>, DiskError> {
+    let (root, policy) = (SystemRoot::default(), Policy::default());
+    disks_from_args_in(&root, &policy, &mut Native, disk_args, unmount).await
+}
+
+/// Opens the disks like `disks_from_args`, refusing those which the policy does not permit,
+/// and inspecting sysfs and procfs beneath `root` to determine whether they are in use.
+/// When `unmount` is set, the disks are released with the backend.
+pub async fn disks_from_args_in<D: Iterator<Item = Box<Path>>>(
+    root: &SystemRoot,
+    policy: &Policy,
+    backend: &mut dyn MountBackend,
+    disk_args: D,
     unmount: bool,
 ) -> Result<Vec<(Box<Path>, File)>, DiskError> {
     let mut disks = Vec::new();
 
     for disk_arg in disk_args {
-        let canonical_path = fs::canonicalize(&disk_arg)
-            .await
-            .map_err(|why| DiskError::NoDisk { disk: disk_arg.clone(), why })?;
-
-        for mount in mounts {
-            if mount.spec.as_bytes().starts_with(canonical_path.as_os_str().as_bytes()) {
-                if unmount {
-                    eprintln!(
-                        "unmounting '{}': {:?} is mounted at {:?}",
-                        disk_arg.display(),
-                        mount.spec,
-                        mount.file
-                    );
-
-                    Command::new("umount").arg(&mount.spec).status().map_result().map_err(
-                        |why| DiskError::UnmountCommand {
-                            path: PathBuf::from(mount.spec.clone()).into_boxed_path(),
-                            why,
-                        },
-                    )?;
-                } else {
-                    return Err(DiskError::AlreadyMounted {
-                        arg: disk_arg.clone(),
-                        source_: PathBuf::from(mount.spec.clone()).into_boxed_path(),
-                        dest: PathBuf::from(mount.file.clone()).into_boxed_path(),
-                    });
-                }
-            }
-        }
-
-        let metadata = canonical_path
-            .metadata()
-            .await
-            .map_err(|why| DiskError::Metadata { arg: disk_arg.clone(), why })?;
-
-        if !metadata.file_type().is_block_device() {
-            return Err(DiskError::NotABlock { arg: disk_arg.clone() });
-        }
+        let canonical_path = check_disk(root, Some(policy), backend, &disk_arg, unmount).await?;
 
+        // The disk is opened exclusively, which fails if the kernel has mounted any of it.
         let disk = OpenOptions::new()
             .read(true)
             .write(true)
-            .custom_flags(libc::O_SYNC)
+            .custom_flags(libc::O_SYNC | libc::O_EXCL)
             .open(&canonical_path)
             .await
-            .map_err(|why| DiskError::Open { disk: disk_arg.clone(), why })?;
+            .map_err(|why| open_error(&disk_arg, why))?;
+
+        lock_disk(&disk).map_err(|why| lock_error(&disk_arg, why))?;
 
         disks.push((canonical_path.into_boxed_path(), disk));
     }
 
     Ok(disks)
 }
+
+/// Opens the disk which other disks are cloned from, which is checked like the disks
+/// of `disks_from_args`, and released when `unmount` is set. It is opened as a standard
+/// file, which `Image::from_device` reads.
+pub async fn source_from_arg(
+    disk_arg: Box<Path>,
+    unmount: bool,
+) -> Result<(Box<Path>, std::fs::File), DiskError> {
+    source_from_arg_in(&SystemRoot::default(), &mut Native, disk_arg, unmount).await
+}
+
+/// Opens the source of a clone like `source_from_arg`, inspecting sysfs and procfs beneath
+/// `root`. Reading it is harmless, so no policy is applied to it, but it is only opened
+/// for reading, and exclusively so that it is not mounted while it is read. A shared lock
+/// is held on it, which refuses any program that locks it to write to it.
+pub async fn source_from_arg_in(
+    root: &SystemRoot,
+    backend: &mut dyn MountBackend,
+    disk_arg: Box<Path>,
+    unmount: bool,
+) -> Result<(Box<Path>, std::fs::File), DiskError> {
+    let canonical_path = check_disk(root, None, backend, &disk_arg, unmount).await?;
+
+    let disk = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_EXCL)
+        .open(&canonical_path)
+        .map_err(|why| open_error(&disk_arg, why))?;
+
+    blockdev::lock_shared(disk.as_raw_fd()).map_err(|why| lock_error(&disk_arg, why))?;
+
+    Ok((canonical_path.into_boxed_path(), disk))
+}
+
+/// Ensures that the disk is a block device which nothing is using, and that the policy
+/// permits it when one is given, returning its canonical path.
+async fn check_disk(
+    root: &SystemRoot,
+    policy: Option<&Policy>,
+    backend: &mut dyn MountBackend,
+    disk_arg: &Path,
+    unmount: bool,
+) -> Result<PathBuf, DiskError> {
+    let canonical_path = fs::canonicalize(disk_arg)
+        .await
+        .map_err(|why| DiskError::NoDisk { disk: disk_arg.into(), why })?;
+
+    let metadata = canonical_path
+        .metadata()
+        .await
+        .map_err(|why| DiskError::Metadata { arg: disk_arg.into(), why })?;
+
+    if !metadata.file_type().is_block_device() {
+        return Err(DiskError::NotABlock { arg: disk_arg.into() });
+    }
+
+    let arg = std::path::Path::new(disk_arg.as_os_str());
+    let disk = std::path::Path::new(canonical_path.as_os_str());
+    if let Some(policy) = policy {
+        policy.check(root, arg, disk)?;
+    }
+
+    let usage = busy::usage(root, arg, disk)?;
+
+    // Nothing is released unless the disk may then be used.
+    if let Some(blocker) = usage.blockers.into_iter().next() {
+        return Err(blocker);
+    }
+
+    if unmount {
+        for (source, dest) in &usage.mounts {
+            eprintln!("unmounting '{}': {:?} is mounted at {:?}", disk_arg.display(), source, dest);
+        }
+
+        unmount::release(root, backend, arg, disk)?;
+    } else if let Some((source, dest)) = usage.mounts.into_iter().next() {
+        return Err(DiskError::AlreadyMounted {
+            arg: disk_arg.into(),
+            source_: PathBuf::from(source).into_boxed_path(),
+            dest: PathBuf::from(dest).into_boxed_path(),
+        });
+    } else if let Some(swap) = usage.swaps.into_iter().next() {
+        return Err(DiskError::Swap {
+            arg: disk_arg.into(),
+            swap: PathBuf::from(swap).into_boxed_path(),
+        });
+    }
+
+    Ok(canonical_path)
+}
+
+fn open_error(disk_arg: &Path, why: io::Error) -> DiskError {
+    match why.raw_os_error() {
+        Some(libc::EBUSY) => DiskError::Exclusive { disk: disk_arg.into() },
+        _ => DiskError::Open { disk: disk_arg.into(), why },
+    }
+}
+
+fn lock_error(disk_arg: &Path, why: io::Error) -> DiskError {
+    match why.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => DiskError::Locked { disk: disk_arg.into() },
+        _ => DiskError::Open { disk: disk_arg.into(), why },
+    }
+}
+
+/// Locks the opened disk for as long as it remains open, following the convention of
+/// systemd-udevd. udev does not probe the disk while it is locked, so the partitions
+/// which appear as it is written are not mounted, and no other instance of popsicle
+/// will write to it at the same time.
+pub fn lock_disk(disk: &impl AsRawFd) -> io::Result<()> {
+    blockdev::lock(disk.as_raw_fd())
+}
@@ -0,0 +1,97 @@
+//! Making the partitions of a flashed disk appear, without it being plugged in again.
+
+use crate::{
+    blockdev,
+    busy::{names, SystemRoot},
+};
+use async_std::task::spawn_blocking;
+use std::{
+    ffi::OsStr,
+    fs, io,
+    os::unix::{
+        fs::MetadataExt,
+        io::{AsRawFd, RawFd},
+    },
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How many times the partition table is read again while the kernel reports the disk
+/// as busy, which it may be for a moment after it was last written to.
+const ATTEMPTS: usize = 10;
+
+/// How long to wait between attempts, and between checks of whether udev has settled.
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// Asks the kernel to read the partition table of the opened disk again, and returns the
+/// nodes of the partitions which it found. The disk is expected to be locked, so that udev
+/// does not probe it while the table is being read.
+pub fn reread_partitions(root: &SystemRoot, disk: &impl AsRawFd) -> io::Result<Vec<PathBuf>> {
+    let fd = disk.as_raw_fd();
+    let mut attempt = 1;
+    while let Err(why) = blockdev::reread_partitions(fd) {
+        if why.raw_os_error() != Some(libc::EBUSY) || attempt == ATTEMPTS {
+            return Err(why);
+        }
+
+        attempt += 1;
+        thread::sleep(INTERVAL);
+    }
+
+    // The kernel name of the disk is found from its device number.
+    let rdev = fs::metadata(format!("/proc/self/fd/{}", fd))?.rdev();
+    let device = fs::canonicalize(root.device(rdev))?;
+    let name = device.file_name().and_then(OsStr::to_str).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "block device has no name in sysfs")
+    })?;
+
+    let partitions = names(root, name)?.split_off(1);
+    Ok(partitions.iter().map(|partition| root.node(partition)).collect())
+}
+
+/// Waits up to `timeout` for udev to have processed its events, and to have created the
+/// nodes of the partitions, returning whether it did so in time. udev holds back the
+/// events of a disk while it is locked, so the disk must be unlocked or closed first.
+pub fn settle_partitions(root: &SystemRoot, partitions: &[PathBuf], timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let settled =
+        || !root.udev_queue().exists() && partitions.iter().all(|partition| partition.exists());
+
+    while !settled() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+
+        thread::sleep(INTERVAL);
+    }
+
+    true
+}
+
+/// Reads the partition table of the locked disk again, and then unlocks it to wait up to
+/// `settle` for udev, as the disk is finished with. Returns the nodes of the partitions,
+/// and whether waiting for udev timed out.
+pub(crate) async fn reread(
+    fd: RawFd,
+    settle: Option<Duration>,
+) -> io::Result<(Vec<PathBuf>, bool)> {
+    let root = SystemRoot::default();
+    let partitions = spawn_blocking({
+        let root = root.clone();
+        move || reread_partitions(&root, &fd)
+    })
+    .await?;
+
+    let timeout = match settle {
+        Some(timeout) => timeout,
+        None => return Ok((partitions, false)),
+    };
+
+    blockdev::unlock(fd)?;
+    spawn_blocking(move || {
+        let settled = settle_partitions(&root, &partitions, timeout);
+        Ok((partitions, !settled))
+    })
+    .await
+}
@@ -0,0 +1,130 @@
+//! Which disks may be written to, so that a typo or `--all` cannot overwrite the system.
+
+use crate::{
+    busy::{boxed, holders, mount_table, names, SystemRoot},
+    DiskError,
+};
+use std::{
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Refuses to write to disks which are unlikely to be intended as the target of an image.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    /// Disks which any of these filesystems are mounted from, directly or through device
+    /// mapper targets such as LUKS or LVM, are refused.
+    pub system_mounts: Vec<PathBuf>,
+    /// Refuses disks which the kernel does not report as removable. Drives in USB
+    /// enclosures are often among these, and must be forced to be written to.
+    pub removable_only: bool,
+    /// Refuses disks which are larger than this many bytes.
+    pub max_size: Option<u64>,
+    /// Disks which are never written to, such as the source of a clone, by their canonical
+    /// paths. These are refused even when the policy is otherwise unrestricted.
+    pub protected: Vec<PathBuf>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            system_mounts: ["/", "/boot", "/home"].iter().map(PathBuf::from).collect(),
+            removable_only: true,
+            max_size: None,
+            protected: Vec::new(),
+        }
+    }
+}
+
+impl Policy {
+    /// Permits writing to any disk.
+    pub fn unrestricted() -> Self {
+        Policy {
+            system_mounts: Vec::new(),
+            removable_only: false,
+            max_size: None,
+            protected: Vec::new(),
+        }
+    }
+
+    /// Refuses the disk at `disk`, which was given as `arg`, if it is dangerous to write to.
+    pub fn check(&self, root: &SystemRoot, arg: &Path, disk: &Path) -> Result<(), DiskError> {
+        if self.protected.iter().any(|protected| protected == disk) {
+            return Err(DiskError::Protected { arg: boxed(arg) });
+        }
+
+        let inspect = |why| DiskError::Inspect { arg: boxed(arg), why };
+        let name = match disk.file_name().and_then(OsStr::to_str) {
+            Some(name) => name,
+            None => return Err(DiskError::NotABlock { arg: boxed(arg) }),
+        };
+
+        if !self.system_mounts.is_empty() {
+            let devices = stacked(root, name).map_err(inspect)?;
+            for (source, mount) in mount_table(root).map_err(inspect)? {
+                let canonical = root.canonicalize(&source);
+                let is_system = self.system_mounts.contains(&mount);
+                if is_system && (devices.contains(&source) || devices.contains(&canonical)) {
+                    return Err(DiskError::SystemDisk { arg: boxed(arg), mount: boxed(&mount) });
+                }
+            }
+        }
+
+        if self.removable_only && !removable(root, name).map_err(inspect)? {
+            return Err(DiskError::NotRemovable { arg: boxed(arg) });
+        }
+
+        if let Some(max) = self.max_size {
+            let sectors = fs::read_to_string(root.block(name).join("size")).map_err(inspect)?;
+            let size = sectors.trim().parse::<u64>().unwrap_or(0) * 512;
+            if size > max {
+                return Err(DiskError::TooLarge { arg: boxed(arg), size, max });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The paths in `/dev` of the disk, its partitions, and every device which is built on top
+/// of them.
+fn stacked(root: &SystemRoot, name: &str) -> io::Result<Vec<PathBuf>> {
+    let dev = Path::new("/dev");
+    let mut devices = Vec::new();
+    let mut pending = Vec::new();
+
+    for member in names(root, name)? {
+        devices.push(dev.join(&member));
+        pending.extend(holders(&root.member(name, &member))?);
+    }
+
+    while let Some(holder) = pending.pop() {
+        let path = dev.join(&holder);
+        if devices.contains(&path) {
+            continue;
+        }
+
+        // Device mapper targets are mounted by the names they are given in `/dev/mapper`.
+        let block = root.block(&holder);
+        if let Ok(mapping) = fs::read_to_string(block.join("dm/name")) {
+            devices.push(dev.join("mapper").join(mapping.trim()));
+        }
+
+        devices.push(path);
+        pending.extend(holders(&block)?);
+    }
+
+    Ok(devices)
+}
+
+/// Whether the kernel reports the disk, or the disk of the partition, as removable.
+fn removable(root: &SystemRoot, name: &str) -> io::Result<bool> {
+    let block = root.block(name);
+    let removable = match block.join("partition").exists() {
+        true => block.join("../removable"),
+        false => block.join("removable"),
+    };
+
+    Ok(fs::read_to_string(removable)?.trim() == "1")
+}
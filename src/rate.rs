@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// How much weight each new measurement is given in the smoothed throughput.
+const SMOOTHING: f64 = 0.25;
+
+/// How quickly a device is moving through its current phase.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Rate {
+    /// Bytes per second, smoothed over the recent updates.
+    pub bytes_per_second: u64,
+    /// How long the rest of the phase is estimated to take, once a rate is known.
+    pub eta: Option<Duration>,
+    /// How long the device has been in the phase.
+    pub elapsed: Duration,
+}
+
+/// Measures the rate of a device through a phase which ends at `total`, if it is known.
+pub(crate) struct Meter {
+    total: Option<u64>,
+    started: Instant,
+    last: Instant,
+    position: u64,
+    smoothed: Option<f64>,
+}
+
+impl Meter {
+    pub fn new(total: Option<u64>) -> Self {
+        let now = Instant::now();
+        Meter { total, started: now, last: now, position: 0, smoothed: None }
+    }
+
+    pub fn total(&self) -> Option<u64> {
+        self.total
+    }
+
+    pub fn update(&mut self, position: u64) -> Rate {
+        let now = Instant::now();
+        let interval = now.duration_since(self.last).as_secs_f64();
+
+        if interval > 0.0 {
+            let current = position.saturating_sub(self.position) as f64 / interval;
+            self.smoothed = Some(match self.smoothed {
+                Some(smoothed) => smoothed + SMOOTHING * (current - smoothed),
+                None => current,
+            });
+        }
+
+        self.last = now;
+        self.position = position;
+
+        let bytes_per_second = self.smoothed.unwrap_or(0.0) as u64;
+        let eta = match (self.total, bytes_per_second) {
+            (None, _) | (_, 0) => None,
+            (Some(total), rate) => Some(Duration::from_secs(total.saturating_sub(position) / rate)),
+        };
+
+        Rate { bytes_per_second, eta, elapsed: now.duration_since(self.started) }
+    }
+}
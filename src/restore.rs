@@ -0,0 +1,297 @@
+//! Restoring flashed disks to empty data drives, with a single partition spanning the
+//! disk which is formatted as FAT32 or exFAT.
+
+use crate::{
+    blockdev, fat, partitions, table,
+    task::{sync, write_at, CancelHandle, FlashEvent, Phase, Progress},
+    DiskError,
+};
+use async_std::{fs::File, prelude::*};
+use futures::future::join_all;
+use std::{
+    fmt,
+    io::{self, Read, SeekFrom},
+    os::unix::{fs::FileTypeExt, io::AsRawFd},
+    time::Duration,
+};
+
+/// How much of the start and the end of a disk is erased, which covers the signatures of
+/// partition tables, ISO 9660 images, and the filesystems at the start of the disk.
+pub(crate) const ALIGN: u64 = 1024 * 1024;
+
+/// How many zeroes are written at a time.
+const CHUNK: u64 = 4 * 1024 * 1024;
+
+/// The partition table which is written to a restored disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionTable {
+    /// A master boot record, which every system can read, but which cannot address more
+    /// than 2 TiB of a disk.
+    Mbr,
+    /// A GUID partition table.
+    Gpt,
+}
+
+/// The filesystem which the partition of a restored disk is formatted with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filesystem {
+    Fat32,
+    Exfat,
+}
+
+impl Filesystem {
+    /// Ensures that the filesystem can be labelled with `label`.
+    pub fn check_label(self, label: &str) -> Result<(), RestoreError> {
+        let (max, length) = match self {
+            Filesystem::Fat32 => (fat::FAT32_LABEL, label.chars().count()),
+            Filesystem::Exfat => (fat::EXFAT_LABEL, label.encode_utf16().count()),
+        };
+
+        if length > max {
+            return Err(RestoreError::LabelLength { filesystem: self, max });
+        }
+
+        let allowed = |character: char| match self {
+            Filesystem::Fat32 => {
+                character.is_ascii()
+                    && !character.is_ascii_control()
+                    && !"\"*+,./:;<=>?[\\]|".contains(character)
+            }
+            Filesystem::Exfat => !character.is_control() && !"\"*/:<>?\\|".contains(character),
+        };
+
+        match label.chars().find(|&character| !allowed(character)) {
+            Some(character) => Err(RestoreError::LabelCharacter { filesystem: self, character }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Filesystem::Fat32 => "FAT32",
+            Filesystem::Exfat => "exFAT",
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+#[rustfmt::skip]
+pub enum RestoreError {
+    #[error("{} labels may be at most {} characters long", filesystem, max)]
+    LabelLength { filesystem: Filesystem, max: usize },
+    #[error("{} labels may not contain '{}'", filesystem, character)]
+    LabelCharacter { filesystem: Filesystem, character: char },
+    #[error("{} cannot be made on a disk of {} bytes", filesystem, size)]
+    Size { filesystem: Filesystem, size: u64 },
+    #[error("disk of {} bytes is too large to be addressed by an MBR partition table", size)]
+    TooLarge { size: u64 },
+}
+
+/// A region of a disk which is written to restore it.
+pub(crate) enum Write {
+    Zeroes { offset: u64, length: u64 },
+    Data { offset: u64, data: Vec<u8> },
+}
+
+/// A disk which is restored, along with its progress.
+struct Target<P: Progress> {
+    file: File,
+    device: P::Device,
+    pb: P,
+}
+
+/// How the restoration of a disk ended.
+enum Outcome {
+    Restored,
+    Failed,
+    Cancelled,
+}
+
+#[derive(new)]
+pub struct Restore<P: Progress> {
+    table: PartitionTable,
+    filesystem: Filesystem,
+    label: String,
+
+    #[new(default)]
+    targets: Vec<Target<P>>,
+
+    /// When cancelled, each disk is sent a `Cancelled` event, and `process` returns
+    /// `DiskError::Killed`.
+    #[new(default)]
+    pub cancel: CancelHandle,
+
+    /// How long to wait for udev to create the partition of each disk, once its partition
+    /// table has been read again.
+    #[new(default)]
+    pub settle: Option<Duration>,
+}
+
+impl<P: Progress> Restore<P> {
+    /// Restores every disk at once. Disks pass through the `Erasing` phase, where the
+    /// signatures at either end of them are erased, and then the `Writing` phase, where the
+    /// filesystem and the partition table are written. The partitions of block devices are
+    /// then found in the `Rereading` phase. Little is written to each disk, so no progress
+    /// is reported through the phases.
+    pub async fn process(mut self) -> anyhow::Result<()> {
+        self.filesystem.check_label(&self.label)?;
+
+        if self.targets.is_empty() {
+            return Err(anyhow!("no writers left"));
+        }
+
+        let restores =
+            std::mem::take(&mut self.targets).into_iter().map(|target| self.restore(target));
+        let outcomes = join_all(restores).await;
+
+        if outcomes.iter().any(|outcome| matches!(outcome, Outcome::Cancelled)) {
+            return Err(DiskError::Killed.into());
+        }
+
+        match outcomes.iter().any(|outcome| matches!(outcome, Outcome::Restored)) {
+            true => Ok(()),
+            false => Err(anyhow!("no writers left")),
+        }
+    }
+
+    pub fn subscribe(&mut self, file: File, device: P::Device, progress: P) -> &mut Self {
+        self.targets.push(Target { file, device, pb: progress });
+        self
+    }
+
+    async fn restore(&self, mut target: Target<P>) -> Outcome {
+        let size = match target.file.seek(SeekFrom::End(0)).await {
+            Ok(size) => size,
+            Err(why) => return failed(target, DiskError::Seek { offset: 0, why }),
+        };
+
+        let is_block = match target.file.metadata().await {
+            Ok(metadata) => metadata.file_type().is_block_device(),
+            Err(_) => false,
+        };
+
+        let sector = blockdev::sector_size(target.file.as_raw_fd()).unwrap_or(512);
+
+        let writes = match self.layout(size, sector) {
+            Ok(writes) => writes,
+            Err(why) => return failed(target, why),
+        };
+
+        // The signatures at either end of the disk are erased before anything is written.
+        let erase = vec![
+            Write::Zeroes { offset: 0, length: ALIGN.min(size) },
+            Write::Zeroes { offset: size.saturating_sub(ALIGN), length: ALIGN.min(size) },
+        ];
+
+        for (phase, writes) in [(Phase::Erasing, erase), (Phase::Writing, writes)] {
+            target.pb.event(&target.device, FlashEvent::Phase(phase));
+            if let Err(outcome) = self.apply(&mut target, &writes).await {
+                return outcome;
+            }
+        }
+
+        if let Err(why) = sync(&mut target.file).await {
+            return failed(target, DiskError::Flush { why });
+        }
+
+        if is_block {
+            target.pb.event(&target.device, FlashEvent::Phase(Phase::Rereading));
+            match partitions::reread(target.file.as_raw_fd(), self.settle).await {
+                Ok((partitions, timed_out)) => {
+                    let event = FlashEvent::Partitions { partitions, timed_out };
+                    target.pb.event(&target.device, event);
+                }
+                Err(why) => return failed(target, DiskError::Reread { why }),
+            }
+        }
+
+        target.pb.event(&target.device, FlashEvent::Done);
+        Outcome::Restored
+    }
+
+    /// What is written to a disk of `size` bytes, made of sectors of `sector` bytes: the
+    /// filesystem, followed by the partition table which points to it.
+    fn layout(&self, size: u64, sector: u64) -> Result<Vec<Write>, DiskError> {
+        let sectors = size / sector;
+        let (start, length) = table::span(self.table, sectors, sector)
+            .map_err(DiskError::Restore)?
+            .ok_or(DiskError::Restore(RestoreError::Size { filesystem: self.filesystem, size }))?;
+
+        let serial = random::<4>().map_err(|why| DiskError::Erase { why })?;
+        let serial = u32::from_le_bytes(serial);
+        let offset = start * sector;
+        let mut writes = match self.filesystem {
+            Filesystem::Fat32 => fat::fat32(offset, length, sector, &self.label, serial),
+            Filesystem::Exfat => fat::exfat(offset, length, sector, &self.label, serial),
+        }
+        .ok_or(DiskError::Restore(RestoreError::Size { filesystem: self.filesystem, size }))?;
+
+        let guids = [random::<16>(), random::<16>()];
+        let [disk, partition] = match guids {
+            [Ok(disk), Ok(partition)] => [disk, partition],
+            [Err(why), _] | [_, Err(why)] => return Err(DiskError::Erase { why }),
+        };
+
+        let mbr_type = match self.filesystem {
+            Filesystem::Fat32 => table::MBR_FAT32,
+            Filesystem::Exfat => table::MBR_EXFAT,
+        };
+
+        writes.extend(match self.table {
+            PartitionTable::Mbr => table::mbr(start, length, sector, mbr_type, serial),
+            PartitionTable::Gpt => {
+                table::gpt(start, length, sectors, sector, &self.label, disk, partition)
+            }
+        });
+
+        Ok(writes)
+    }
+
+    /// Writes each region in turn.
+    async fn apply(&self, target: &mut Target<P>, writes: &[Write]) -> Result<(), Outcome> {
+        let zeroes = vec![0; CHUNK as usize];
+        for write in writes {
+            let (offset, length) = match write {
+                Write::Zeroes { offset, length } => (*offset, *length),
+                Write::Data { offset, data } => (*offset, data.len() as u64),
+            };
+
+            let mut written = 0;
+            while written < length {
+                if self.cancel.is_cancelled() {
+                    target.pb.event(&target.device, FlashEvent::Cancelled);
+                    return Err(Outcome::Cancelled);
+                }
+
+                let data = match write {
+                    Write::Zeroes { .. } => &zeroes[..(length - written).min(CHUNK) as usize],
+                    Write::Data { data, .. } => &data[..],
+                };
+
+                let at = offset + written;
+                if let Err(why) = write_at(&mut target.file, at, data, None).await {
+                    target.pb.event(&target.device, FlashEvent::Failed(why));
+                    return Err(Outcome::Failed);
+                }
+
+                written += data.len() as u64;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Random bytes, for the serial numbers and the GUIDs of restored disks.
+fn random<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn failed<P: Progress>(mut target: Target<P>, why: DiskError) -> Outcome {
+    target.pb.event(&target.device, FlashEvent::Failed(why));
+    Outcome::Failed
+}
@@ -0,0 +1,208 @@
+//! Checkpoints of how far each device got through writing an image, so that an interrupted
+//! flash can continue where it left off.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Read},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// How much of the start of an image is hashed to identify it.
+const PREFIX: u64 = 1024 * 1024;
+
+/// Identifies an image across runs, without reading all of it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct ImageId {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Seconds since the epoch that the image was last modified.
+    pub modified: u64,
+    /// The SHA-256 digest of the first mebibyte of the image, in hexadecimal.
+    pub prefix: String,
+}
+
+impl ImageId {
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+
+        let mut hasher = Sha256::new();
+        io::copy(&mut file.take(PREFIX), &mut hasher)?;
+        let prefix = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        Ok(ImageId { path: fs::canonicalize(path)?, size: metadata.len(), modified, prefix })
+    }
+}
+
+/// Identifies a device across runs, and across the paths that it may reappear at.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct DeviceId {
+    pub serial: String,
+    pub size: u64,
+}
+
+impl DeviceId {
+    /// Identifies the block device at `path` by the serial number that udev reports for it,
+    /// and its size. The serial is the one which udisks reports, so that devices are
+    /// identified alike by every frontend. Devices without one are refused.
+    pub fn from_block_device(path: &Path) -> io::Result<Self> {
+        let rdev = fs::metadata(path)?.rdev();
+        let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+        let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+        let sysfs = PathBuf::from(format!("/sys/dev/block/{}:{}", major, minor));
+
+        let udev = fs::read_to_string(format!("/run/udev/data/b{}:{}", major, minor));
+        let udev = udev.unwrap_or_default();
+        let property = |key: &str| {
+            let prefix = format!("E:{}=", key);
+            udev.lines().find_map(|line| line.strip_prefix(&prefix)).map(String::from)
+        };
+
+        let serial = property("ID_SERIAL_SHORT")
+            .or_else(|| property("ID_SCSI_SERIAL"))
+            .map(|serial| serial.trim().to_owned())
+            .filter(|serial| !serial.is_empty())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "device has no serial number")
+            })?;
+
+        let sectors = fs::read_to_string(sysfs.join("size"))?;
+        let sectors = sectors.trim().parse::<u64>().map_err(|why| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("invalid device size: {}", why))
+        })?;
+
+        Ok(DeviceId { serial, size: sectors * 512 })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Checkpoint {
+    image: ImageId,
+    device: DeviceId,
+    offset: u64,
+}
+
+/// The checkpoints of every device which was not finished being flashed.
+///
+/// Every process which flashes devices shares the journal, so each change is made to the
+/// journal as it is on disk, while holding a lock on it.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    checkpoints: Vec<Checkpoint>,
+}
+
+impl Journal {
+    /// Where the journal is kept by default, within the user's state directory.
+    pub fn default_path() -> Option<PathBuf> {
+        let state = match env::var_os("XDG_STATE_HOME") {
+            Some(state) if !state.is_empty() => PathBuf::from(state),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".local/state"),
+        };
+
+        Some(state.join("popsicle/journal.ron"))
+    }
+
+    /// Reads the journal at `path`, which is empty if it does not exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let checkpoints = read(&path)?;
+        Ok(Journal { path, checkpoints })
+    }
+
+    /// The offset that the device was last known to have been written up to with the image.
+    pub fn offset(&self, image: &ImageId, device: &DeviceId) -> Option<u64> {
+        self.position(image, device).map(|index| self.checkpoints[index].offset)
+    }
+
+    /// Records that the device has been written up to `offset` with the image.
+    pub fn record(&mut self, image: &ImageId, device: &DeviceId, offset: u64) -> io::Result<()> {
+        self.update(|checkpoints| {
+            match position(checkpoints, image, device) {
+                Some(index) => checkpoints[index].offset = offset,
+                None => checkpoints.push(Checkpoint {
+                    image: image.clone(),
+                    device: device.clone(),
+                    offset,
+                }),
+            }
+
+            true
+        })
+    }
+
+    /// Forgets the checkpoint of the device, once it is done or must start over.
+    pub fn remove(&mut self, image: &ImageId, device: &DeviceId) -> io::Result<()> {
+        self.update(|checkpoints| match position(checkpoints, image, device) {
+            Some(index) => {
+                checkpoints.remove(index);
+                true
+            }
+            None => false,
+        })
+    }
+
+    fn position(&self, image: &ImageId, device: &DeviceId) -> Option<usize> {
+        position(&self.checkpoints, image, device)
+    }
+
+    /// Reads the journal again while holding a lock on it, so that the checkpoints which
+    /// other processes recorded since are kept, and saves it if `change` changed it.
+    fn update(&mut self, change: impl FnOnce(&mut Vec<Checkpoint>) -> bool) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // The journal itself is replaced when it is saved, so the lock is held on a file
+        // of its own. It is released once the file is closed.
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.with_extension("ron.lock"))?;
+
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.checkpoints = read(&self.path)?;
+        match change(&mut self.checkpoints) {
+            true => self.save(),
+            false => Ok(()),
+        }
+    }
+
+    /// Replaces the journal on disk, so that it is never left partially written.
+    fn save(&self) -> io::Result<()> {
+        let data = ron::to_string(&self.checkpoints)
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+
+        let temporary = self.path.with_extension("ron.tmp");
+        fs::write(&temporary, data)?;
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+/// Reads the checkpoints of the journal at `path`, of which there are none if it does not
+/// exist yet.
+fn read(path: &Path) -> io::Result<Vec<Checkpoint>> {
+    match fs::read(path) {
+        Ok(data) => ron::de::from_bytes(&data)
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why)),
+        Err(why) if why.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(why) => Err(why),
+    }
+}
+
+fn position(checkpoints: &[Checkpoint], image: &ImageId, device: &DeviceId) -> Option<usize> {
+    checkpoints.iter().position(|entry| entry.image == *image && entry.device == *device)
+}
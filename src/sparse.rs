@@ -0,0 +1,256 @@
+//! Expansion of the Android sparse image format produced by `img2simg`.
+//!
+//! A sparse image is a sequence of chunks, each describing a run of blocks. Raw chunks
+//! carry their data, fill chunks repeat a four byte pattern, and don't care chunks
+//! leave the blocks untouched. CRC32 chunks hold a checksum of the output so far.
+
+use crate::{
+    image::{Extent, Reader},
+    ImageError,
+};
+use crc32fast::Hasher;
+use futures::{
+    io::{AsyncRead, AsyncReadExt},
+    ready,
+    task::{Context, Poll},
+};
+use std::{fs, io, os::unix::fs::FileExt, pin::Pin};
+
+pub(crate) const SPARSE_MAGIC: u32 = 0xED26_FF3A;
+
+/// The size of the file header, which later versions may extend.
+pub(crate) const SPARSE_HEADER_SIZE: usize = 28;
+
+const CHUNK_RAW: u16 = 0xCAC1;
+const CHUNK_FILL: u16 = 0xCAC2;
+const CHUNK_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_CRC32: u16 = 0xCAC4;
+
+const ZEROES: [u8; 4096] = [0; 4096];
+
+fn invalid(why: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, why)
+}
+
+/// The file header of a sparse image.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SparseHeader {
+    header_size: u16,
+    chunk_header_size: u16,
+    block_size: u32,
+    blocks: u32,
+    chunks: u32,
+}
+
+impl SparseHeader {
+    pub fn read(file: &fs::File) -> io::Result<Self> {
+        let mut header = [0u8; SPARSE_HEADER_SIZE];
+        file.read_exact_at(&mut header, 0)?;
+        Self::parse(&header)
+    }
+
+    /// Parses the header from the first bytes of the image.
+    pub fn parse(header: &[u8]) -> io::Result<Self> {
+        if header.len() < SPARSE_HEADER_SIZE {
+            return Err(invalid("sparse image header is truncated"));
+        }
+
+        let u16_at = |at: usize| u16::from_le_bytes([header[at], header[at + 1]]);
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+        };
+
+        if u32_at(0) != SPARSE_MAGIC {
+            return Err(invalid("sparse image magic is invalid"));
+        }
+
+        if u16_at(4) != 1 {
+            return Err(invalid("sparse image major version is not supported"));
+        }
+
+        let header = SparseHeader {
+            header_size: u16_at(8),
+            chunk_header_size: u16_at(10),
+            block_size: u32_at(12),
+            blocks: u32_at(16),
+            chunks: u32_at(20),
+        };
+
+        if header.header_size < 28 || header.chunk_header_size < 12 {
+            return Err(invalid("sparse image headers are too small"));
+        }
+
+        if header.block_size == 0 || header.block_size % 4 != 0 {
+            return Err(invalid("sparse image block size is not a multiple of 4"));
+        }
+
+        Ok(header)
+    }
+}
+
+/// The chunk currently being read.
+enum Chunk {
+    Raw { remaining: u64 },
+    Fill { pattern: [u8; 4], remaining: u64 },
+}
+
+/// Tracks the expansion of a sparse image as it is read sequentially.
+pub(crate) struct Sparse {
+    header: SparseHeader,
+    started: bool,
+    chunks: u32,
+    /// The offset in the expanded image at which the next chunk begins.
+    position: u64,
+    chunk: Chunk,
+    crc: Hasher,
+}
+
+impl Sparse {
+    pub fn new(header: SparseHeader) -> Self {
+        Sparse {
+            header,
+            started: false,
+            chunks: header.chunks,
+            position: 0,
+            chunk: Chunk::Raw { remaining: 0 },
+            crc: Hasher::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Sparse::new(self.header);
+    }
+
+    /// The size of the expanded image.
+    pub fn size(&self) -> u64 {
+        u64::from(self.header.blocks) * u64::from(self.header.block_size)
+    }
+
+    pub async fn next_extent(&mut self, reader: &mut Reader) -> io::Result<Option<Extent>> {
+        if !self.started {
+            reader.skip(u64::from(self.header.header_size)).await?;
+            self.started = true;
+        }
+
+        if let Chunk::Raw { remaining } = self.chunk {
+            if remaining != 0 {
+                return Err(invalid("sparse image chunk was not read in full"));
+            }
+        }
+
+        let mut header = vec![0u8; usize::from(self.header.chunk_header_size)];
+        while self.chunks != 0 {
+            self.chunks -= 1;
+
+            reader.read_exact(&mut header).await?;
+            let kind = u16::from_le_bytes([header[0], header[1]]);
+            let blocks = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            let total = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+
+            let data_size = total
+                .checked_sub(u32::from(self.header.chunk_header_size))
+                .ok_or_else(|| invalid("sparse image chunk is smaller than its header"))?;
+
+            let offset = self.position;
+            let length = u64::from(blocks) * u64::from(self.header.block_size);
+
+            match kind {
+                CHUNK_RAW => {
+                    if u64::from(data_size) != length {
+                        return Err(invalid("sparse image raw chunk has the wrong size"));
+                    }
+
+                    self.chunk = Chunk::Raw { remaining: length };
+                }
+                CHUNK_FILL => {
+                    if data_size != 4 {
+                        return Err(invalid("sparse image fill chunk has the wrong size"));
+                    }
+
+                    let mut pattern = [0u8; 4];
+                    reader.read_exact(&mut pattern).await?;
+                    self.chunk = Chunk::Fill { pattern, remaining: length };
+                }
+                CHUNK_DONT_CARE => {
+                    // The checksum treats skipped blocks as if they were zeroed.
+                    let mut remaining = length;
+                    while remaining != 0 {
+                        let chunk = remaining.min(ZEROES.len() as u64);
+                        self.crc.update(&ZEROES[..chunk as usize]);
+                        remaining -= chunk;
+                    }
+
+                    self.position += length;
+                    reader.skip(u64::from(data_size)).await?;
+                    continue;
+                }
+                CHUNK_CRC32 => {
+                    if data_size != 4 {
+                        return Err(invalid("sparse image checksum chunk has the wrong size"));
+                    }
+
+                    let mut expected = [0u8; 4];
+                    reader.read_exact(&mut expected).await?;
+                    if self.crc.clone().finalize() != u32::from_le_bytes(expected) {
+                        let why = ImageError::SparseChecksum { offset };
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, why));
+                    }
+
+                    continue;
+                }
+                _ => return Err(invalid("sparse image contains an unknown chunk type")),
+            }
+
+            self.position += length;
+            return Ok(Some(Extent { offset, length }));
+        }
+
+        if self.position != self.size() {
+            return Err(invalid("sparse image chunks do not cover the whole image"));
+        }
+
+        Ok(None)
+    }
+
+    pub fn poll_read(
+        &mut self,
+        reader: &mut Reader,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let read = match &mut self.chunk {
+            Chunk::Raw { remaining } => {
+                let max = (*remaining).min(buf.len() as u64) as usize;
+                if max == 0 {
+                    return Poll::Ready(Ok(0));
+                }
+
+                let read = ready!(Pin::new(reader).poll_read(cx, &mut buf[..max]))?;
+                if read == 0 {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "sparse image ended within a raw chunk",
+                    )));
+                }
+
+                *remaining -= read as u64;
+                read
+            }
+            Chunk::Fill { pattern, remaining } => {
+                let max = (*remaining).min(buf.len() as u64) as usize;
+
+                // Chunks span whole blocks, so the pattern is aligned to what remains.
+                let phase = (4 - (*remaining % 4) as usize) % 4;
+                for (index, byte) in buf[..max].iter_mut().enumerate() {
+                    *byte = pattern[(phase + index) % 4];
+                }
+
+                *remaining -= max as u64;
+                max
+            }
+        };
+
+        self.crc.update(&buf[..read]);
+        Poll::Ready(Ok(read))
+    }
+}
@@ -0,0 +1,133 @@
+//! Writing partition tables which hold a single partition, spanning the disk.
+
+use crate::restore::{PartitionTable, RestoreError, Write, ALIGN};
+
+/// The MBR partition type of FAT32 partitions which are addressed by LBA.
+pub(crate) const MBR_FAT32: u8 = 0x0C;
+
+/// The MBR partition type of exFAT partitions, which they share with NTFS.
+pub(crate) const MBR_EXFAT: u8 = 0x07;
+
+/// The MBR partition type of the partition which protects a GUID partition table.
+const MBR_PROTECTIVE: u8 = 0xEE;
+
+/// The GUID of Microsoft basic data partitions, as it is laid out on the disk.
+const BASIC_DATA: [u8; 16] = [
+    0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+];
+
+/// How many entries a GUID partition table has room for, and how large each is.
+const GPT_ENTRIES: u64 = 128;
+const GPT_ENTRY: u64 = 128;
+
+/// The start and the length of the partition on a disk of `sectors` sectors, both in
+/// sectors, which is aligned to a mebibyte. `None` if the disk has no room for it.
+pub(crate) fn span(
+    table: PartitionTable,
+    sectors: u64,
+    sector: u64,
+) -> Result<Option<(u64, u64)>, RestoreError> {
+    let align = ALIGN / sector;
+    let end = match table {
+        PartitionTable::Mbr => {
+            if sectors > u64::from(u32::MAX) {
+                return Err(RestoreError::TooLarge { size: sectors * sector });
+            }
+
+            sectors
+        }
+        // The backup table is kept at the end of the disk.
+        PartitionTable::Gpt => sectors.saturating_sub(1 + entry_sectors(sector)),
+    };
+
+    let end = end / align * align;
+    Ok(end.checked_sub(align).filter(|&length| length > 0).map(|length| (align, length)))
+}
+
+/// The master boot record of a disk, with the partition as its only entry.
+pub(crate) fn mbr(start: u64, length: u64, sector: u64, kind: u8, signature: u32) -> Vec<Write> {
+    let mut data = vec![0; sector as usize];
+    data[440..444].copy_from_slice(&signature.to_le_bytes());
+    mbr_entry(&mut data, kind, start as u32, length as u32);
+    vec![Write::Data { offset: 0, data }]
+}
+
+/// The protective MBR, and the primary and backup GUID partition tables, of a disk of
+/// `sectors` sectors, with the partition as their only entry.
+pub(crate) fn gpt(
+    start: u64,
+    length: u64,
+    sectors: u64,
+    sector: u64,
+    label: &str,
+    disk: [u8; 16],
+    partition: [u8; 16],
+) -> Vec<Write> {
+    let mut mbr = vec![0; sector as usize];
+    mbr_entry(&mut mbr, MBR_PROTECTIVE, 1, (sectors - 1).min(u64::from(u32::MAX)) as u32);
+
+    let mut entries = vec![0; (GPT_ENTRIES * GPT_ENTRY) as usize];
+    entries[0..16].copy_from_slice(&BASIC_DATA);
+    entries[16..32].copy_from_slice(&guid(partition));
+    entries[32..40].copy_from_slice(&start.to_le_bytes());
+    entries[40..48].copy_from_slice(&(start + length - 1).to_le_bytes());
+    for (index, unit) in label.encode_utf16().take(36).enumerate() {
+        entries[56 + index * 2..58 + index * 2].copy_from_slice(&unit.to_le_bytes());
+    }
+
+    let entry_sectors = entry_sectors(sector);
+    let last = sectors - 1;
+    let backup_entries = last - entry_sectors;
+    let entries_crc = crc32fast::hash(&entries);
+
+    let header = |current: u64, backup: u64, entries_at: u64| {
+        let mut header = vec![0; sector as usize];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&current.to_le_bytes());
+        header[32..40].copy_from_slice(&backup.to_le_bytes());
+        header[40..48].copy_from_slice(&(2 + entry_sectors).to_le_bytes());
+        header[48..56].copy_from_slice(&(backup_entries - 1).to_le_bytes());
+        header[56..72].copy_from_slice(&guid(disk));
+        header[72..80].copy_from_slice(&entries_at.to_le_bytes());
+        header[80..84].copy_from_slice(&(GPT_ENTRIES as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(GPT_ENTRY as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = crc32fast::hash(&header[..92]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    };
+
+    vec![
+        Write::Data { offset: 0, data: mbr },
+        Write::Data { offset: sector, data: header(1, last, 2) },
+        Write::Data { offset: 2 * sector, data: entries.clone() },
+        Write::Data { offset: backup_entries * sector, data: entries },
+        Write::Data { offset: last * sector, data: header(last, 1, backup_entries) },
+    ]
+}
+
+/// How many sectors the entries of a GUID partition table take up.
+fn entry_sectors(sector: u64) -> u64 {
+    (GPT_ENTRIES * GPT_ENTRY + sector - 1) / sector
+}
+
+/// Fills in the first entry of a master boot record, which is only addressed by LBA, and
+/// signs the record.
+fn mbr_entry(mbr: &mut [u8], kind: u8, start: u32, length: u32) {
+    let entry = &mut mbr[446..462];
+    entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[4] = kind;
+    entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&length.to_le_bytes());
+    mbr[510..512].copy_from_slice(&[0x55, 0xAA]);
+}
+
+/// Makes a random version 4 GUID of the bytes, as it is laid out on the disk.
+fn guid(mut bytes: [u8; 16]) -> [u8; 16] {
+    bytes[7] = (bytes[7] & 0x0F) | 0x40;
+    bytes[8] = (bytes[8] & 0x3F) | 0x80;
+    bytes
+}
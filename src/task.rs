@@ -1,34 +1,402 @@
+use crate::{
+    blockdev,
+    checksum::{Checksum, Hasher},
+    eject::PowerOff,
+    events::{self, EventProgress, EventSender, EventStream},
+    image::Origin,
+    partitions,
+    rate::{Meter, Rate},
+    resume::{DeviceId, ImageId, Journal},
+    verify::Verifier,
+    DiskError, Image, ImageError,
+};
 use anyhow::Context;
-use async_std::{fs::File, prelude::*};
-use srmw::*;
-use std::{collections::HashMap, io::SeekFrom, time::Instant};
+use async_std::{fs::File, prelude::*, task::spawn_blocking};
+use futures::{
+    channel::mpsc,
+    future::{join, join_all},
+    SinkExt,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, SeekFrom},
+    os::unix::{fs::FileTypeExt, io::AsRawFd},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The granularity at which blocks of zeroes are skipped.
+const ZERO_BLOCK: usize = 4096;
+
+/// How much of a device is read back at a time when validating by hash.
+const HASH_CHUNK: usize = 1024 * 1024;
+
+/// How often the offset that each device has been written up to is recorded in the journal.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// When resuming, one in this many of the blocks already written is read back to check
+/// that the device still holds them, along with the last of them. Should one differ, the
+/// device is written again from the last block which was read back.
+const RESUME_SAMPLE: u64 = 16;
 
 pub trait Progress {
     type Device;
-    fn message(&mut self, device: &Self::Device, kind: &str, message: &str);
-    fn finish(&mut self);
-    fn set(&mut self, value: u64);
+
+    /// Informs the device's progress of what has happened to it. `Done`, `Failed` and
+    /// `Cancelled` are the last event of each device.
+    fn event(&mut self, device: &Self::Device, event: FlashEvent);
+}
+
+/// A phase that a device passes through while it is flashed. The progress of the device
+/// starts over from zero when writing and verifying begin.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Phase {
+    Erasing,
+    Writing,
+    /// Waiting for the writes to the device to complete.
+    Syncing,
+    /// Returning to the start of the device, before verifying it.
+    Seeking,
+    Verifying,
+    /// Waiting for the kernel to read the new partition table of the device.
+    Rereading,
+    /// Flushing the device, and powering it off once every device is finished.
+    Ejecting,
+    /// Reading the device into an image, when it is backed up.
+    Reading,
+    /// Reading the image through before anything is written, to check it against the
+    /// checksums of its block map.
+    Checking,
+}
+
+#[derive(Debug)]
+pub enum FlashEvent {
+    Phase(Phase),
+    /// The device failed, and nothing further will be done to it.
+    Failed(DiskError),
+    /// Only the blocks which differed from the image were written, which came to this many
+    /// bytes. Sent at the end of writing, when `Task::delta` is set.
+    Rewritten(u64),
+    /// The partitions of the device which the kernel found once it was finished, when
+    /// `Task::reread` is set, and whether udev was still processing them once `Task::settle`
+    /// had passed. Sent before `Done`.
+    Partitions {
+        partitions: Vec<PathBuf>,
+        timed_out: bool,
+    },
+    /// The device was powered off, when `Task::eject` is set. Sent before `Done`.
+    Ejected,
+    /// The device was written, but could not be powered off. Sent before `Done`.
+    EjectFailed(DiskError),
+    /// The task was cancelled before the device was done.
+    Cancelled,
+    /// The image was written, and verified if requested.
+    Done,
+    /// The offset that the device has been written or verified up to in the current phase,
+    /// and how quickly it is getting through the phase.
+    Progress {
+        position: u64,
+        rate: Rate,
+    },
+}
+
+/// How the regions of each device that the image covers are erased before writing, so that
+/// blocks which are skipped over read back as zeroes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Erase {
+    /// Discards the blocks. Not every device reads discarded blocks back as zeroes, which
+    /// validation will catch.
+    Discard,
+    /// Has the kernel write zeroes to the blocks, using the device's own command if it has one.
+    ZeroFill,
+}
+
+/// How each device is validated after it has been written to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Validation {
+    /// Reads the image a second time, comparing it to each device as it goes.
+    #[default]
+    Compare,
+    /// Hashes the image while it is copied, then hashes the regions of each device that were
+    /// written to, so that the image is only read once. If an expected digest is given, such
+    /// as the published checksum of the uncompressed image, the image must also match it.
+    /// Regions of the image which are skipped rather than written, such as those a block map
+    /// leaves unmapped, are hashed as the zeroes they expand to.
+    Hash { checksum: Checksum, expected: Option<Vec<u8>> },
+}
+
+/// Cancels a `Task` from another thread, or from a signal handler.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Stops the task before it writes or validates anything further.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A device which is written to, along with its progress.
+struct Target<P: Progress> {
+    file: File,
+    device: P::Device,
+    pb: P,
+    /// Identifies the device in the journal.
+    identity: Option<DeviceId>,
+    /// The offset that writing resumes from, which is before any block that is written.
+    resume: u64,
+    /// Reads the device back with `O_DIRECT`, when the device may not be opened again.
+    reader: Option<std::fs::File>,
+}
+
+/// A block of the image, which is shared by the pipelines of every device.
+struct Block {
+    offset: u64,
+    data: Box<[u8]>,
+}
+
+#[derive(Clone)]
+enum Message {
+    Block(Arc<Block>),
+    /// Every block of the image has been sent.
+    End,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pass {
+    Copy,
+    Validate,
+}
+
+/// The digest of the image as it is copied, and the regions of the devices it covers.
+struct Written {
+    checksum: Checksum,
+    hasher: Hasher,
+    regions: Vec<(u64, u64)>,
+    /// The digest of the whole of the image as it expands, with the gaps between its extents
+    /// hashed as zeroes, and how far it has got. This is what an expected digest is compared
+    /// to, as only the extents are written, and so only they are compared to the devices.
+    expanded: Option<(Hasher, u64)>,
+}
+
+impl Written {
+    fn update(&mut self, offset: u64, data: &[u8]) {
+        self.hasher.update(data);
+        if let Some((hasher, position)) = self.expanded.as_mut() {
+            hash_zeroes(hasher, offset.saturating_sub(*position));
+            hasher.update(data);
+            *position = offset + data.len() as u64;
+        }
+
+        match self.regions.last_mut() {
+            Some((start, length)) if *start + *length == offset => *length += data.len() as u64,
+            _ => self.regions.push((offset, data.len() as u64)),
+        }
+    }
+}
+
+/// The journal that the progress of each identified device is recorded in.
+struct Checkpoints {
+    journal: Arc<Mutex<Journal>>,
+    image: ImageId,
+}
+
+/// What the pipeline of every device shares.
+struct Shared<'a> {
+    cancel: &'a CancelHandle,
+    millis_between: u64,
+    delta: bool,
+    reread: bool,
+    settle: Option<Duration>,
+    eject: bool,
+    checkpoints: Option<&'a Checkpoints>,
+}
+
+impl Shared<'_> {
+    /// Whether the progress of the device is recorded in the journal.
+    fn records<P: Progress>(&self, target: &Target<P>) -> bool {
+        self.checkpoints.is_some() && target.identity.is_some()
+    }
+
+    /// Records that the device has been written up to `offset`. The journal is only an aid
+    /// to resuming, so the device does not fail if it cannot be saved.
+    async fn record<P: Progress>(&self, target: &Target<P>, offset: u64) {
+        self.change(target, move |journal, image, identity| {
+            journal.record(image, identity, offset)
+        })
+        .await;
+    }
+
+    /// Forgets the device, once it is done or has to be written from the start again.
+    async fn forget<P: Progress>(&self, target: &Target<P>) {
+        self.change(target, |journal, image, identity| journal.remove(image, identity)).await;
+    }
+
+    /// Changes the journal in a blocking task, as it is locked and written to disk.
+    async fn change<P: Progress, F>(&self, target: &Target<P>, change: F)
+    where
+        F: FnOnce(&mut Journal, &ImageId, &DeviceId) -> io::Result<()> + Send + 'static,
+    {
+        if let (Some(checkpoints), Some(identity)) = (self.checkpoints, &target.identity) {
+            let journal = checkpoints.journal.clone();
+            let (image, identity) = (checkpoints.image.clone(), identity.clone());
+            let _ = spawn_blocking(move || {
+                let mut journal = journal.lock().expect("journal lock");
+                change(&mut journal, &image, &identity)
+            })
+            .await;
+        }
+    }
+}
+
+/// Why the image stopped being read before its end.
+enum Stop {
+    Source(io::Error),
+    Cancelled,
+    NoWriters,
+}
+
+/// How the pipeline of a device ended.
+enum Outcome<P: Progress> {
+    /// The pass completed, and the device continues on to the next pass.
+    Continue(Target<P>),
+    /// The pass completed, and the device was finished as this was the last pass. It is
+    /// told that it is done once it has been ejected.
+    Finished(Target<P>),
+    /// The device failed, and was finished with an error.
+    Failed,
+    /// The image stopped being read, or the task was cancelled, before the end.
+    Interrupted(Target<P>),
 }
 
 #[derive(new)]
 pub struct Task<P: Progress> {
-    image: File,
-
-    #[new(default)]
-    pub writer: MultiWriter<File>,
+    image: Image,
 
     #[new(default)]
-    pub state: HashMap<usize, (P::Device, P)>,
+    targets: Vec<Target<P>>,
 
     #[new(value = "125")]
     pub millis_between: u64,
 
+    /// The number of buffers that each device may fall behind the reading of the image.
+    /// Faster devices run at most this far ahead of the slowest, which bounds memory use.
+    #[new(value = "64")]
+    pub window: usize,
+
+    /// Seeks past blocks of zeroes in the image, rather than writing them. Validation
+    /// still reads these blocks back, and requires them to be zeroes. This is ignored
+    /// unless the devices are erased, as skipped blocks would keep what they held before,
+    /// and so also when any device is resumed rather than erased.
+    #[new(default)]
+    pub skip_zeroes: bool,
+
+    /// Erases each device before it is written to. The unallocated clusters of virtual
+    /// machine disks are then skipped, rather than written as zeroes.
+    #[new(default)]
+    pub erase: Option<Erase>,
+
+    /// Reads the image through once before anything is erased or written, when it is
+    /// checked against the checksums of a block map, so that an image which does not match
+    /// never reaches the devices. Otherwise each range is checked as it is written, and the
+    /// devices fail once a range which does not match has been written to them.
+    #[new(default)]
+    pub precheck: bool,
+
+    /// Reads each block of the devices before writing it, and only writes the blocks which
+    /// differ from the image. Devices are not erased, and blocks of zeroes are compared
+    /// rather than skipped.
+    #[new(default)]
+    pub delta: bool,
+
+    /// When cancelled, each device is sent a `Cancelled` event, and `process` returns
+    /// `DiskError::Killed`.
+    #[new(default)]
+    pub cancel: CancelHandle,
+
+    /// How devices are validated, when `check` is set.
+    #[new(default)]
+    pub validation: Validation,
+
+    /// Continues writing each device from the offset recorded for it in the journal, after
+    /// reading back a sample of the blocks that were already written. The journal is only
+    /// read when this is set, while it is written to whenever one is given.
+    #[new(default)]
+    pub resume: bool,
+
+    /// Asks the kernel to read the partition table of each block device again once it is
+    /// finished, so that its partitions appear without it being plugged in again.
+    #[new(default)]
+    pub reread: bool,
+
+    /// After the partition table is read again, waits up to this long for udev to create
+    /// the nodes of the partitions.
+    #[new(default)]
+    pub settle: Option<Duration>,
+
+    /// Flushes each device once it is finished, and powers it off with this once every
+    /// device is finished and closed.
+    #[new(default)]
+    pub eject: Option<Arc<dyn PowerOff>>,
+
+    /// The sender of the stream of events, and the stream until it is taken.
+    #[new(default)]
+    events: Option<(EventSender<P::Device>, Option<EventStream<P::Device>>)>,
+
+    #[new(default)]
+    written: Option<Written>,
+
+    #[new(default)]
+    checkpoints: Option<Checkpoints>,
+
     check: bool,
 }
 
+impl<D: Clone> Task<EventProgress<D>> {
+    /// A progress which sends the events of the device that it is subscribed with to the
+    /// stream of the task, as an alternative to implementing `Progress`.
+    pub fn event_progress(&mut self) -> EventProgress<D> {
+        self.event_channel().0.progress()
+    }
+
+    /// Takes the stream of the events of every device which is subscribed with an
+    /// `event_progress`, or `None` if it was already taken. The stream ends once the task
+    /// is finished.
+    ///
+    /// Only a task of `EventProgress` has a stream. The events of a task with any other
+    /// `Progress` are only given to that progress, which may pass them on to an
+    /// `EventProgress` of a stream made with `events::channel`.
+    pub fn events(&mut self) -> Option<EventStream<D>> {
+        self.event_channel().1.take()
+    }
+
+    fn event_channel(&mut self) -> &mut (EventSender<D>, Option<EventStream<D>>) {
+        self.events.get_or_insert_with(|| {
+            let (sender, stream) = events::channel();
+            (sender, Some(stream))
+        })
+    }
+}
+
 impl<P: Progress> Task<P> {
     /// Performs the asynchronous USB device flashing.
+    ///
+    /// The image is read once, a buffer at a time, and each device is written to by its
+    /// own pipeline. Devices are finished as soon as their own pipeline is done.
     pub async fn process(mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        self.exclude_source().await;
+        self.restore();
+        self.check_image(buf).await?;
+        self.prepare().await;
+        self.check_cancelled()?;
         self.copy(buf).await.context("failed to copy ISO")?;
 
         if self.check {
@@ -36,108 +404,850 @@ impl<P: Progress> Task<P> {
             self.validate(buf).await.context("validation error")?;
         }
 
-        for (_, pb) in self.state.values_mut() {
-            pb.finish();
-        }
-
         Ok(())
     }
 
     pub fn subscribe(&mut self, file: File, device: P::Device, progress: P) -> &mut Self {
-        let entity = self.writer.insert(file);
-        self.state.insert(entity, (device, progress));
+        let identity = None;
+        self.targets.push(Target { file, device, pb: progress, identity, resume: 0, reader: None });
         self
     }
 
-    async fn copy(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
-        let mut stream = self.writer.copy(&mut self.image, buf);
-        let mut total = 0;
-        let mut last = Instant::now();
-        while let Some(event) = stream.next().await {
-            match event {
-                CopyEvent::Progress(written) => {
-                    total += written as u64;
-                    let now = Instant::now();
-                    if now.duration_since(last).as_millis() > self.millis_between as u128 {
-                        last = now;
-                        for (_, pb) in self.state.values_mut() {
-                            pb.set(total);
-                        }
-                    }
-                }
-                CopyEvent::Failure(entity, why) => {
-                    let (device, mut pb) = self.state.remove(&entity).expect("missing entity");
-                    pb.message(&device, "E", &format!("{}", why));
-                    pb.finish();
+    /// Subscribes a device which is identified in the journal, so that it may be resumed.
+    pub fn subscribe_identified(
+        &mut self,
+        file: File,
+        device: P::Device,
+        progress: P,
+        identity: DeviceId,
+    ) -> &mut Self {
+        let identity = Some(identity);
+        self.targets.push(Target { file, device, pb: progress, identity, resume: 0, reader: None });
+        self
+    }
+
+    /// Reads the device which was subscribed last back through `reader`, which was opened
+    /// with `O_DIRECT`, rather than opening the device again. Frontends which have udisks
+    /// open devices for them may not open them again themselves.
+    pub fn direct_reader(&mut self, reader: std::fs::File) -> &mut Self {
+        if let Some(target) = self.targets.last_mut() {
+            target.reader = Some(reader);
+        }
+        self
+    }
+
+    /// Records how far each identified device has been written with the image in the
+    /// journal, so that writing can be resumed if it is interrupted.
+    pub fn journal(&mut self, journal: Journal, image: ImageId) -> &mut Self {
+        self.checkpoints = Some(Checkpoints { journal: Arc::new(Mutex::new(journal)), image });
+        self
+    }
+
+    /// Fails any device which the image is read from, such as the drive which is cloned,
+    /// rather than overwriting it as it is read.
+    async fn exclude_source(&mut self) {
+        let origin = self.image.origin();
+        for target in std::mem::take(&mut self.targets) {
+            let is_source = match target.file.metadata().await {
+                Ok(metadata) => Origin::of(&metadata) == origin,
+                Err(_) => false,
+            };
+
+            match is_source {
+                true => {
+                    failed(target, DiskError::Source);
                 }
-                CopyEvent::SourceFailure(why) => {
-                    for (device, pb) in self.state.values_mut() {
-                        pb.message(device, "E", &format!("{}", why));
-                        pb.finish();
-                    }
+                false => self.targets.push(target),
+            }
+        }
+    }
+
+    /// Looks up the offsets that each device is resumed from.
+    fn restore(&mut self) {
+        let checkpoints = match (&self.checkpoints, self.resume) {
+            (Some(checkpoints), true) => checkpoints,
+            _ => return,
+        };
+
+        let journal = checkpoints.journal.lock().expect("journal lock");
+        for target in &mut self.targets {
+            if let Some(identity) = &target.identity {
+                target.resume = journal.offset(&checkpoints.image, identity).unwrap_or(0);
+            }
+        }
+    }
+
+    /// Reads the ranges of the block map through before anything is erased or written, so
+    /// that an image which does not match their checksums never reaches the devices.
+    async fn check_image(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        if !self.precheck || !self.image.has_checksums() || self.targets.is_empty() {
+            return Ok(());
+        }
+
+        for target in &mut self.targets {
+            target.pb.event(&target.device, FlashEvent::Phase(Phase::Checking));
+        }
 
-                    return Err(why).context("error reading from source");
+        let (image, targets) = (&mut self.image, &mut self.targets);
+        match read_through(image, buf, targets, self.millis_between, &self.cancel).await {
+            Ok(()) => Ok(()),
+            Err(Stop::Source(why)) => Err(source_failure(&mut self.targets, why)),
+            Err(_) => Err(cancelled(&mut self.targets)),
+        }
+    }
+
+    /// Erases the subscribed devices if requested, dropping those which could not be.
+    /// Devices which are resumed are not erased, so nothing which the image leaves out is
+    /// skipped over when any of them are.
+    async fn prepare(&mut self) {
+        let erase = match self.erase {
+            Some(erase) if !self.delta => erase,
+            _ => {
+                self.image.set_fill_unallocated(true);
+                return;
+            }
+        };
+
+        if self.targets.iter().any(|target| target.resume != 0) {
+            self.skip_zeroes = false;
+            self.image.set_fill_unallocated(true);
+        }
+
+        for target in self.targets.iter_mut().filter(|target| target.resume == 0) {
+            target.pb.event(&target.device, FlashEvent::Phase(Phase::Erasing));
+        }
+
+        // Devices are erased whole when the size that the image expands to is not known.
+        let size = self.image.size();
+        let mut lengths = Vec::new();
+        for target in &mut self.targets {
+            lengths.push(match size {
+                Some(size) => Ok(size),
+                None => target.file.seek(SeekFrom::End(0)).await,
+            });
+        }
+
+        let results = join_all(self.targets.iter().zip(lengths).map(|(target, length)| {
+            let fd = target.file.as_raw_fd();
+            let resumed = target.resume != 0;
+            spawn_blocking(move || match resumed {
+                true => Ok(()),
+                false => blockdev::erase(fd, erase, length?),
+            })
+        }))
+        .await;
+
+        for (target, result) in std::mem::take(&mut self.targets).into_iter().zip(results) {
+            match result {
+                Ok(()) => self.targets.push(target),
+                Err(why) => {
+                    failed(target, DiskError::Erase { why });
                 }
-                CopyEvent::NoWriters => return Err(anyhow!("no writers left")),
             }
         }
+    }
+
+    async fn copy(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        if let (true, Validation::Hash { checksum, expected }) = (self.check, &self.validation) {
+            let checksum = *checksum;
+            self.written = Some(Written {
+                checksum,
+                hasher: Hasher::new(checksum),
+                regions: Vec::new(),
+                expanded: expected.as_ref().map(|_| (Hasher::new(checksum), 0)),
+            });
+        }
+
+        for target in &mut self.targets {
+            target.pb.event(&target.device, FlashEvent::Phase(Phase::Writing));
+        }
+
+        self.run(buf, Pass::Copy, !self.check).await
+    }
+
+    fn check_cancelled(&mut self) -> anyhow::Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(cancelled(&mut self.targets));
+        }
 
         Ok(())
     }
 
     async fn seek(&mut self) -> anyhow::Result<()> {
-        for (path, pb) in self.state.values_mut() {
-            pb.set(0);
-            pb.message(path, "S", "");
+        for target in &mut self.targets {
+            target.pb.event(&target.device, FlashEvent::Phase(Phase::Seeking));
         }
 
-        self.image.seek(SeekFrom::Start(0)).await?;
-
-        let mut stream = self.writer.seek(SeekFrom::Start(0));
-        while let Some((entity, why)) = stream.next().await {
-            let (path, mut pb) = self.state.remove(&entity).expect("missing entity");
-            pb.message(&path, "E", &format!("errored seeking to start: {}", why));
-            pb.finish();
+        // Each pipeline seeks its device to the offset of the first block it receives.
+        if self.written.is_none() {
+            self.image.rewind().await?;
         }
 
         Ok(())
     }
 
     async fn validate(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
-        for (path, pb) in self.state.values_mut() {
-            pb.set(0);
-            pb.message(path, "V", "");
+        for target in &mut self.targets {
+            target.pb.event(&target.device, FlashEvent::Phase(Phase::Verifying));
+        }
+
+        match self.written.take() {
+            Some(written) => self.validate_hashes(written).await,
+            None => self.run(buf, Pass::Validate, true).await,
+        }
+    }
+
+    /// Hashes the regions of each device that the image was written to, and compares them
+    /// to the digest of the image.
+    async fn validate_hashes(&mut self, written: Written) -> anyhow::Result<()> {
+        let Written { checksum, hasher, regions, expanded } = written;
+        let digest = hasher.finalize();
+
+        if let (Validation::Hash { expected: Some(expected), .. }, Some((mut image, position))) =
+            (&self.validation, expanded)
+        {
+            // The image may also end with a gap.
+            hash_zeroes(&mut image, self.image.size().unwrap_or(0).saturating_sub(position));
+            if image.finalize() != *expected {
+                for target in self.targets.drain(..) {
+                    failed(target, DiskError::Image(ImageError::Checksum { checksum }));
+                }
+
+                return Err(ImageError::Checksum { checksum }.into());
+            }
+        }
+
+        if self.targets.is_empty() {
+            return Err(anyhow!("no writers left"));
         }
 
-        let copy_bufs = &mut Vec::new();
-        let mut total = 0;
-        let mut stream = self.writer.validate(&mut self.image, buf, copy_bufs);
+        let shared = Shared {
+            cancel: &self.cancel,
+            millis_between: self.millis_between,
+            delta: self.delta,
+            reread: self.reread,
+            settle: self.settle,
+            eject: self.eject.is_some(),
+            checkpoints: self.checkpoints.as_ref(),
+        };
+
+        let pipelines = std::mem::take(&mut self.targets)
+            .into_iter()
+            .map(|target| hash_pipeline(target, checksum, &regions, &digest, &shared));
+
+        let outcomes = join_all(pipelines).await;
+        self.settle(outcomes, Ok(())).await
+    }
+
+    /// Reads the image once, sending each block to the pipeline of every device.
+    async fn run(&mut self, buf: &mut [u8], pass: Pass, last: bool) -> anyhow::Result<()> {
+        if self.targets.is_empty() {
+            return Err(anyhow!("no writers left"));
+        }
+
+        let total = self.image.size();
+        let shared = Shared {
+            cancel: &self.cancel,
+            millis_between: self.millis_between,
+            delta: self.delta,
+            reread: self.reread,
+            settle: self.settle,
+            eject: self.eject.is_some(),
+            checkpoints: self.checkpoints.as_ref(),
+        };
+
+        let mut senders = Vec::new();
+        let mut pipelines = Vec::new();
+        for target in std::mem::take(&mut self.targets) {
+            let (tx, rx) = mpsc::channel(self.window);
+            senders.push(tx);
+            pipelines.push(pipeline(target, rx, pass, last, total, &shared));
+        }
+
+        let erased = self.erase.is_some() && !self.delta;
+        let skip_zeroes = self.skip_zeroes && erased && pass == Pass::Copy;
+        let written = if pass == Pass::Copy { self.written.as_mut() } else { None };
+        let reader = read_blocks(&mut self.image, buf, senders, skip_zeroes, written, &self.cancel);
+        let (read, outcomes) = join(reader, join_all(pipelines)).await;
+        self.settle(outcomes, read).await
+    }
+
+    /// Powers off the finished devices if requested, each once it is closed, and then
+    /// informs them that they are done. The devices are powered off concurrently, as each
+    /// may block for as long as the device takes to stop.
+    async fn done(&self, finished: Vec<Target<P>>) {
+        let ejections = finished.into_iter().map(|target| {
+            let Target { file, device, pb, reader, .. } = target;
+            let eject = self.eject.clone();
+            async move {
+                let ejected = match eject {
+                    Some(eject) => {
+                        let disk =
+                            std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()));
+                        drop((file, reader));
 
-        while let Some(event) = stream.next().await {
-            match event {
-                ValidationEvent::Progress(written) => {
-                    total += written as u64;
-                    for (_, pb) in self.state.values_mut() {
-                        pb.set(total);
+                        let power_off = move || disk.and_then(|disk| eject.power_off(&disk));
+                        Some(spawn_blocking(power_off).await)
                     }
+                    None => None,
+                };
+
+                (device, pb, ejected)
+            }
+        });
+
+        for (device, mut pb, ejected) in join_all(ejections).await {
+            match ejected {
+                Some(Ok(())) => pb.event(&device, FlashEvent::Ejected),
+                Some(Err(why)) => {
+                    pb.event(&device, FlashEvent::EjectFailed(DiskError::Eject { why }))
+                }
+                None => (),
+            }
+
+            pb.event(&device, FlashEvent::Done);
+        }
+    }
+
+    /// Keeps the devices which may continue, finishes those which are done, and determines
+    /// the result of the pass.
+    async fn settle(
+        &mut self,
+        outcomes: Vec<Outcome<P>>,
+        read: Result<(), Stop>,
+    ) -> anyhow::Result<()> {
+        let mut finished = Vec::new();
+        let mut interrupted = false;
+        let mut succeeded = false;
+        for outcome in outcomes {
+            match outcome {
+                Outcome::Continue(target) => {
+                    succeeded = true;
+                    self.targets.push(target);
+                }
+                Outcome::Interrupted(target) => {
+                    interrupted = true;
+                    self.targets.push(target);
                 }
-                ValidationEvent::Failure(entity, why) => {
-                    let (path, mut pb) = self.state.remove(&entity).expect("missing entity");
-                    pb.message(&path, "E", &format!("{}", why));
-                    pb.finish();
+                Outcome::Finished(target) => {
+                    succeeded = true;
+                    finished.push(target);
                 }
-                ValidationEvent::SourceFailure(why) => {
-                    for (path, pb) in self.state.values_mut() {
-                        pb.message(path, "E", &format!("error reading from source: {}", why));
-                        pb.finish();
+                Outcome::Failed => (),
+            }
+        }
+
+        self.done(finished).await;
+
+        match read {
+            Err(Stop::Source(why)) => Err(source_failure(&mut self.targets, why)),
+            Err(Stop::Cancelled) => Err(cancelled(&mut self.targets)),
+            Err(Stop::NoWriters) | Ok(()) if interrupted => Err(cancelled(&mut self.targets)),
+            Err(Stop::NoWriters) => Err(anyhow!("no writers left")),
+            Ok(()) if !succeeded => Err(anyhow!("no writers left")),
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+/// Reads each extent of the image a buffer at a time, and sends the blocks to every
+/// pipeline which is still receiving them.
+async fn read_blocks(
+    image: &mut Image,
+    buf: &mut [u8],
+    mut senders: Vec<mpsc::Sender<Message>>,
+    skip_zeroes: bool,
+    mut written: Option<&mut Written>,
+    cancel: &CancelHandle,
+) -> Result<(), Stop> {
+    while let Some(extent) = image.next_extent().await.map_err(Stop::Source)? {
+        let mut offset = extent.offset;
+        let mut remaining = extent.length;
+
+        while remaining != 0 {
+            if cancel.is_cancelled() {
+                return Err(Stop::Cancelled);
+            }
+
+            let max = remaining.min(buf.len() as u64) as usize;
+            let read = read_to_fill(image, &mut buf[..max]).await.map_err(Stop::Source)?;
+            if read == 0 {
+                break;
+            }
+
+            remaining -= read as u64;
+            if let Some(written) = written.as_mut() {
+                written.update(offset, &buf[..read]);
+            }
+
+            let mut start = 0;
+            while start < read {
+                // Runs of blocks which are all zeroes, or not, are sent or skipped whole.
+                let end = match skip_zeroes {
+                    true => {
+                        let zeroes = is_zeroes(&buf[start..read.min(start + ZERO_BLOCK)]);
+                        let mut end = read.min(start + ZERO_BLOCK);
+                        while end < read
+                            && is_zeroes(&buf[end..read.min(end + ZERO_BLOCK)]) == zeroes
+                        {
+                            end = read.min(end + ZERO_BLOCK);
+                        }
+
+                        if zeroes {
+                            start = end;
+                            continue;
+                        }
+
+                        end
                     }
+                    false => read,
+                };
+
+                let block = Block { offset: offset + start as u64, data: buf[start..end].into() };
+                broadcast(&mut senders, Message::Block(Arc::new(block))).await?;
+                start = end;
+            }
+
+            offset += read as u64;
+        }
+    }
+
+    broadcast(&mut senders, Message::End).await
+}
+
+/// Reads each extent of the image through without sending it anywhere, informing every
+/// device of how far the image has been read, and then rewinds the image.
+async fn read_through<P: Progress>(
+    image: &mut Image,
+    buf: &mut [u8],
+    targets: &mut [Target<P>],
+    millis_between: u64,
+    cancel: &CancelHandle,
+) -> Result<(), Stop> {
+    let mut meter = Meter::new(image.size());
+    let mut last_update = Instant::now();
+    let mut position = 0;
+    while let Some(extent) = image.next_extent().await.map_err(Stop::Source)? {
+        let mut remaining = extent.length;
+        while remaining != 0 {
+            if cancel.is_cancelled() {
+                return Err(Stop::Cancelled);
+            }
+
+            let max = remaining.min(buf.len() as u64) as usize;
+            let read = read_to_fill(image, &mut buf[..max]).await.map_err(Stop::Source)?;
+            if read == 0 {
+                break;
+            }
 
-                    return Err(why).context("error reading from source");
+            remaining -= read as u64;
+            position = extent.offset + extent.length - remaining;
+
+            let now = Instant::now();
+            if now.duration_since(last_update).as_millis() > millis_between as u128 {
+                last_update = now;
+                for target in targets.iter_mut() {
+                    report(target, &mut meter, position);
                 }
-                ValidationEvent::NoWriters => return Err(anyhow!("no writers left")),
             }
         }
+    }
 
-        Ok(())
+    for target in targets.iter_mut() {
+        report(target, &mut meter, position);
     }
+
+    image.rewind().await.map_err(Stop::Source)
+}
+
+/// Sends the message to every pipeline, dropping those which have stopped receiving.
+async fn broadcast(senders: &mut Vec<mpsc::Sender<Message>>, message: Message) -> Result<(), Stop> {
+    let mut index = 0;
+    while index < senders.len() {
+        if senders[index].send(message.clone()).await.is_err() {
+            senders.swap_remove(index);
+        } else {
+            index += 1;
+        }
+    }
+
+    if senders.is_empty() {
+        return Err(Stop::NoWriters);
+    }
+
+    Ok(())
+}
+
+/// Writes or validates the blocks received at the pace of the device, independently
+/// of the other devices.
+async fn pipeline<P: Progress>(
+    mut target: Target<P>,
+    mut blocks: mpsc::Receiver<Message>,
+    pass: Pass,
+    last: bool,
+    total: Option<u64>,
+    shared: &Shared<'_>,
+) -> Outcome<P> {
+    let mut position = None;
+    let mut cursor = None;
+    let mut resumed = 0;
+    // The blocks which the device already held since the last one which was read back.
+    let mut unverified = Vec::new();
+    let mut rewritten = 0;
+    let mut last_update = Instant::now();
+    let mut last_checkpoint = Instant::now();
+    let mut meter = Meter::new(total);
+
+    // Validation, and comparing the blocks that a device may already hold, reads the
+    // device through a descriptor of its own, bypassing the page cache.
+    let mut verifier = None;
+    if pass == Pass::Validate || target.resume != 0 || shared.delta {
+        let fd = target.file.as_raw_fd();
+        let reader = target.reader.as_ref().map(AsRawFd::as_raw_fd);
+        match spawn_blocking(move || Verifier::open(fd, reader)).await {
+            Ok(opened) => verifier = Some(opened),
+            Err(why) => return failed(target, DiskError::Verify { why }),
+        }
+    }
+
+    loop {
+        let block = match blocks.next().await {
+            Some(Message::Block(block)) => block,
+            Some(Message::End) => break,
+            None => return Outcome::Interrupted(target),
+        };
+
+        if shared.cancel.is_cancelled() {
+            return Outcome::Interrupted(target);
+        }
+
+        let length = block.data.len() as u64;
+        let end = block.offset + length;
+        let result = if pass == Pass::Validate {
+            match compare(&mut verifier, &block).await {
+                Ok(Some(offset)) => Err(DiskError::VerifyMismatch { offset }),
+                Ok(None) => Ok(()),
+                Err(why) => Err(verify_error(why)),
+            }
+        } else if end <= target.resume {
+            // Blocks which the device already holds are sampled, rather than written again.
+            resumed += 1;
+            if (resumed - 1) % RESUME_SAMPLE == 0 || end + length > target.resume {
+                match compare(&mut verifier, &block).await {
+                    Ok(Some(_)) => {
+                        // Those which were not read back are written again along with it,
+                        // and writing continues from there.
+                        unverified.push(block.clone());
+                        target.resume = unverified[0].offset;
+                        shared.record(&target, target.resume).await;
+
+                        let mut result = Ok(());
+                        for block in unverified.drain(..) {
+                            let end = block.offset + block.data.len() as u64;
+                            result =
+                                write_at(&mut target.file, block.offset, &block.data, cursor).await;
+                            cursor = Some(end);
+                            if result.is_err() {
+                                break;
+                            }
+                        }
+
+                        result
+                    }
+                    Ok(None) => {
+                        unverified.clear();
+                        Ok(())
+                    }
+                    Err(why) => Err(verify_error(why)),
+                }
+            } else {
+                unverified.push(block.clone());
+                Ok(())
+            }
+        } else if shared.delta {
+            match compare(&mut verifier, &block).await {
+                Ok(Some(offset)) => {
+                    // The block is written again from the first page which differs.
+                    let start = (offset - block.offset) as usize / ZERO_BLOCK * ZERO_BLOCK;
+                    let data = &block.data[start..];
+                    let offset = block.offset + start as u64;
+                    let result = write_at(&mut target.file, offset, data, cursor).await;
+                    rewritten += data.len() as u64;
+                    cursor = Some(end);
+                    result
+                }
+                Ok(None) => Ok(()),
+                Err(why) => Err(verify_error(why)),
+            }
+        } else {
+            let result = write_at(&mut target.file, block.offset, &block.data, cursor).await;
+            cursor = Some(end);
+            result
+        };
+
+        if let Err(why) = result {
+            if pass == Pass::Validate || end <= target.resume {
+                shared.forget(&target).await;
+            }
+
+            return failed(target, why);
+        }
+
+        position = Some(end);
+
+        let now = Instant::now();
+        if now.duration_since(last_update).as_millis() > shared.millis_between as u128 {
+            last_update = now;
+            report(&mut target, &mut meter, end);
+        }
+
+        if pass == Pass::Copy
+            && shared.records(&target)
+            && now.duration_since(last_checkpoint) >= CHECKPOINT_INTERVAL
+        {
+            last_checkpoint = now;
+            if let Err(why) = sync(&mut target.file).await {
+                return failed(target, DiskError::Flush { why });
+            }
+
+            shared.record(&target, end).await;
+        }
+    }
+
+    if let Some(position) = position {
+        report(&mut target, &mut meter, position);
+    }
+
+    if pass == Pass::Copy && shared.delta {
+        target.pb.event(&target.device, FlashEvent::Rewritten(rewritten));
+    }
+
+    // Writes may still be in flight until the device is flushed.
+    if pass == Pass::Copy {
+        target.pb.event(&target.device, FlashEvent::Phase(Phase::Syncing));
+    }
+
+    if let Err(why) = target.file.flush().await {
+        return failed(target, DiskError::Flush { why });
+    }
+
+    if let (Pass::Copy, Some(position)) = (pass, position) {
+        if shared.records(&target) {
+            if let Err(why) = target.file.sync_data().await {
+                return failed(target, DiskError::Flush { why });
+            }
+
+            shared.record(&target, position).await;
+        }
+    }
+
+    if last {
+        shared.forget(&target).await;
+        return finish(target, shared).await;
+    }
+
+    Outcome::Continue(target)
+}
+
+/// Hashes `length` bytes of zeroes.
+fn hash_zeroes(hasher: &mut Hasher, mut length: u64) {
+    let zeroes = [0u8; 4096];
+    while length != 0 {
+        let chunk = length.min(zeroes.len() as u64) as usize;
+        hasher.update(&zeroes[..chunk]);
+        length -= chunk as u64;
+    }
+}
+
+/// Hashes each region of the device that the image was written to, in the order that the
+/// image was read, and compares the result to the digest of the image.
+async fn hash_pipeline<P: Progress>(
+    mut target: Target<P>,
+    checksum: Checksum,
+    regions: &[(u64, u64)],
+    digest: &[u8],
+    shared: &Shared<'_>,
+) -> Outcome<P> {
+    let fd = target.file.as_raw_fd();
+    let reader = target.reader.as_ref().map(AsRawFd::as_raw_fd);
+    let mut verifier = match spawn_blocking(move || Verifier::open(fd, reader)).await {
+        Ok(verifier) => verifier,
+        Err(why) => return failed(target, DiskError::Verify { why }),
+    };
+
+    let mut hasher = Hasher::new(checksum);
+    let mut position = 0;
+    let mut last_update = Instant::now();
+    let mut meter = Meter::new(regions.last().map(|&(offset, length)| offset + length));
+
+    for &(offset, length) in regions {
+        let end = offset + length;
+        position = offset;
+
+        while position < end {
+            if shared.cancel.is_cancelled() {
+                return Outcome::Interrupted(target);
+            }
+
+            let at = position;
+            let length = (end - at).min(HASH_CHUNK as u64) as usize;
+            let result;
+            (verifier, hasher, result) = spawn_blocking(move || {
+                let result = verifier.read(at, length).map(|data| hasher.update(data));
+                (verifier, hasher, result)
+            })
+            .await;
+
+            if let Err(why) = result {
+                shared.forget(&target).await;
+                return failed(target, verify_error(why));
+            }
+
+            position += length as u64;
+
+            let now = Instant::now();
+            if now.duration_since(last_update).as_millis() > shared.millis_between as u128 {
+                last_update = now;
+                report(&mut target, &mut meter, position);
+            }
+        }
+    }
+
+    shared.forget(&target).await;
+    if hasher.finalize() != digest {
+        return failed(target, DiskError::VerifyDigest { checksum });
+    }
+
+    report(&mut target, &mut meter, position);
+    finish(target, shared).await
+}
+
+/// Finishes the device, after reading its partition table again and flushing it for
+/// ejection if requested. The device is still locked while its partitions are found, and
+/// is unlocked for udev to create them.
+async fn finish<P: Progress>(mut target: Target<P>, shared: &Shared<'_>) -> Outcome<P> {
+    let is_block = match target.file.metadata().await {
+        Ok(metadata) => metadata.file_type().is_block_device(),
+        Err(_) => false,
+    };
+
+    if shared.reread && is_block {
+        target.pb.event(&target.device, FlashEvent::Phase(Phase::Rereading));
+        match partitions::reread(target.file.as_raw_fd(), shared.settle).await {
+            Ok((partitions, timed_out)) => {
+                let event = FlashEvent::Partitions { partitions, timed_out };
+                target.pb.event(&target.device, event);
+            }
+            Err(why) => return failed(target, DiskError::Reread { why }),
+        }
+    }
+
+    if shared.eject {
+        target.pb.event(&target.device, FlashEvent::Phase(Phase::Ejecting));
+        if let Err(why) = sync(&mut target.file).await {
+            return failed(target, DiskError::Flush { why });
+        }
+
+        // The kernel's buffers of the device are dropped, as it does when it is closed.
+        if is_block {
+            if let Err(why) = blockdev::flush_buffers(target.file.as_raw_fd()) {
+                return failed(target, DiskError::Flush { why });
+            }
+        }
+    }
+
+    Outcome::Finished(target)
+}
+
+/// Informs the device of how far it has got through the current phase.
+fn report<P: Progress>(target: &mut Target<P>, meter: &mut Meter, position: u64) {
+    let rate = meter.update(position);
+    target.pb.event(&target.device, FlashEvent::Progress { position, rate });
+}
+
+/// Informs the device of the error which it failed with.
+fn failed<P: Progress>(mut target: Target<P>, why: DiskError) -> Outcome<P> {
+    target.pb.event(&target.device, FlashEvent::Failed(why));
+    Outcome::Failed
+}
+
+/// Reads back the region of the device that the block belongs at, and returns the offset
+/// of the first byte which differs.
+async fn compare(verifier: &mut Option<Verifier>, block: &Arc<Block>) -> io::Result<Option<u64>> {
+    let mut reader = verifier.take().expect("device opened for reading");
+    let block = block.clone();
+    let (reader, result) = spawn_blocking(move || {
+        let result = reader.compare(block.offset, &block.data);
+        (reader, result)
+    })
+    .await;
+
+    *verifier = Some(reader);
+    result
+}
+
+/// Waits for the writes to the device to reach it.
+pub(crate) async fn sync(file: &mut File) -> io::Result<()> {
+    file.flush().await?;
+    file.sync_data().await
+}
+
+/// Writes the data at the offset, seeking there if the file is not already at it.
+pub(crate) async fn write_at(
+    file: &mut File,
+    offset: u64,
+    data: &[u8],
+    position: Option<u64>,
+) -> Result<(), DiskError> {
+    if position != Some(offset) {
+        file.seek(SeekFrom::Start(offset)).await.map_err(|why| DiskError::Seek { offset, why })?;
+    }
+
+    file.write_all(data).await.map_err(|why| match why.kind() {
+        io::ErrorKind::WriteZero => DiskError::WriteEOF,
+        _ => DiskError::Write { why },
+    })
+}
+
+pub(crate) fn verify_error(why: io::Error) -> DiskError {
+    match why.kind() {
+        io::ErrorKind::UnexpectedEof => DiskError::VerifyEOF,
+        _ => DiskError::Verify { why },
+    }
+}
+
+/// Informs every device that the task was cancelled.
+fn cancelled<P: Progress>(targets: &mut Vec<Target<P>>) -> anyhow::Error {
+    for mut target in targets.drain(..) {
+        target.pb.event(&target.device, FlashEvent::Cancelled);
+    }
+
+    anyhow::Error::new(DiskError::Killed)
+}
+
+/// Informs every device that the image could not be read.
+fn source_failure<P: Progress>(targets: &mut Vec<Target<P>>, why: io::Error) -> anyhow::Error {
+    for target in targets.drain(..) {
+        let why = io::Error::new(why.kind(), why.to_string());
+        failed(target, DiskError::Image(ImageError::ReadError { why }));
+    }
+
+    anyhow::Error::new(why).context("error reading from source")
+}
+
+/// Reads until the buffer is full, or the end of the image is reached.
+async fn read_to_fill(image: &mut Image, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match image.read(&mut buf[read..]).await? {
+            0 => break,
+            count => read += count,
+        }
+    }
+
+    Ok(read)
+}
+
+fn is_zeroes(buf: &[u8]) -> bool {
+    buf.iter().all(|&byte| byte == 0)
 }
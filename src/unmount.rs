@@ -0,0 +1,133 @@
+//! Releasing the mounts and swap of a disk before it is written to.
+
+use crate::{
+    busy::{boxed, mounts_of, names, swaps_of, SystemRoot},
+    DiskError,
+};
+use std::{
+    ffi::{CString, OsStr},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+/// How many times the mounts of a disk are released, as they may be mounted again by udisks
+/// or gvfs while they are being released.
+const ATTEMPTS: usize = 5;
+
+/// How a filesystem is unmounted, from the least to the most disruptive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unmount {
+    Normal,
+    /// Aborts pending requests to the filesystem, if it supports that.
+    Force,
+    /// Detaches the filesystem now, and cleans it up once it is no longer busy.
+    Lazy,
+}
+
+/// The system calls which release a disk, which may be substituted in tests.
+pub trait MountBackend {
+    fn unmount(&mut self, target: &Path, how: Unmount) -> io::Result<()>;
+
+    fn swap_off(&mut self, device: &Path) -> io::Result<()>;
+
+    /// Waits for anything which reacts to unmounts, such as udisks, to have done so.
+    fn settle(&mut self) {}
+}
+
+/// Releases disks with `umount2` and `swapoff`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Native;
+
+impl MountBackend for Native {
+    fn unmount(&mut self, target: &Path, how: Unmount) -> io::Result<()> {
+        let flags = match how {
+            Unmount::Normal => 0,
+            Unmount::Force => libc::MNT_FORCE,
+            Unmount::Lazy => libc::MNT_DETACH,
+        };
+
+        let target = cstring(target.as_os_str())?;
+        match unsafe { libc::umount2(target.as_ptr(), flags) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn swap_off(&mut self, device: &Path) -> io::Result<()> {
+        let device = cstring(device.as_os_str())?;
+        match unsafe { libc::swapoff(device.as_ptr()) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn settle(&mut self) {
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+fn cstring(path: &OsStr) -> io::Result<CString> {
+    CString::new(path.as_bytes()).map_err(|why| io::Error::new(io::ErrorKind::InvalidInput, why))
+}
+
+/// Deactivates the swap on the disk at `disk`, which was given as `arg`, and unmounts its
+/// filesystems, until neither is found again afterwards.
+pub fn release<B: MountBackend + ?Sized>(
+    root: &SystemRoot,
+    backend: &mut B,
+    arg: &Path,
+    disk: &Path,
+) -> Result<(), DiskError> {
+    let inspect = |why| DiskError::Inspect { arg: boxed(arg), why };
+    let name = match disk.file_name().and_then(OsStr::to_str) {
+        Some(name) => name,
+        None => return Err(DiskError::NotABlock { arg: boxed(arg) }),
+    };
+
+    let names = names(root, name).map_err(inspect)?;
+    for _ in 0..ATTEMPTS {
+        let swaps = swaps_of(root, &names).map_err(inspect)?;
+        let mut mounts = mounts_of(root, &names).map_err(inspect)?;
+        if swaps.is_empty() && mounts.is_empty() {
+            return Ok(());
+        }
+
+        for swap in swaps {
+            backend
+                .swap_off(&swap)
+                .map_err(|why| DiskError::SwapOff { path: boxed(&swap), why })?;
+        }
+
+        // Filesystems mounted within others are unmounted first.
+        mounts.sort_by_key(|(_, target)| std::cmp::Reverse(target.components().count()));
+        for (_, target) in mounts {
+            unmount(backend, &target)
+                .map_err(|why| DiskError::Unmount { path: boxed(&target), why })?;
+        }
+
+        backend.settle();
+    }
+
+    match mounts_of(root, &names).map_err(inspect)?.into_iter().next() {
+        Some((source, _)) => Err(DiskError::Remounted { arg: boxed(arg), path: boxed(&source) }),
+        None => Ok(()),
+    }
+}
+
+/// Unmounts the filesystem, forcing it and then detaching it if it is busy.
+fn unmount<B: MountBackend + ?Sized>(backend: &mut B, target: &Path) -> io::Result<()> {
+    let mut busy = Ok(());
+    for how in [Unmount::Normal, Unmount::Force, Unmount::Lazy] {
+        match backend.unmount(target, how) {
+            Err(why) if why.raw_os_error() == Some(libc::EBUSY) => busy = Err(why),
+            // The filesystem was already unmounted by something else.
+            Err(why) if why.raw_os_error() == Some(libc::EINVAL) => return Ok(()),
+            result => return result,
+        }
+    }
+
+    busy
+}
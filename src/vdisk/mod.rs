@@ -0,0 +1,283 @@
+//! Reading of virtual machine disk images as the raw disk that they describe.
+//!
+//! Each format maps the clusters of the virtual disk to locations within the image file.
+//! These are collected into runs when the image is opened, and read in the order of the
+//! virtual disk. Clusters which were never allocated, or which are recorded as zeroes,
+//! belong to no run, and are skipped like the unmapped ranges of a block map. Devices which
+//! are not erased beforehand have the gaps between runs read as zeroes instead, so that the
+//! whole of the disk is written and validated.
+
+mod qcow2;
+mod vhd;
+mod vhdx;
+mod vmdk;
+
+use crate::{
+    image::{Extent, Reader},
+    Format,
+};
+use flate2::read::{DeflateDecoder, ZlibDecoder};
+use futures::{
+    io::{AsyncRead, AsyncReadExt},
+    ready,
+    task::{Context, Poll},
+};
+use std::{
+    fs,
+    io::{self, Read},
+    os::unix::fs::FileExt,
+    pin::Pin,
+};
+
+fn invalid(why: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, why)
+}
+
+fn read(file: &fs::File, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; length];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf)
+}
+
+fn u16_le(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_le(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_le(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+fn u32_be(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_be(buf: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/// Detects a virtual machine disk from the first bytes of the image, or from the
+/// footer at the end of the image in the case of fixed VHD images.
+pub(crate) fn probe(
+    file: &fs::File,
+    file_size: u64,
+    magic: &[u8],
+) -> io::Result<Option<(Format, VirtualDisk)>> {
+    let probed = if magic.starts_with(qcow2::MAGIC) {
+        (Format::Qcow2, qcow2::open(file, file_size)?)
+    } else if magic.starts_with(vhdx::MAGIC) {
+        (Format::Vhdx, vhdx::open(file)?)
+    } else if magic.starts_with(vmdk::MAGIC) {
+        (Format::Vmdk, vmdk::open(file, file_size)?)
+    } else if let Some(disk) = vhd::open(file, file_size)? {
+        (Format::Vhd, disk)
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(probed))
+}
+
+/// The format of a virtual machine disk which begins with these bytes. Fixed VHDs, which
+/// only have a footer, are not found this way.
+pub(crate) fn detect(magic: &[u8]) -> Option<Format> {
+    if magic.starts_with(qcow2::MAGIC) {
+        Some(Format::Qcow2)
+    } else if magic.starts_with(vhdx::MAGIC) {
+        Some(Format::Vhdx)
+    } else if magic.starts_with(vmdk::MAGIC) {
+        Some(Format::Vmdk)
+    } else if magic.starts_with(vhd::COOKIE) {
+        Some(Format::Vhd)
+    } else {
+        None
+    }
+}
+
+/// Where the data of a run is stored in the image file.
+#[derive(Clone, Copy, Debug)]
+enum Data {
+    /// Stored as is, starting at this offset.
+    Stored { offset: u64 },
+    /// A raw deflate stream of at most `length` bytes, as used by qcow2.
+    Deflate { offset: u64, length: u64 },
+    /// A VMDK grain marker, followed by a zlib stream of at most `limit` bytes.
+    Grain { offset: u64, limit: u64 },
+}
+
+/// A region of the virtual disk which has been allocated.
+#[derive(Clone, Copy, Debug)]
+struct Run {
+    offset: u64,
+    length: u64,
+    data: Data,
+}
+
+/// The run currently being read.
+enum Current {
+    Stored {
+        remaining: u64,
+    },
+    /// A gap between runs, which is read as zeroes.
+    Zeroes {
+        remaining: u64,
+    },
+    Inflated {
+        data: Vec<u8>,
+        position: usize,
+    },
+}
+
+pub(crate) struct VirtualDisk {
+    size: u64,
+    runs: Vec<Run>,
+    next: usize,
+    /// The end of the extent which was last produced.
+    position: u64,
+    current: Current,
+    /// Produces the gaps between runs as extents of zeroes, rather than skipping them.
+    pub fill: bool,
+}
+
+impl VirtualDisk {
+    fn new(size: u64) -> Self {
+        let current = Current::Stored { remaining: 0 };
+        VirtualDisk { size, runs: Vec::new(), next: 0, position: 0, current, fill: false }
+    }
+
+    /// Records an allocated region, which must follow those that were already recorded.
+    /// Stored regions which are contiguous in both the disk and the file are merged.
+    fn push(&mut self, offset: u64, length: u64, data: Data) {
+        // The final cluster may extend beyond the end of the disk.
+        let length = length.min(self.size.saturating_sub(offset));
+        if length == 0 {
+            return;
+        }
+
+        if let (Some(last), Data::Stored { offset: from }) = (self.runs.last_mut(), data) {
+            if let Data::Stored { offset: last_from } = last.data {
+                if last.offset + last.length == offset && last_from + last.length == from {
+                    last.length += length;
+                    return;
+                }
+            }
+        }
+
+        self.runs.push(Run { offset, length, data });
+    }
+
+    pub fn reset(&mut self) {
+        self.next = 0;
+        self.position = 0;
+        self.current = Current::Stored { remaining: 0 };
+    }
+
+    /// The size of the virtual disk.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub async fn next_extent(&mut self, reader: &mut Reader) -> io::Result<Option<Extent>> {
+        // The gap before the next run, or at the end of the disk, is produced first.
+        let end = self.runs.get(self.next).map_or(self.size, |run| run.offset);
+        if self.fill && self.position < end {
+            let (offset, length) = (self.position, end - self.position);
+            self.position = end;
+            self.current = Current::Zeroes { remaining: length };
+            return Ok(Some(Extent { offset, length }));
+        }
+
+        let run = match self.runs.get(self.next) {
+            Some(run) => *run,
+            None => return Ok(None),
+        };
+
+        self.next += 1;
+        self.position = run.offset + run.length;
+        self.current = match run.data {
+            Data::Stored { offset } => {
+                reader.seek_to(offset).await?;
+                Current::Stored { remaining: run.length }
+            }
+            Data::Deflate { offset, length } => {
+                // The length is rounded up to whole sectors, which may pass the end of the file.
+                let mut compressed = Vec::new();
+                reader.seek_to(offset).await?;
+                reader.take(length).read_to_end(&mut compressed).await?;
+                inflate(DeflateDecoder::new(compressed.as_slice()), run.length)?
+            }
+            Data::Grain { offset, limit } => {
+                let mut marker = [0u8; 12];
+                reader.seek_to(offset).await?;
+                reader.read_exact(&mut marker).await?;
+                if u64_le(&marker, 0) * 512 != run.offset {
+                    return Err(invalid("VMDK grain is not where its grain table placed it"));
+                }
+
+                let length = u64::from(u32_le(&marker, 8));
+                if length > limit {
+                    return Err(invalid("VMDK grain is larger than its grain size allows"));
+                }
+
+                let mut compressed = vec![0u8; length as usize];
+                reader.read_exact(&mut compressed).await?;
+                inflate(ZlibDecoder::new(compressed.as_slice()), run.length)?
+            }
+        };
+
+        Ok(Some(Extent { offset: run.offset, length: run.length }))
+    }
+
+    pub fn poll_read(
+        &mut self,
+        reader: &mut Reader,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.current {
+            Current::Stored { remaining } => {
+                let max = (*remaining).min(buf.len() as u64) as usize;
+                if max == 0 {
+                    return Poll::Ready(Ok(0));
+                }
+
+                let read = ready!(Pin::new(reader).poll_read(cx, &mut buf[..max]))?;
+                if read == 0 {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "image ended within an allocated cluster",
+                    )));
+                }
+
+                *remaining -= read as u64;
+                Poll::Ready(Ok(read))
+            }
+            Current::Zeroes { remaining } => {
+                let read = (*remaining).min(buf.len() as u64) as usize;
+                buf[..read].fill(0);
+                *remaining -= read as u64;
+                Poll::Ready(Ok(read))
+            }
+            Current::Inflated { data, position } => {
+                let read = (data.len() - *position).min(buf.len());
+                buf[..read].copy_from_slice(&data[*position..*position + read]);
+                *position += read;
+                Poll::Ready(Ok(read))
+            }
+        }
+    }
+}
+
+/// Decompresses a cluster, which must expand to at least `length` bytes.
+fn inflate<R: Read>(decoder: R, length: u64) -> io::Result<Current> {
+    let mut data = Vec::with_capacity(length as usize);
+    decoder.take(length).read_to_end(&mut data)?;
+    if (data.len() as u64) < length {
+        return Err(invalid("compressed cluster is truncated"));
+    }
+
+    Ok(Current::Inflated { data, position: 0 })
+}
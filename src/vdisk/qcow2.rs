@@ -0,0 +1,92 @@
+//! QEMU copy-on-write images, in versions 2 and 3.
+
+use super::{invalid, read, u32_be, u64_be, Data, VirtualDisk};
+use std::{fs, io};
+
+pub const MAGIC: &[u8] = b"QFI\xFB";
+
+const OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+const COMPRESSED: u64 = 1 << 62;
+const ZERO: u64 = 1;
+
+/// Of the incompatible features, only the dirty bit is understood. The refcounts that
+/// it concerns are not needed to read the image.
+const DIRTY: u64 = 1;
+
+pub fn open(file: &fs::File, file_size: u64) -> io::Result<VirtualDisk> {
+    let header = read(file, 0, 72)?;
+
+    let version = u32_be(&header, 4);
+    if version != 2 && version != 3 {
+        return Err(invalid("qcow2 version is not supported"));
+    }
+
+    if u64_be(&header, 8) != 0 {
+        return Err(invalid("qcow2 images with a backing file are not supported"));
+    }
+
+    if u32_be(&header, 32) != 0 {
+        return Err(invalid("encrypted qcow2 images are not supported"));
+    }
+
+    if version == 3 && u64_be(&read(file, 72, 8)?, 0) & !DIRTY != 0 {
+        return Err(invalid("qcow2 image uses features which are not supported"));
+    }
+
+    let cluster_bits = u32_be(&header, 20);
+    if !(9..=21).contains(&cluster_bits) {
+        return Err(invalid("qcow2 cluster size is invalid"));
+    }
+
+    let size = u64_be(&header, 24);
+    let cluster_size = 1u64 << cluster_bits;
+    let l2_entries = cluster_size / 8;
+
+    // Every cluster that the tables may refer to must lie at an offset that can be counted,
+    // and the L1 table within the image, before it is allocated.
+    let span = cluster_size * l2_entries;
+    let l1_entries = size / span + u64::from(size % span != 0);
+    if l1_entries.checked_mul(span).is_none() {
+        return Err(invalid("qcow2 image is too large"));
+    }
+
+    if u64::from(u32_be(&header, 36)) < l1_entries {
+        return Err(invalid("qcow2 L1 table is smaller than the disk"));
+    }
+
+    let l1_offset = u64_be(&header, 40);
+    if l1_offset.saturating_add(l1_entries * 8) > file_size {
+        return Err(invalid("qcow2 L1 table extends beyond the end of the image"));
+    }
+
+    // Compressed clusters store the host offset in the low bits, and the number of
+    // additional 512 byte sectors that the compressed data spans above them.
+    let offset_bits = 62 - (cluster_bits - 8);
+    let sectors_mask = (1u64 << (cluster_bits - 8)) - 1;
+
+    let mut disk = VirtualDisk::new(size);
+    let l1 = read(file, l1_offset, l1_entries as usize * 8)?;
+    for (l1_index, l1_entry) in l1.chunks_exact(8).enumerate() {
+        let l2_offset = u64_be(l1_entry, 0) & OFFSET_MASK;
+        if l2_offset == 0 {
+            continue;
+        }
+
+        let l2 = read(file, l2_offset, cluster_size as usize)?;
+        for (l2_index, l2_entry) in l2.chunks_exact(8).enumerate() {
+            let entry = u64_be(l2_entry, 0);
+            let offset = (l1_index as u64 * l2_entries + l2_index as u64) * cluster_size;
+
+            if entry & COMPRESSED != 0 {
+                let host = entry & ((1 << offset_bits) - 1);
+                let sectors = (entry >> offset_bits) & sectors_mask;
+                let length = (sectors + 1) * 512 - (host & 511);
+                disk.push(offset, cluster_size, Data::Deflate { offset: host, length });
+            } else if entry & OFFSET_MASK != 0 && entry & ZERO == 0 {
+                disk.push(offset, cluster_size, Data::Stored { offset: entry & OFFSET_MASK });
+            }
+        }
+    }
+
+    Ok(disk)
+}
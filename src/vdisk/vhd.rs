@@ -0,0 +1,87 @@
+//! Microsoft Virtual Hard Disk images, in the fixed and dynamic variants.
+
+use super::{invalid, read, u32_be, u64_be, Data, VirtualDisk};
+use std::{fs, io};
+
+pub const COOKIE: &[u8] = b"conectix";
+const DYNAMIC_COOKIE: &[u8] = b"cxsparse";
+
+const FIXED: u32 = 2;
+const DYNAMIC: u32 = 3;
+const DIFFERENCING: u32 = 4;
+
+const UNALLOCATED: u32 = 0xFFFF_FFFF;
+
+/// Dynamic disks begin with a copy of their footer, but fixed disks are raw disks with
+/// only the footer appended, so the end of the file is checked for one as well.
+pub fn open(file: &fs::File, file_size: u64) -> io::Result<Option<VirtualDisk>> {
+    if file_size < 512 {
+        return Ok(None);
+    }
+
+    let mut footer = read(file, 0, 512)?;
+    if !footer.starts_with(COOKIE) {
+        footer = read(file, file_size - 512, 512)?;
+        if !footer.starts_with(COOKIE) || !checksum_valid(&footer) {
+            return Ok(None);
+        }
+    } else if !checksum_valid(&footer) {
+        return Err(invalid("VHD footer checksum is invalid"));
+    }
+
+    let size = u64_be(&footer, 48);
+    let mut disk = VirtualDisk::new(size);
+
+    match u32_be(&footer, 60) {
+        FIXED => {
+            if file_size - 512 < size {
+                return Err(invalid("VHD is smaller than its disk"));
+            }
+
+            disk.push(0, size, Data::Stored { offset: 0 });
+        }
+        DYNAMIC => {
+            let header = read(file, u64_be(&footer, 16), 1024)?;
+            if !header.starts_with(DYNAMIC_COOKIE) {
+                return Err(invalid("VHD dynamic disk header is missing"));
+            }
+
+            let block_size = u64::from(u32_be(&header, 32));
+            if block_size == 0 || block_size % 512 != 0 {
+                return Err(invalid("VHD block size is invalid"));
+            }
+
+            let blocks = (size + block_size - 1) / block_size;
+            if u64::from(u32_be(&header, 28)) < blocks {
+                return Err(invalid("VHD block allocation table is smaller than the disk"));
+            }
+
+            // Each block is preceded by a bitmap of its sectors, padded to a whole sector.
+            let bitmap = ((block_size / 512 + 7) / 8 + 511) / 512 * 512;
+
+            let table = read(file, u64_be(&header, 16), blocks as usize * 4)?;
+            for (index, entry) in table.chunks_exact(4).enumerate() {
+                let sector = u32_be(entry, 0);
+                if sector != UNALLOCATED {
+                    let offset = u64::from(sector) * 512 + bitmap;
+                    disk.push(index as u64 * block_size, block_size, Data::Stored { offset });
+                }
+            }
+        }
+        DIFFERENCING => return Err(invalid("differencing VHD images are not supported")),
+        _ => return Err(invalid("VHD disk type is not supported")),
+    }
+
+    Ok(Some(disk))
+}
+
+/// The checksum is the one's complement of the sum of the footer without the checksum.
+fn checksum_valid(footer: &[u8]) -> bool {
+    let sum = footer
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !(64..68).contains(index))
+        .fold(0u32, |sum, (_, &byte)| sum.wrapping_add(u32::from(byte)));
+
+    !sum == u32_be(footer, 64)
+}
@@ -0,0 +1,123 @@
+//! Microsoft Virtual Hard Disk v2 images.
+
+use super::{invalid, read, u16_le, u32_le, u64_le, Data, VirtualDisk};
+use std::{fs, io};
+
+pub const MAGIC: &[u8] = b"vhdxfile";
+
+const HEADERS: [u64; 2] = [64 * 1024, 128 * 1024];
+const REGION_TABLE: u64 = 192 * 1024;
+
+// GUIDs are stored with their first three fields in little endian.
+const BAT_REGION: [u8; 16] = [
+    0x66, 0x77, 0xC2, 0x2D, 0x23, 0xF6, 0x00, 0x42, 0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08,
+];
+const METADATA_REGION: [u8; 16] = [
+    0x06, 0xA2, 0x7C, 0x8B, 0x90, 0x47, 0x9A, 0x4B, 0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E,
+];
+const FILE_PARAMETERS: [u8; 16] = [
+    0x37, 0x67, 0xA1, 0xCA, 0x36, 0xFA, 0x43, 0x4D, 0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B,
+];
+const VIRTUAL_DISK_SIZE: [u8; 16] = [
+    0x24, 0x42, 0xA5, 0x2F, 0x1B, 0xCD, 0x76, 0x48, 0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8,
+];
+const LOGICAL_SECTOR_SIZE: [u8; 16] = [
+    0x1D, 0xBF, 0x41, 0x81, 0x6F, 0xA9, 0x09, 0x47, 0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F,
+];
+
+const HAS_PARENT: u32 = 2;
+
+const PAYLOAD_FULLY_PRESENT: u64 = 6;
+const PAYLOAD_PARTIALLY_PRESENT: u64 = 7;
+
+pub fn open(file: &fs::File) -> io::Result<VirtualDisk> {
+    // Of the two headers, the one with the greater sequence number is current.
+    let mut current: Option<Vec<u8>> = None;
+    for &offset in &HEADERS {
+        let header = read(file, offset, 64)?;
+        if header.starts_with(b"head")
+            && current.as_ref().map_or(true, |current| u64_le(&header, 8) > u64_le(current, 8))
+        {
+            current = Some(header);
+        }
+    }
+
+    let header = current.ok_or_else(|| invalid("VHDX header is missing"))?;
+    if header[48..64].iter().any(|&byte| byte != 0) {
+        return Err(invalid("VHDX log must be replayed before the image can be read"));
+    }
+
+    let table = read(file, REGION_TABLE, 64 * 1024)?;
+    if !table.starts_with(b"regi") {
+        return Err(invalid("VHDX region table is missing"));
+    }
+
+    let mut bat = None;
+    let mut metadata = None;
+    for entry in table[16..].chunks_exact(32).take(u32_le(&table, 8) as usize) {
+        let region = Some((u64_le(entry, 16), u64::from(u32_le(entry, 24))));
+        if entry[..16] == BAT_REGION {
+            bat = region;
+        } else if entry[..16] == METADATA_REGION {
+            metadata = region;
+        }
+    }
+
+    let (bat_offset, bat_length) = bat.ok_or_else(|| invalid("VHDX has no allocation table"))?;
+    let (metadata_offset, metadata_length) =
+        metadata.ok_or_else(|| invalid("VHDX has no metadata region"))?;
+
+    let metadata = read(file, metadata_offset, metadata_length as usize)?;
+    if !metadata.starts_with(b"metadata") {
+        return Err(invalid("VHDX metadata table is missing"));
+    }
+
+    let item = |id: &[u8; 16], length: usize| {
+        metadata[32..]
+            .chunks_exact(32)
+            .take(usize::from(u16_le(&metadata, 10)))
+            .find(|entry| entry[..16] == id[..])
+            .and_then(|entry| {
+                let offset = u32_le(entry, 16) as usize;
+                metadata.get(offset..offset + length)
+            })
+            .ok_or_else(|| invalid("VHDX metadata is incomplete"))
+    };
+
+    let parameters = item(&FILE_PARAMETERS, 8)?;
+    if u32_le(parameters, 4) & HAS_PARENT != 0 {
+        return Err(invalid("differencing VHDX images are not supported"));
+    }
+
+    let block_size = u64::from(u32_le(parameters, 0));
+    let size = u64_le(item(&VIRTUAL_DISK_SIZE, 8)?, 0);
+    let sector_size = u64::from(u32_le(item(&LOGICAL_SECTOR_SIZE, 4)?, 0));
+    if !block_size.is_power_of_two() || !sector_size.is_power_of_two() {
+        return Err(invalid("VHDX block size is invalid"));
+    }
+
+    // Each chunk of payload blocks is followed by the entry for its sector bitmap.
+    let chunk_ratio = (1u64 << 23) * sector_size / block_size;
+    if chunk_ratio == 0 {
+        return Err(invalid("VHDX block size is invalid"));
+    }
+
+    let blocks = (size + block_size - 1) / block_size;
+    let entries = blocks + blocks.saturating_sub(1) / chunk_ratio;
+    if entries * 8 > bat_length {
+        return Err(invalid("VHDX block allocation table is smaller than the disk"));
+    }
+
+    let mut disk = VirtualDisk::new(size);
+    let table = read(file, bat_offset, entries as usize * 8)?;
+    for block in 0..blocks {
+        let entry = u64_le(&table, (block + block / chunk_ratio) as usize * 8);
+        if let PAYLOAD_FULLY_PRESENT | PAYLOAD_PARTIALLY_PRESENT = entry & 7 {
+            // The offset is stored in megabytes, above the state.
+            let offset = entry & !0xF_FFFF;
+            disk.push(block * block_size, block_size, Data::Stored { offset });
+        }
+    }
+
+    Ok(disk)
+}
@@ -0,0 +1,95 @@
+//! VMware sparse extents, including the stream optimized variant used to distribute images.
+
+use super::{invalid, read, u16_le, u32_le, u64_le, Data, VirtualDisk};
+use std::{fs, io};
+
+pub const MAGIC: &[u8] = b"KDMV";
+
+const SECTOR: u64 = 512;
+const COMPRESSED: u32 = 1 << 16;
+const DEFLATE: u16 = 1;
+const GD_AT_END: u64 = u64::MAX;
+/// The size of the marker which precedes each compressed grain.
+const MARKER: u64 = 12;
+
+pub fn open(file: &fs::File, file_size: u64) -> io::Result<VirtualDisk> {
+    let mut header = read(file, 0, 512)?;
+
+    // Stream optimized images are written in a single pass, so only the copy of the header
+    // in the footer, which precedes the end of stream marker, knows where the grain directory is.
+    if u64_le(&header, 56) == GD_AT_END {
+        if file_size < 3 * SECTOR {
+            return Err(invalid("VMDK footer is missing"));
+        }
+
+        header = read(file, file_size - 2 * SECTOR, 512)?;
+        if !header.starts_with(MAGIC) || u64_le(&header, 56) == GD_AT_END {
+            return Err(invalid("VMDK footer is missing"));
+        }
+    }
+
+    if u32_le(&header, 4) > 3 {
+        return Err(invalid("VMDK version is not supported"));
+    }
+
+    let compressed = u32_le(&header, 8) & COMPRESSED != 0;
+    if compressed && u16_le(&header, 77) != DEFLATE {
+        return Err(invalid("VMDK compression algorithm is not supported"));
+    }
+
+    let sectors = |at| {
+        u64_le(&header, at).checked_mul(SECTOR).ok_or_else(|| invalid("VMDK header is invalid"))
+    };
+
+    let size = sectors(12)?;
+    let grain_size = sectors(20)?;
+    let entries = u64::from(u32_le(&header, 44));
+    let span = grain_size.checked_mul(entries).filter(|&span| span != 0);
+    let span = span.ok_or_else(|| invalid("VMDK grain size is invalid"))?;
+
+    // Every grain that the tables may refer to must lie at an offset that can be counted,
+    // and the tables themselves within the image, before any of them are allocated.
+    let tables = size / span + u64::from(size % span != 0);
+    if tables.checked_mul(span).is_none() {
+        return Err(invalid("VMDK grain size is invalid"));
+    }
+
+    let directory = sectors(56)?;
+    if entries * 4 > file_size || directory.saturating_add(tables * 4) > file_size {
+        return Err(invalid("VMDK grain tables extend beyond the end of the image"));
+    }
+
+    // Incompressible grains expand by at most zlib's worst case overhead.
+    let compressed_limit = grain_size.saturating_add((grain_size >> 12) + (grain_size >> 14) + 13);
+
+    let mut disk = VirtualDisk::new(size);
+    let directory = read(file, directory, tables as usize * 4)?;
+    for (gd_index, gd_entry) in directory.chunks_exact(4).enumerate() {
+        let table = u64::from(u32_le(gd_entry, 0));
+        if table == 0 {
+            continue;
+        }
+
+        let table = read(file, table * SECTOR, entries as usize * 4)?;
+        for (gt_index, gt_entry) in table.chunks_exact(4).enumerate() {
+            // Unallocated grains are recorded as zero, and grains of zeroes as one.
+            let grain = u64::from(u32_le(gt_entry, 0));
+            if grain <= 1 {
+                continue;
+            }
+
+            let offset = (gd_index as u64 * entries + gt_index as u64) * grain_size;
+            let data = if compressed {
+                let offset = grain * SECTOR;
+                let remaining = file_size.saturating_sub(offset + MARKER);
+                Data::Grain { offset, limit: compressed_limit.min(remaining) }
+            } else {
+                Data::Stored { offset: grain * SECTOR }
+            };
+
+            disk.push(offset, grain_size, data);
+        }
+    }
+
+    Ok(disk)
+}
@@ -0,0 +1,124 @@
+//! Reading devices back for validation, from the media rather than the page cache.
+
+use crate::blockdev;
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::{
+        fs::{FileExt, OpenOptionsExt},
+        io::{AsRawFd, FromRawFd, RawFd},
+    },
+};
+
+/// Direct reads are made into memory aligned to at least a page.
+const PAGE: u64 = 4096;
+
+pub(crate) struct Verifier {
+    file: File,
+    /// Reads are aligned to this, which is the logical sector size when reading directly.
+    sector: u64,
+    buf: Vec<u8>,
+}
+
+impl Verifier {
+    /// Waits for the writes to reach the device, then reads it through `reader`, which was
+    /// opened with `O_DIRECT`, or else opens it again with `O_DIRECT`.
+    ///
+    /// Block devices always support direct reads. Files on filesystems which do not, such
+    /// as tmpfs, and devices which may not be opened again, such as those which udisks
+    /// opened on behalf of an unprivileged user, have their cached pages dropped and are
+    /// read through the cache instead.
+    pub fn open(fd: RawFd, reader: Option<RawFd>) -> io::Result<Self> {
+        if unsafe { libc::fdatasync(fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let direct = match reader {
+            Some(reader) => duplicate(reader),
+            None => OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_DIRECT | libc::O_CLOEXEC)
+                .open(format!("/proc/self/fd/{}", fd)),
+        };
+
+        let (file, sector) = match direct {
+            Ok(file) => {
+                let sector = blockdev::sector_size(file.as_raw_fd()).unwrap_or(PAGE);
+                (file, sector)
+            }
+            Err(why)
+                if matches!(
+                    why.raw_os_error(),
+                    Some(libc::EINVAL | libc::EACCES | libc::EPERM)
+                ) =>
+            {
+                // Only block devices have a buffer cache to flush, and only administrators
+                // may flush it.
+                if let Err(why) = blockdev::flush_buffers(fd) {
+                    if !matches!(
+                        why.raw_os_error(),
+                        Some(libc::ENOTTY | libc::EACCES | libc::EPERM)
+                    ) {
+                        return Err(why);
+                    }
+                }
+
+                match unsafe { libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_DONTNEED) } {
+                    0 => (),
+                    errno => return Err(io::Error::from_raw_os_error(errno)),
+                }
+
+                (duplicate(fd)?, 1)
+            }
+            Err(why) => return Err(why),
+        };
+
+        Ok(Verifier { file, sector, buf: Vec::new() })
+    }
+
+    /// Reads back the region of the device that `data` was written to at `offset`, and
+    /// returns the offset of the first byte which differs.
+    pub fn compare(&mut self, offset: u64, data: &[u8]) -> io::Result<Option<u64>> {
+        let read = self.read(offset, data.len())?;
+        let differs = read.iter().zip(data).position(|(a, b)| a != b);
+        Ok(differs.map(|index| offset + index as u64))
+    }
+
+    /// Reads `length` bytes from the device at `offset`.
+    pub fn read(&mut self, offset: u64, length: usize) -> io::Result<&[u8]> {
+        let start = offset / self.sector * self.sector;
+        let end = (offset + length as u64 + self.sector - 1) / self.sector * self.sector;
+        let aligned = (end - start) as usize;
+
+        let align = self.sector.max(PAGE) as usize;
+        self.buf.resize(aligned + align, 0);
+        let skew = self.buf.as_ptr().align_offset(align);
+        let buf = &mut self.buf[skew..skew + aligned];
+
+        let mut read = 0;
+        while read < aligned {
+            match self.file.read_at(&mut buf[read..], start + read as u64)? {
+                0 => break,
+                count => read += count,
+            }
+        }
+
+        let head = (offset - start) as usize;
+        if read < head + length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "device ended before the image",
+            ));
+        }
+
+        Ok(&buf[head..head + length])
+    }
+}
+
+/// Duplicates `fd`, so that it may be read from without taking ownership of it.
+fn duplicate(fd: RawFd) -> io::Result<File> {
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        dup => Ok(unsafe { File::from_raw_fd(dup) }),
+    }
+}
@@ -0,0 +1,279 @@
+//! Sanitising devices, by discarding or overwriting every block of them.
+
+use crate::{
+    blockdev,
+    rate::Meter,
+    task::{sync, verify_error, write_at, CancelHandle, FlashEvent, Phase, Progress},
+    verify::Verifier,
+    DiskError,
+};
+use async_std::{fs::File, prelude::*, task::spawn_blocking};
+use futures::future::join_all;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Read, SeekFrom},
+    os::unix::io::AsRawFd,
+    time::Instant,
+};
+
+/// How much of a device is wiped or verified at a time.
+const CHUNK: u64 = 4 * 1024 * 1024;
+
+/// How the blocks of each device are wiped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WipeMethod {
+    /// Discards the blocks securely, or discards them if the device cannot do so securely,
+    /// and writes zeroes if it cannot discard them at all. Not every device reads discarded
+    /// blocks back as zeroes, which verification will catch.
+    Discard,
+    /// Writes zeroes over every block.
+    Zeroes,
+    /// Writes random data over every block, which differs for each device.
+    Random,
+}
+
+/// What a wiped device reads back as.
+#[derive(Clone, Copy)]
+enum Fill {
+    Zeroes,
+    /// The SHA-256 digests of a counter, following a seed which is random for each device,
+    /// which can be generated again for verification.
+    Random([u8; 32]),
+}
+
+impl Fill {
+    fn random() -> io::Result<Self> {
+        let mut seed = [0; 32];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut seed)?;
+        Ok(Fill::Random(seed))
+    }
+
+    /// Fills `buf` with the data which the wipe leaves at `offset`.
+    fn data(&self, offset: u64, buf: &mut [u8]) {
+        match self {
+            Fill::Zeroes => buf.fill(0),
+            Fill::Random(seed) => {
+                let counter = offset / 32;
+                for (index, block) in buf.chunks_mut(32).enumerate() {
+                    let digest = Sha256::new()
+                        .chain_update(seed)
+                        .chain_update((counter + index as u64).to_le_bytes())
+                        .finalize();
+                    block.copy_from_slice(&digest[..block.len()]);
+                }
+            }
+        }
+    }
+}
+
+/// A device which is wiped, along with its progress.
+struct Target<P: Progress> {
+    file: File,
+    device: P::Device,
+    pb: P,
+    /// Reads the device back with `O_DIRECT`, when the device may not be opened again.
+    reader: Option<std::fs::File>,
+}
+
+/// How the wipe of a device ended.
+enum Outcome {
+    Wiped,
+    Failed,
+    Cancelled,
+}
+
+#[derive(new)]
+pub struct Wipe<P: Progress> {
+    method: WipeMethod,
+
+    #[new(default)]
+    targets: Vec<Target<P>>,
+
+    #[new(value = "125")]
+    pub millis_between: u64,
+
+    /// When cancelled, each device is sent a `Cancelled` event, and `process` returns
+    /// `DiskError::Killed`.
+    #[new(default)]
+    pub cancel: CancelHandle,
+
+    verify: bool,
+}
+
+impl<P: Progress> Wipe<P> {
+    /// Wipes every device at once, each at its own pace. Devices pass through the
+    /// `Erasing` phase, and then `Verifying` if requested.
+    pub async fn process(mut self) -> anyhow::Result<()> {
+        if self.targets.is_empty() {
+            return Err(anyhow!("no writers left"));
+        }
+
+        let wipes = std::mem::take(&mut self.targets).into_iter().map(|target| self.wipe(target));
+        let outcomes = join_all(wipes).await;
+
+        if outcomes.iter().any(|outcome| matches!(outcome, Outcome::Cancelled)) {
+            return Err(DiskError::Killed.into());
+        }
+
+        match outcomes.iter().any(|outcome| matches!(outcome, Outcome::Wiped)) {
+            true => Ok(()),
+            false => Err(anyhow!("no writers left")),
+        }
+    }
+
+    pub fn subscribe(&mut self, file: File, device: P::Device, progress: P) -> &mut Self {
+        self.targets.push(Target { file, device, pb: progress, reader: None });
+        self
+    }
+
+    /// Reads the device which was subscribed last back through `reader`, which was opened
+    /// with `O_DIRECT`, rather than opening the device again.
+    pub fn direct_reader(&mut self, reader: std::fs::File) -> &mut Self {
+        if let Some(target) = self.targets.last_mut() {
+            target.reader = Some(reader);
+        }
+        self
+    }
+
+    async fn wipe(&self, mut target: Target<P>) -> Outcome {
+        let size = match target.file.seek(SeekFrom::End(0)).await {
+            Ok(size) => size,
+            Err(why) => return failed(target, DiskError::Seek { offset: 0, why }),
+        };
+
+        let fill = match self.method {
+            WipeMethod::Random => match Fill::random() {
+                Ok(fill) => fill,
+                Err(why) => return failed(target, DiskError::Erase { why }),
+            },
+            _ => Fill::Zeroes,
+        };
+
+        target.pb.event(&target.device, FlashEvent::Phase(Phase::Erasing));
+
+        // Whether the device is still to be discarded, and whether securely.
+        let mut discard = match self.method {
+            WipeMethod::Discard => Some(true),
+            _ => None,
+        };
+
+        let mut buf = vec![0; CHUNK as usize];
+        let mut cursor = None;
+        let mut offset = 0;
+        let mut meter = Meter::new(Some(size));
+        let mut last_update = Instant::now();
+        while offset < size {
+            if self.cancel.is_cancelled() {
+                return cancelled(target);
+            }
+
+            let length = (size - offset).min(CHUNK);
+            if let Some(secure) = discard {
+                let fd = target.file.as_raw_fd();
+                match spawn_blocking(move || blockdev::discard(fd, secure, offset, length)).await {
+                    Ok(()) => (),
+                    // Devices fall back to discarding insecurely, and then to writing zeroes.
+                    Err(why) if is_unsupported(&why) => {
+                        discard = if secure { Some(false) } else { None };
+                        continue;
+                    }
+                    Err(why) => return failed(target, DiskError::Erase { why }),
+                }
+            } else {
+                // Random data is generated in a blocking task, as it takes long enough to hold
+                // up the other devices. The buffer holds nothing but zeroes otherwise.
+                let chunk = length as usize;
+                if let Fill::Random(_) = fill {
+                    buf = spawn_blocking(move || {
+                        fill.data(offset, &mut buf[..chunk]);
+                        buf
+                    })
+                    .await;
+                }
+
+                if let Err(why) = write_at(&mut target.file, offset, &buf[..chunk], cursor).await {
+                    return failed(target, why);
+                }
+
+                cursor = Some(offset + length);
+            }
+
+            offset += length;
+            self.report(&mut target, &mut meter, &mut last_update, offset);
+        }
+
+        if let Err(why) = sync(&mut target.file).await {
+            return failed(target, DiskError::Flush { why });
+        }
+
+        if self.verify {
+            target.pb.event(&target.device, FlashEvent::Phase(Phase::Verifying));
+
+            let fd = target.file.as_raw_fd();
+            let reader = target.reader.as_ref().map(AsRawFd::as_raw_fd);
+            let mut verifier = match spawn_blocking(move || Verifier::open(fd, reader)).await {
+                Ok(verifier) => verifier,
+                Err(why) => return failed(target, DiskError::Verify { why }),
+            };
+
+            let mut offset = 0;
+            let mut meter = Meter::new(Some(size));
+            while offset < size {
+                if self.cancel.is_cancelled() {
+                    return cancelled(target);
+                }
+
+                let length = (size - offset).min(CHUNK) as usize;
+                let result;
+                (verifier, buf, result) = spawn_blocking(move || {
+                    fill.data(offset, &mut buf[..length]);
+                    let result = verifier.compare(offset, &buf[..length]);
+                    (verifier, buf, result)
+                })
+                .await;
+
+                match result {
+                    Ok(Some(offset)) => {
+                        return failed(target, DiskError::VerifyMismatch { offset });
+                    }
+                    Ok(None) => (),
+                    Err(why) => return failed(target, verify_error(why)),
+                }
+
+                offset += length as u64;
+                self.report(&mut target, &mut meter, &mut last_update, offset);
+            }
+        }
+
+        target.pb.event(&target.device, FlashEvent::Done);
+        Outcome::Wiped
+    }
+
+    /// Informs the device of how far it has got through the current phase, at most once
+    /// every `millis_between`, and always at the end of the phase.
+    fn report(&self, target: &mut Target<P>, meter: &mut Meter, last: &mut Instant, at: u64) {
+        let now = Instant::now();
+        if Some(at) == meter.total()
+            || now.duration_since(*last).as_millis() > self.millis_between as u128
+        {
+            *last = now;
+            let rate = meter.update(at);
+            target.pb.event(&target.device, FlashEvent::Progress { position: at, rate });
+        }
+    }
+}
+
+/// Whether the device, or the file, does not support the request at all.
+fn is_unsupported(why: &io::Error) -> bool {
+    matches!(why.raw_os_error(), Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY))
+}
+
+fn failed<P: Progress>(mut target: Target<P>, why: DiskError) -> Outcome {
+    target.pb.event(&target.device, FlashEvent::Failed(why));
+    Outcome::Failed
+}
+
+fn cancelled<P: Progress>(mut target: Target<P>) -> Outcome {
+    target.pb.event(&target.device, FlashEvent::Cancelled);
+    Outcome::Cancelled
+}
@@ -0,0 +1,171 @@
+mod common;
+
+use common::{events, temp, Log, Recorder};
+use futures::{executor, prelude::*};
+use popsicle::{BackedUp, Backup, BackupOutput, Bmap, Compression, Image};
+use sha2::{Digest, Sha256};
+use std::fs;
+
+const BLOCK: usize = 4096;
+
+/// A device of a mebibyte and a half, with data in blocks 0 and 3-4, and zeroes elsewhere.
+fn device() -> Vec<u8> {
+    let mut data = vec![0; 1024 * 1024 + 512];
+    for range in [0..BLOCK, 3 * BLOCK..5 * BLOCK] {
+        for i in range {
+            data[i] = (i % 251) as u8 + 1;
+        }
+    }
+
+    data
+}
+
+/// Backs up the device, returning the image and what was recorded of it.
+fn backup(name: &str, compression: Compression, trim: bool) -> (Vec<u8>, BackedUp) {
+    let path = |suffix: &str| temp(&format!("{}{}", name, suffix));
+
+    fs::write(path(".dev"), device()).unwrap();
+
+    let log = Log::default();
+    let source = fs::File::open(path(".dev")).unwrap();
+    let mut backup = Backup::new(source.into(), 0, Recorder { log: log.clone() });
+    backup.compression = compression;
+    backup.trim = trim;
+
+    let image = fs::File::create(path(".img")).unwrap();
+    let backed_up = executor::block_on(backup.process(image.into())).unwrap();
+    assert_eq!(events(&log, 0), ["Phase(Reading)", "Done"]);
+
+    let image = fs::read(path(".img")).unwrap();
+    fs::remove_file(path(".dev")).unwrap();
+    fs::remove_file(path(".img")).unwrap();
+    (image, backed_up)
+}
+
+#[test]
+fn backup_raw() {
+    let (image, backed_up) = backup("backup-raw", Compression::None, false);
+    assert!(image == device());
+    assert_eq!(backed_up.sha256[..], Sha256::digest(&image)[..]);
+
+    let bmap = Bmap::parse(&backed_up.bmap.to_xml()).unwrap();
+    assert_eq!(bmap.image_size, image.len() as u64);
+    assert_eq!(bmap.blocks_count, 257);
+    let ranges: Vec<_> = bmap.ranges.iter().map(|range| (range.start, range.end)).collect();
+    assert_eq!(ranges, [(0, 0), (3, 4)]);
+}
+
+#[test]
+fn backup_trimmed() {
+    let (image, backed_up) = backup("backup-trimmed", Compression::Zstd, true);
+    let path = temp("trimmed.zst");
+    fs::write(&path, image).unwrap();
+
+    let expected = &device()[..5 * BLOCK];
+    assert_eq!(backed_up.sha256[..], Sha256::digest(expected)[..]);
+
+    // The image reads back through its block map, whose checksums are verified.
+    let mut image = Image::new(fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(image.compression(), Compression::Zstd);
+    image.set_bmap(Bmap::parse(&backed_up.bmap.to_xml()).unwrap()).unwrap();
+
+    executor::block_on(async move {
+        let mut extents = Vec::new();
+        while let Some(extent) = image.next_extent().await.unwrap() {
+            let mut data = Vec::new();
+            (&mut image).take(extent.length).read_to_end(&mut data).await.unwrap();
+
+            let start = extent.offset as usize;
+            assert!(data == expected[start..start + data.len()]);
+            extents.push((extent.offset, extent.length));
+        }
+
+        assert_eq!(extents, [(0, 4096), (12288, 8192)]);
+    });
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn backup_output() {
+    let path = |suffix: &str| temp(&format!("output{}", suffix));
+
+    fs::write(path(".dev"), device()).unwrap();
+    let exists = |suffix: &str| path(suffix).exists();
+
+    // A cancelled backup leaves nothing behind once its output is dropped.
+    let source = fs::File::open(path(".dev")).unwrap();
+    let backup = Backup::new(source.into(), 0, Recorder { log: Log::default() });
+    backup.cancel.cancel();
+
+    let (output, image) = BackupOutput::create(&path(".img")).unwrap();
+    assert!(exists(".img.partial"));
+    assert!(BackupOutput::create(&path(".img")).is_err());
+    assert!(executor::block_on(backup.process(image.into())).is_err());
+    drop(output);
+    assert!(![".img", ".img.partial", ".img.bmap", ".img.sha256"].iter().any(|s| exists(s)));
+
+    // The image only takes its path once it is saved.
+    let source = fs::File::open(path(".dev")).unwrap();
+    let backup = Backup::new(source.into(), 0, Recorder { log: Log::default() });
+
+    let (output, image) = BackupOutput::create(&path(".img")).unwrap();
+    let backed_up = executor::block_on(backup.process(image.into())).unwrap();
+    assert!(!exists(".img"));
+    output.save(&backed_up).unwrap();
+
+    assert!(!exists(".img.partial"));
+    assert!(fs::read(path(".img")).unwrap() == device());
+    assert_eq!(fs::read(path(".img.bmap")).unwrap(), backed_up.bmap.to_xml().as_bytes());
+    assert!(exists(".img.sha256"));
+
+    for suffix in [".dev", ".img", ".img.bmap", ".img.sha256"] {
+        fs::remove_file(path(suffix)).unwrap();
+    }
+}
+
+#[test]
+fn backup_output_kept() {
+    let path = |suffix: &str| temp(&format!("kept{}", suffix));
+
+    // What is at the path of the image cannot be replaced, as it is a directory.
+    fs::write(path(".dev"), device()).unwrap();
+    fs::create_dir_all(path(".img/image")).unwrap();
+    fs::write(path(".img.bmap"), "bmap").unwrap();
+    fs::write(path(".img.sha256"), "sha256").unwrap();
+
+    let source = fs::File::open(path(".dev")).unwrap();
+    let backup = Backup::new(source.into(), 0, Recorder { log: Log::default() });
+    let (output, image) = BackupOutput::create(&path(".img")).unwrap();
+    let backed_up = executor::block_on(backup.process(image.into())).unwrap();
+    assert!(output.save(&backed_up).is_err());
+
+    // The files of what was there are kept, and the partial files are removed.
+    assert_eq!(fs::read_to_string(path(".img.bmap")).unwrap(), "bmap");
+    assert_eq!(fs::read_to_string(path(".img.sha256")).unwrap(), "sha256");
+    for suffix in [".img.partial", ".img.bmap.partial", ".img.sha256.partial"] {
+        assert!(!path(suffix).exists(), "{} was left behind", suffix);
+    }
+
+    fs::remove_dir_all(path(".img")).unwrap();
+    for suffix in [".dev", ".img.bmap", ".img.sha256"] {
+        fs::remove_file(path(suffix)).unwrap();
+    }
+}
+
+#[test]
+fn backup_digest_name() {
+    let (_, backed_up) = backup("backup-named", Compression::Zstd, false);
+    let path = |suffix: &str| temp(&format!("named{}", suffix));
+
+    // The digest is of the decompressed image, which it is saved next to, and names.
+    backed_up.save(&path(".img.zst")).unwrap();
+    let name = format!("popsicle-{}-named.img", std::process::id());
+    let digest = fs::read_to_string(path(".img.sha256")).unwrap();
+    assert!(digest.ends_with(&format!("  {}\n", name)), "{}", digest);
+    assert!(!path(".img.zst.sha256").exists());
+
+    for suffix in [".img.zst.bmap", ".img.sha256"] {
+        fs::remove_file(path(suffix)).unwrap();
+    }
+}
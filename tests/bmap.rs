@@ -0,0 +1,69 @@
+use futures::{executor, prelude::*};
+use popsicle::{Bmap, BmapError, Image};
+use std::{fs::File, path::Path};
+
+const SAMPLE: &str = include_str!("sample.img.bmap");
+
+#[test]
+fn parse() {
+    let bmap = Bmap::parse(SAMPLE).unwrap();
+
+    assert_eq!(bmap.image_size, 1024 * 1024);
+    assert_eq!(bmap.block_size, 4096);
+    assert_eq!(bmap.blocks_count, 256);
+
+    let ranges: Vec<_> = bmap.ranges.iter().map(|range| (range.start, range.end)).collect();
+    assert_eq!(ranges, [(0, 1), (3, 5), (7, 7), (200, 255)]);
+    assert!(bmap.ranges.iter().all(|range| range.checksum.is_some()));
+
+    assert_eq!(bmap.extent(&bmap.ranges[1]), (3 * 4096, 3 * 4096));
+    assert_eq!(bmap.mapped_size(), 62 * 4096);
+}
+
+#[test]
+fn corrupt() {
+    let tampered = SAMPLE.replace("> 7 <", "> 8 <");
+    assert!(matches!(Bmap::parse(&tampered), Err(BmapError::Corrupt)));
+}
+
+#[test]
+fn overflow() {
+    // The range is within the count of blocks, but its offset in bytes cannot be counted.
+    let xml = "<bmap version=\"1.4\"><ImageSize>4096</ImageSize><BlockSize>4096</BlockSize>\
+        <BlocksCount>18446744073709551615</BlocksCount>\
+        <BlockMap><Range>4503599627370496</Range></BlockMap></bmap>";
+    assert!(matches!(Bmap::parse(xml), Err(BmapError::OutOfBounds { .. })));
+}
+
+#[test]
+fn find() {
+    let expected = Some(Path::new("tests/sample.img.bmap").to_path_buf());
+    assert_eq!(Bmap::find(Path::new("tests/sample.img.xz")), expected);
+    assert_eq!(Bmap::find(Path::new("tests/sample.img")), expected);
+    assert_eq!(Bmap::find(Path::new("tests/ipc.ron")), None);
+}
+
+#[test]
+fn extents() {
+    let mut image = Image::new(File::open("tests/sample.img.xz").unwrap()).unwrap();
+    image.set_bmap(Bmap::parse(SAMPLE).unwrap()).unwrap();
+
+    executor::block_on(async move {
+        let mut extents = Vec::new();
+        while let Some(extent) = image.next_extent().await.unwrap() {
+            let mut data = Vec::new();
+            (&mut image).take(extent.length).read_to_end(&mut data).await.unwrap();
+
+            let start = extent.offset as usize;
+            assert!((start..start + data.len()).map(|i| (i % 251) as u8).eq(data));
+            extents.push((extent.offset, extent.length));
+        }
+
+        assert_eq!(extents, [(0, 8192), (12288, 12288), (28672, 4096), (819200, 229376)]);
+    });
+}
+
+#[test]
+fn serialise() {
+    assert_eq!(Bmap::parse(SAMPLE).unwrap().to_xml(), SAMPLE);
+}
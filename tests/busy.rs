@@ -0,0 +1,262 @@
+use popsicle::{usage, DiskError, MountBackend, Policy, PowerOff, Sysfs, SystemRoot, Unmount};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Creates a file beneath the root, along with its parent directories.
+fn create(root: &Path, path: &str, contents: &str) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+/// Creates a symbolic link beneath the root, along with its parent directories.
+fn link(root: &Path, path: &str, target: &str) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::os::unix::fs::symlink(target, path).unwrap();
+}
+
+#[test]
+fn blockers() {
+    let dir = std::env::temp_dir().join(format!("popsicle-{}-busy", std::process::id()));
+    let block = "sys/class/block";
+
+    create(&dir, &format!("{}/sda/sda1/partition", block), "1");
+    create(&dir, &format!("{}/sda/sda2/partition", block), "2");
+    create(&dir, &format!("{}/sda/sda2/holders/dm-0", block), "");
+    create(&dir, &format!("{}/dm-0/dm/uuid", block), "CRYPT-LUKS2-0123-luks\n");
+    create(&dir, &format!("{}/dm-0/dm/name", block), "luks-0123\n");
+    create(&dir, &format!("{}/sdaa/sdaa1/partition", block), "1");
+    create(&dir, &format!("{}/sdaa/sdaa1/holders/md127", block), "");
+    create(&dir, &format!("{}/sdb/holders/dm-1", block), "");
+    create(&dir, &format!("{}/dm-1/dm/uuid", block), "LVM-abcdef\n");
+    create(&dir, &format!("{}/dm-1/dm/name", block), "vg-root\n");
+    create(
+        &dir,
+        "proc/swaps",
+        "Filename\tType\t\tSize\t\tUsed\tPriority\n/dev/sdaa1\tpartition\t1024\t0\t-2\n",
+    );
+    create(
+        &dir,
+        "proc/self/mounts",
+        "/dev/sda1 /media/My\\040Stick vfat rw 0 0\n/dev/disk/by-label/DATA /media/data ext4 rw 0 0\n",
+    );
+
+    // Links are resolved beneath the root, rather than in the /dev of the system.
+    link(&dir, "dev/disk/by-label/DATA", "../../sda3");
+    create(&dir, &format!("{}/sda/sda3/partition", block), "3");
+
+    let root = SystemRoot::new(&dir);
+    let usage_of = |name: &str| {
+        let disk = Path::new("/dev").join(name);
+        usage(&root, &disk, &disk).unwrap()
+    };
+
+    // The partitions of /dev/sdaa are not those of /dev/sda.
+    let sda = usage_of("sda");
+    assert_eq!(
+        sda.mounts,
+        [
+            ("/dev/sda1".into(), "/media/My Stick".into()),
+            ("/dev/disk/by-label/DATA".into(), "/media/data".into())
+        ]
+    );
+    assert!(sda.swaps.is_empty());
+    assert!(matches!(
+        &sda.blockers[..],
+        [DiskError::Encrypted { member, mapping, .. }] if member == "sda2" && mapping == "luks-0123"
+    ));
+
+    let sdaa = usage_of("sdaa");
+    assert!(sdaa.mounts.is_empty());
+    assert_eq!(sdaa.swaps, [PathBuf::from("/dev/sdaa1")]);
+    assert!(matches!(
+        &sdaa.blockers[..],
+        [DiskError::RaidMember { array, .. }] if array == "md127"
+    ));
+
+    let sdb = usage_of("sdb").blockers;
+    assert!(matches!(&sdb[..], [DiskError::LogicalVolume { volume, .. }] if volume == "vg-root"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn policy() {
+    let dir = std::env::temp_dir().join(format!("popsicle-{}-policy", std::process::id()));
+    let block = "sys/class/block";
+
+    // The root filesystem is a logical volume, within an encrypted partition of /dev/sda.
+    create(&dir, &format!("{}/sda/removable", block), "0\n");
+    create(&dir, &format!("{}/sda/sda2/partition", block), "2");
+    create(&dir, &format!("{}/sda/sda2/holders/dm-0", block), "");
+    create(&dir, &format!("{}/dm-0/dm/name", block), "luks-0123\n");
+    create(&dir, &format!("{}/dm-0/holders/dm-1", block), "");
+    create(&dir, &format!("{}/dm-1/dm/name", block), "vg-root\n");
+    create(&dir, &format!("{}/sdb/removable", block), "0\n");
+    create(&dir, &format!("{}/sdc/removable", block), "1\n");
+    create(&dir, &format!("{}/sdc/size", block), "2048\n");
+    create(
+        &dir,
+        "proc/self/mounts",
+        "/dev/mapper/vg-root / ext4 rw,relatime 0 0\n/dev/disk/by-uuid/0123 /home ext4 rw 0 0\n",
+    );
+
+    // The home filesystem is on a removable disk, which it is mounted from by its UUID.
+    create(&dir, &format!("{}/sdd/removable", block), "1\n");
+    create(&dir, &format!("{}/sdd/sdd1/partition", block), "1");
+    link(&dir, "dev/disk/by-uuid/0123", "/dev/sdd1");
+
+    // The disk of a USB enclosure, which is not reported as removable, is refused too.
+    let usb = "sys/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0";
+    create(&dir, &format!("{}/block/sde/removable", usb), "0\n");
+    link(&dir, &format!("{}/sde", block), &format!("../../../{}/block/sde", usb));
+
+    let root = SystemRoot::new(&dir);
+    let check = |policy: &Policy, name: &str| {
+        let disk = Path::new("/dev").join(name);
+        policy.check(&root, &disk, &disk)
+    };
+
+    let policy = Policy::default();
+    assert!(
+        matches!(check(&policy, "sda"), Err(DiskError::SystemDisk { mount, .. }) if mount.to_str() == Some("/"))
+    );
+    assert!(matches!(check(&policy, "sdb"), Err(DiskError::NotRemovable { .. })));
+    assert!(
+        matches!(check(&policy, "sdd"), Err(DiskError::SystemDisk { mount, .. }) if mount.to_str() == Some("/home"))
+    );
+    check(&policy, "sdc").unwrap();
+    assert!(matches!(check(&policy, "sde"), Err(DiskError::NotRemovable { .. })));
+
+    let policy = Policy { max_size: Some(512 * 1024), ..Policy::default() };
+    assert!(matches!(check(&policy, "sdc"), Err(DiskError::TooLarge { size: 1048576, .. })));
+
+    check(&Policy::unrestricted(), "sda").unwrap();
+    check(&Policy::unrestricted(), "sde").unwrap();
+
+    // The source of a clone is refused, however unrestricted the policy is.
+    let policy = Policy { protected: vec![PathBuf::from("/dev/sdc")], ..Policy::unrestricted() };
+    assert!(matches!(check(&policy, "sdc"), Err(DiskError::Protected { .. })));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Records what is released, and mounts the disk again a number of times, as udisks might.
+struct Remounting {
+    root: PathBuf,
+    remounts: usize,
+    calls: Vec<String>,
+}
+
+impl MountBackend for Remounting {
+    fn unmount(&mut self, target: &Path, how: Unmount) -> io::Result<()> {
+        self.calls.push(format!("unmount {} {:?}", target.display(), how));
+        if how == Unmount::Normal && target.ends_with("busy") {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+
+        Ok(())
+    }
+
+    fn swap_off(&mut self, device: &Path) -> io::Result<()> {
+        self.calls.push(format!("swapoff {}", device.display()));
+        Ok(())
+    }
+
+    fn settle(&mut self) {
+        let mounts = match self.remounts {
+            0 => "",
+            _ => "/dev/sdc1 /media/stick vfat rw 0 0\n",
+        };
+
+        self.remounts = self.remounts.saturating_sub(1);
+        fs::write(self.root.join("proc/self/mounts"), mounts).unwrap();
+        fs::write(self.root.join("proc/swaps"), "Filename\n").unwrap();
+    }
+}
+
+#[test]
+fn release() {
+    let dir = std::env::temp_dir().join(format!("popsicle-{}-release", std::process::id()));
+    create(&dir, "sys/class/block/sdc/sdc1/partition", "1");
+    create(&dir, "sys/class/block/sdc/sdc2/partition", "2");
+    create(&dir, "sys/class/block/sdc/sdc3/partition", "3");
+    create(&dir, "proc/swaps", "Filename\n/dev/sdc3 partition 1024 0 -2\n");
+    create(
+        &dir,
+        "proc/self/mounts",
+        "/dev/sdc1 /media/stick vfat rw 0 0\n/dev/sdc2 /media/stick/busy ext4 rw 0 0\n",
+    );
+
+    let root = SystemRoot::new(&dir);
+    let disk = Path::new("/dev/sdc");
+    let mut backend = Remounting { root: dir.clone(), remounts: 1, calls: Vec::new() };
+    popsicle::release(&root, &mut backend, disk, disk).unwrap();
+    assert_eq!(
+        backend.calls,
+        [
+            "swapoff /dev/sdc3",
+            "unmount /media/stick/busy Normal",
+            "unmount /media/stick/busy Force",
+            "unmount /media/stick Normal",
+            "unmount /media/stick Normal",
+        ]
+    );
+
+    // The disk is given up on if it keeps being mounted again.
+    create(&dir, "proc/self/mounts", "/dev/sdc1 /media/stick vfat rw 0 0\n");
+    let mut backend = Remounting { root: dir.clone(), remounts: usize::MAX, calls: Vec::new() };
+    let why = popsicle::release(&root, &mut backend, disk, disk).unwrap_err();
+    assert!(matches!(why, DiskError::Remounted { path, .. } if path.ends_with("sdc1")));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn power_off() {
+    let dir = std::env::temp_dir().join(format!("popsicle-{}-power-off", std::process::id()));
+    let usb = "sys/devices/pci0000:00/usb1/1-2";
+    let scsi = "sys/devices/pci0000:00/ata1/host0/target0:0:0/0:0:0:0";
+    create(&dir, &format!("{}/idVendor", usb), "0781\n");
+    create(&dir, &format!("{}/remove", usb), "");
+    create(&dir, &format!("{}/1-2:1.0/host6/target6:0:0/6:0:0:0/delete", usb), "");
+    create(&dir, &format!("{}/delete", scsi), "");
+
+    let block = dir.join("sys/class/block");
+    for (name, device) in
+        [("sdc", format!("{}/1-2:1.0/host6/target6:0:0/6:0:0:0", usb)), ("sda", scsi.into())]
+    {
+        fs::create_dir_all(block.join(name)).unwrap();
+        std::os::unix::fs::symlink(dir.join(device), block.join(name).join("device")).unwrap();
+    }
+
+    let sysfs = Sysfs { root: SystemRoot::new(&dir) };
+
+    // USB disks are removed from their port, rather than only being deleted.
+    sysfs.power_off(Path::new("/dev/sdc")).unwrap();
+    assert_eq!(fs::read_to_string(dir.join(usb).join("remove")).unwrap(), "1");
+    sysfs.power_off(Path::new("/dev/sda")).unwrap();
+    assert_eq!(fs::read_to_string(dir.join(scsi).join("delete")).unwrap(), "1");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn settle_partitions() {
+    let dir = std::env::temp_dir().join(format!("popsicle-{}-settle", std::process::id()));
+    create(&dir, "dev/sdf1", "");
+    create(&dir, "run/udev/queue", "");
+
+    // udev is waited for while it has events queued, until the wait times out.
+    let root = SystemRoot::new(&dir);
+    let partitions = [dir.join("dev/sdf1")];
+    assert!(!popsicle::settle_partitions(&root, &partitions, Duration::from_millis(200)));
+
+    fs::remove_file(dir.join("run/udev/queue")).unwrap();
+    assert!(popsicle::settle_partitions(&root, &partitions, Duration::ZERO));
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,47 @@
+//! What the integration tests share.
+
+#![allow(dead_code)]
+
+use popsicle::{FlashEvent, Progress};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// The events which each device received, other than those of progress.
+pub type Log = Arc<Mutex<Vec<(usize, String)>>>;
+
+pub struct Recorder {
+    pub log: Log,
+}
+
+impl Progress for Recorder {
+    type Device = usize;
+
+    fn event(&mut self, device: &usize, event: FlashEvent) {
+        if let FlashEvent::Progress { .. } = event {
+            return;
+        }
+
+        self.log.lock().unwrap().push((*device, format!("{:?}", event)));
+    }
+}
+
+/// The events of a device, in the order they were received.
+pub fn events(log: &Log, device: usize) -> Vec<String> {
+    let log = log.lock().unwrap();
+    log.iter().filter(|(id, _)| *id == device).map(|(_, event)| event.clone()).collect()
+}
+
+/// A path in the temporary directory which no other test, or run of the tests, uses.
+pub fn temp(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("popsicle-{}-{}", std::process::id(), name))
+}
+
+/// A file of `size` bytes to write to, which does not hold zeroes.
+pub fn target(name: &str, size: usize) -> PathBuf {
+    let path = temp(name);
+    fs::write(&path, vec![0xFF; size]).unwrap();
+    path
+}
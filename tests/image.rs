@@ -0,0 +1,228 @@
+use futures::{executor, prelude::*};
+use popsicle::{Compression, Format, Image, ImageError};
+use std::fs::File;
+
+const SAMPLE_SIZE: usize = 1024 * 1024;
+
+fn sample() -> Vec<u8> {
+    (0..SAMPLE_SIZE).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn decompress() {
+    let samples = [
+        ("tests/sample.img.gz", Compression::Gzip, Some(SAMPLE_SIZE as u64)),
+        ("tests/sample.img.xz", Compression::Xz, Some(SAMPLE_SIZE as u64)),
+        ("tests/sample.img.zst", Compression::Zstd, Some(SAMPLE_SIZE as u64)),
+        // bzip2 does not record the decompressed size.
+        ("tests/sample.img.bz2", Compression::Bzip2, None),
+    ];
+
+    executor::block_on(async move {
+        for (path, compression, size) in samples {
+            let mut image = Image::new(File::open(path).unwrap()).unwrap();
+            assert_eq!(image.compression(), compression, "{}", path);
+            assert_eq!(image.size(), size, "{}", path);
+
+            // The second pass is what `Task` performs when validating.
+            for _ in 0..2 {
+                let mut data = Vec::new();
+                image.read_to_end(&mut data).await.unwrap();
+                assert!(data == sample(), "{} decompressed incorrectly", path);
+                image.rewind().await.unwrap();
+            }
+        }
+    });
+}
+
+#[test]
+fn sparse() {
+    const BLOCK: usize = 4096;
+
+    let raw: Vec<u8> = (0..2 * BLOCK).map(|i| (i % 251) as u8).collect();
+    let fill: Vec<u8> = [0xDE, 0xAD, 0xBE, 0xEF].iter().copied().cycle().take(2 * BLOCK).collect();
+    let raw2: Vec<u8> = (0..BLOCK).map(|i| (i * 7 % 256) as u8).collect();
+
+    // Don't care chunks at blocks 2-4 and 8-15 are skipped rather than written.
+    let expected = [(0, raw), (5 * BLOCK as u64, fill), (7 * BLOCK as u64, raw2)];
+
+    // Compressed sparse images are expanded as they are decompressed.
+    let samples =
+        [("tests/sample.simg", Compression::None), ("tests/sample.simg.gz", Compression::Gzip)];
+
+    executor::block_on(async move {
+        for (path, compression) in samples {
+            let mut image = Image::new(File::open(path).unwrap()).unwrap();
+            assert_eq!(image.compression(), compression, "{}", path);
+            assert_eq!(image.format(), Format::AndroidSparse, "{}", path);
+            assert_eq!(image.size(), Some(16 * BLOCK as u64), "{}", path);
+
+            for _ in 0..2 {
+                let mut extents = Vec::new();
+                while let Some(extent) = image.next_extent().await.unwrap() {
+                    let mut data = Vec::new();
+                    (&mut image).take(extent.length).read_to_end(&mut data).await.unwrap();
+                    assert_eq!(extent.length, data.len() as u64);
+                    extents.push((extent.offset, data));
+                }
+
+                assert!(extents == expected, "{} expanded incorrectly", path);
+                image.rewind().await.unwrap();
+            }
+        }
+    });
+}
+
+#[test]
+fn virtual_disks() {
+    const CLUSTER: u64 = 4096;
+    const BLOCK: u64 = 1024 * 1024;
+
+    // The size of each disk, and the clusters which hold data, in units of its clusters.
+    let samples = [
+        ("tests/sample.qcow2", Format::Qcow2, CLUSTER, 16, &[(0, 2), (5, 1), (9, 1), (15, 1)][..]),
+        ("tests/sample.vhd", Format::Vhd, CLUSTER, 16, &[(0, 1), (1, 1), (5, 1)]),
+        ("tests/sample-fixed.vhd", Format::Vhd, CLUSTER, 2, &[(0, 2)]),
+        ("tests/sample.vhdx", Format::Vhdx, BLOCK, 3, &[(2, 1)]),
+        ("tests/sample.vmdk", Format::Vmdk, CLUSTER, 16, &[(0, 1), (1, 1), (6, 1)]),
+    ];
+
+    executor::block_on(async move {
+        for (path, format, unit, size, clusters) in samples {
+            let mut image = Image::new(File::open(path).unwrap()).unwrap();
+            assert_eq!(image.format(), format, "{}", path);
+            assert_eq!(image.size(), Some(size * unit), "{}", path);
+
+            let mut expected = vec![0; (size * unit) as usize];
+            for &(start, length) in clusters {
+                for i in start * unit..(start + length) * unit {
+                    expected[i as usize] = (i % 251) as u8;
+                }
+            }
+
+            // Unallocated and zeroed clusters are skipped, and only hold zeroes.
+            let mut disk = vec![0; expected.len()];
+            let mut read = 0;
+            while let Some(extent) = image.next_extent().await.unwrap() {
+                let range = extent.offset as usize..(extent.offset + extent.length) as usize;
+                image.read_exact(&mut disk[range]).await.unwrap();
+                read += extent.length;
+            }
+
+            assert!(disk == expected, "{} expanded incorrectly", path);
+            let allocated: u64 = clusters.iter().map(|&(_, length)| length * unit).sum();
+            assert!(read < size * unit || allocated == size * unit, "{} was not skipped", path);
+            image.rewind().await.unwrap();
+
+            // When they are filled, they are read as zeroes, so the whole disk is written.
+            image.set_fill_unallocated(true);
+            for _ in 0..2 {
+                let mut disk = Vec::new();
+                while let Some(extent) = image.next_extent().await.unwrap() {
+                    assert_eq!(extent.offset, disk.len() as u64, "{}", path);
+                    (&mut image).take(extent.length).read_to_end(&mut disk).await.unwrap();
+                }
+
+                assert!(disk == expected, "{} expanded incorrectly", path);
+                image.rewind().await.unwrap();
+            }
+        }
+    });
+}
+
+#[test]
+fn vmdk_grain_size() {
+    // The first grain marker of the sample states a compressed size of 4 GiB.
+    let mut data = std::fs::read("tests/sample.vmdk").unwrap();
+    data[1024 + 8..1024 + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+
+    let path = std::env::temp_dir().join(format!("popsicle-{}-grain.vmdk", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+
+    executor::block_on(async {
+        let mut image = Image::new(File::open(&path).unwrap()).unwrap();
+        let error = image.next_extent().await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    });
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn vdisk_overflow() {
+    // The qcow2 disk is as large as can be counted, and each VMDK grain table is 16 GiB.
+    let mut qcow2 = std::fs::read("tests/sample.qcow2").unwrap();
+    qcow2[24..32].copy_from_slice(&u64::MAX.to_be_bytes());
+    let mut vmdk = std::fs::read("tests/sample.vmdk").unwrap();
+    let footer = vmdk.len() - 1024;
+    vmdk[footer + 44..footer + 48].copy_from_slice(&u32::MAX.to_le_bytes());
+
+    for (name, data) in [("overflow.qcow2", qcow2), ("overflow.vmdk", vmdk)] {
+        let path = std::env::temp_dir().join(format!("popsicle-{}-{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+
+        let error = Image::new(File::open(&path).unwrap()).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData, "{}", name);
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn gzip_members() {
+    use flate2::{write::GzEncoder, Compression as Level};
+    use std::io::Write;
+
+    // Each member records only its own size, so that of the image is not known.
+    let mut data = Vec::new();
+    for half in sample().chunks(SAMPLE_SIZE / 2) {
+        let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(half).unwrap();
+        data.extend(encoder.finish().unwrap());
+    }
+
+    let path = std::env::temp_dir().join(format!("popsicle-{}-members.gz", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+
+    executor::block_on(async {
+        let mut image = Image::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(image.size(), None);
+
+        let mut data = Vec::new();
+        image.read_to_end(&mut data).await.unwrap();
+        assert!(data == sample(), "gzip members decompressed incorrectly");
+    });
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn compressed_virtual_disk() {
+    // The clusters of a virtual disk cannot be sought to through the decompressor.
+    let error = Image::new(File::open("tests/sample.qcow2.xz").unwrap()).err().unwrap();
+    let why = error.get_ref().and_then(|why| why.downcast_ref::<ImageError>());
+    assert!(matches!(
+        why,
+        Some(ImageError::CompressedDisk { format: Format::Qcow2, compression: Compression::Xz })
+    ));
+}
+
+#[test]
+fn trailing_data() {
+    // Padding after the last frame keeps the size from being found, but not the image from
+    // being read.
+    let mut data = std::fs::read("tests/sample.img.zst").unwrap();
+    data.extend([0; 4096]);
+    let path = std::env::temp_dir().join(format!("popsicle-{}-trailing.zst", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+
+    executor::block_on(async {
+        let mut image = Image::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(image.size(), None);
+
+        let mut data = Vec::new();
+        (&mut image).take(SAMPLE_SIZE as u64).read_to_end(&mut data).await.unwrap();
+        assert!(data == sample(), "padded zstd image decompressed incorrectly");
+    });
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -1,7 +1,7 @@
 use futures::{executor, io::AllowStdIo, prelude::*};
 use futures_codec::FramedRead;
-use popsicle::codec::*;
-use std::io::Cursor;
+use popsicle::{codec::*, Phase, Rate};
+use std::{io::Cursor, time::Duration};
 
 const SAMPLE: &[u8] = include_bytes!("ipc.ron");
 
@@ -12,21 +12,27 @@ fn ipc() {
             Message::Size(2229190656),
             Message::Device("/dev/sdb".into()),
             Message::Device("/dev/sda".into()),
-            Message::Set("/dev/sda".into(), 589824),
-            Message::Set("/dev/sdb".into(), 589824),
-            Message::Set("/dev/sdb".into(), 384434176),
-            Message::Set("/dev/sda".into(), 1669005312),
-            Message::Set("/dev/sdb".into(), 2228748288),
-            Message::Set("/dev/sda".into(), 0),
-            Message::Message("/dev/sda".into(), "S".into()),
-            Message::Set("/dev/sdb".into(), 0),
-            Message::Message("/dev/sdb".into(), "S".into()),
-            Message::Set("/dev/sda".into(), 0),
-            Message::Message("/dev/sda".into(), "V".into()),
-            Message::Set("/dev/sdb".into(), 0),
-            Message::Message("/dev/sdb".into(), "V".into()),
+            Message::Phase("/dev/sda".into(), Phase::Writing),
+            Message::Phase("/dev/sdb".into(), Phase::Writing),
+            Message::Set("/dev/sda".into(), 589824, rate(4718592, Some(472), 125)),
+            Message::Set("/dev/sdb".into(), 589824, rate(4718592, Some(472), 125)),
+            Message::Set("/dev/sdb".into(), 384434176, rate(25165824, Some(73), 15250)),
+            Message::Set("/dev/sda".into(), 1669005312, rate(41943040, Some(13), 39875)),
+            Message::Set("/dev/sdb".into(), 2229190656, rate(27262976, Some(0), 81750)),
+            Message::Set("/dev/sda".into(), 2229190656, rate(41943040, Some(0), 53125)),
+            Message::Phase("/dev/sda".into(), Phase::Syncing),
+            Message::Phase("/dev/sdb".into(), Phase::Syncing),
+            Message::Phase("/dev/sda".into(), Phase::Seeking),
+            Message::Phase("/dev/sda".into(), Phase::Verifying),
+            Message::Phase("/dev/sdb".into(), Phase::Seeking),
+            Message::Phase("/dev/sdb".into(), Phase::Verifying),
+            Message::Set("/dev/sda".into(), 0, rate(0, None, 0)),
+            Message::Set("/dev/sda".into(), 2229190656, rate(104857600, Some(0), 21250)),
             Message::Finished("/dev/sda".into()),
-            Message::Finished("/dev/sdb".into()),
+            Message::Failed(
+                "/dev/sdb".into(),
+                "error verifying disk: mismatch at byte 4096".into(),
+            ),
         ];
 
         let input = AllowStdIo::new(Cursor::new(SAMPLE));
@@ -34,15 +40,25 @@ fn ipc() {
         let mut stream = FramedRead::new(input, PopsicleDecoder::default());
 
         let mut expected_iter = expected.iter();
+        let mut lines = SAMPLE.split_inclusive(|&byte| byte == b'\n');
 
         let mut matched = 0;
         while let Some(message) = stream.next().await {
             let message = message.unwrap();
 
             assert_eq!(message, *expected_iter.next().unwrap());
+            assert_eq!(message.encode().as_bytes(), lines.next().unwrap());
             matched += 1;
         }
 
         assert_eq!(matched, expected.len());
     });
 }
+
+fn rate(bytes_per_second: u64, eta: Option<u64>, elapsed: u64) -> Rate {
+    Rate {
+        bytes_per_second,
+        eta: eta.map(Duration::from_secs),
+        elapsed: Duration::from_millis(elapsed),
+    }
+}
@@ -0,0 +1,23 @@
+use std::fs::{self, OpenOptions};
+
+#[test]
+fn lock() {
+    let path = std::env::temp_dir().join(format!("popsicle-{}-lock", std::process::id()));
+    let open = || {
+        OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).unwrap()
+    };
+
+    let first = open();
+    popsicle::lock_disk(&first).unwrap();
+
+    // The lock belongs to the open file, so opening the disk again does not share it.
+    let second = open();
+    let why = popsicle::lock_disk(&second).unwrap_err();
+    assert_eq!(why.raw_os_error(), Some(libc::EWOULDBLOCK));
+
+    drop(first);
+    popsicle::lock_disk(&second).unwrap();
+
+    drop(second);
+    fs::remove_file(path).unwrap();
+}
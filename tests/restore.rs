@@ -0,0 +1,108 @@
+mod common;
+
+use common::{events, temp, Log, Recorder};
+use futures::executor;
+use popsicle::{Filesystem, PartitionTable, Restore};
+use std::fs;
+
+const SIZE: usize = 64 * 1024 * 1024;
+
+/// Restores a file which holds an ISO 9660 signature, and returns what it then holds.
+fn restore(name: &str, table: PartitionTable, filesystem: Filesystem) -> Vec<u8> {
+    let path = temp(name);
+    let mut data = vec![0xFF; SIZE];
+    data[0x8001..0x8006].copy_from_slice(b"CD001");
+    fs::write(&path, data).unwrap();
+
+    let log = Log::default();
+    let mut restore = Restore::new(table, filesystem, "Popsicle".into());
+    let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+    restore.subscribe(file.into(), 0, Recorder { log: log.clone() });
+    executor::block_on(restore.process()).unwrap();
+
+    assert_eq!(events(&log, 0), ["Phase(Erasing)", "Phase(Writing)", "Done"]);
+
+    let data = fs::read(&path).unwrap();
+    fs::remove_file(path).unwrap();
+    data
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[test]
+fn restore_gpt_fat32() {
+    let data = restore("restore-fat32", PartitionTable::Gpt, Filesystem::Fat32);
+    assert!(data[0x8001..0x8006].iter().all(|&byte| byte == 0));
+
+    // The protective MBR covers the disk, and both GUID partition tables are intact.
+    assert_eq!(data[446 + 4], 0xEE);
+    assert_eq!(data[510..512], [0x55, 0xAA]);
+    let last = SIZE - 512;
+    for (header, entries) in [(512, 1024), (last, last - 16 * 1024)] {
+        assert_eq!(&data[header..header + 8], b"EFI PART");
+        let mut copy = data[header..header + 92].to_vec();
+        copy[16..20].fill(0);
+        assert_eq!(u32_at(&data, header + 16), crc32fast::hash(&copy));
+        let crc = crc32fast::hash(&data[entries..entries + 16 * 1024]);
+        assert_eq!(u32_at(&data, header + 88), crc);
+        assert_eq!(u32_at(&data, entries + 32), 2048);
+    }
+
+    let boot = &data[1024 * 1024..];
+    assert_eq!(&boot[71..82], b"POPSICLE   ");
+    assert_eq!(&boot[82..90], b"FAT32   ");
+    assert_eq!(boot[510..512], [0x55, 0xAA]);
+}
+
+#[test]
+fn restore_mbr_exfat() {
+    let data = restore("restore-exfat", PartitionTable::Mbr, Filesystem::Exfat);
+    assert_eq!(data[446 + 4], 0x07);
+    assert_eq!(u32_at(&data, 446 + 8), 2048);
+    assert_eq!(u32_at(&data, 446 + 12), (SIZE / 512 - 2048) as u32);
+
+    // The checksum of the boot region fills the sector which follows it.
+    let boot = &data[1024 * 1024..];
+    assert_eq!(&boot[3..11], b"EXFAT   ");
+    let sum = boot[..11 * 512]
+        .iter()
+        .enumerate()
+        .filter(|&(index, _)| !matches!(index, 106 | 107 | 112))
+        .fold(0u32, |sum, (_, &byte)| sum.rotate_right(1).wrapping_add(u32::from(byte)));
+    assert!(boot[11 * 512..12 * 512].chunks(4).all(|chunk| chunk == sum.to_le_bytes()));
+    assert_eq!(boot[..12 * 512], boot[12 * 512..24 * 512]);
+}
+
+#[test]
+fn restore_large_fat32() {
+    let path = temp("restore-large");
+    let file = fs::File::create(&path).unwrap();
+
+    // FAT32 cannot count the sectors of a partition of 3 TiB, which nothing is written to.
+    let size = 3 << 40;
+    file.set_len(size).unwrap();
+
+    let log = Log::default();
+    let mut restore = Restore::new(PartitionTable::Gpt, Filesystem::Fat32, String::new());
+    restore.subscribe(file.into(), 0, Recorder { log: log.clone() });
+    assert!(executor::block_on(restore.process()).is_err());
+
+    let metadata = fs::metadata(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(metadata.len(), size);
+    assert_eq!(std::os::unix::fs::MetadataExt::blocks(&metadata), 0);
+    assert_eq!(
+        events(&log, 0),
+        [format!("Failed(Restore(Size {{ filesystem: Fat32, size: {} }}))", size)]
+    );
+}
+
+#[test]
+fn restore_label() {
+    assert!(Filesystem::Fat32.check_label("USB DRIVE").is_ok());
+    assert!(Filesystem::Fat32.check_label("DATA.DRIVE").is_err());
+    assert!(Filesystem::Fat32.check_label("TWELVE CHARS").is_err());
+    assert!(Filesystem::Exfat.check_label("Données").is_ok());
+}
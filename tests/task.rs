@@ -0,0 +1,477 @@
+mod common;
+
+use common::{events, target, Log, Recorder};
+use futures::{executor, future::join, StreamExt};
+use popsicle::{
+    Bmap, CancelHandle, Checksum, DeviceId, DiskError, Erase, FlashEvent, Image, ImageError,
+    ImageId, Journal, Phase, PowerOff, Progress, Task, Validation,
+};
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// The size of the devices, which the sample image fills.
+const DEVICE: usize = 1024 * 1024;
+
+fn task(paths: &[&PathBuf], log: &Log, check: bool) -> Task<Recorder> {
+    let image = Image::new(File::open("tests/sample.img.xz").unwrap()).unwrap();
+    let mut task = Task::new(image, check);
+    task.window = 2;
+
+    for (device, path) in paths.iter().enumerate() {
+        let file = fs::OpenOptions::new().read(true).write(true).open(path).unwrap();
+        task.subscribe(file.into(), device, Recorder { log: log.clone() });
+    }
+
+    task
+}
+
+#[test]
+fn flash() {
+    let (a, b) = (target("flash-a", DEVICE), target("flash-b", DEVICE));
+    let log = Log::default();
+
+    // Only block devices have their partition tables read again.
+    let mut flash = task(&[&a, &b], &log, true);
+    flash.reread = true;
+    executor::block_on(flash.process(&mut [0u8; 4096])).unwrap();
+
+    let sample: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    for path in [a, b] {
+        assert!(fs::read(&path).unwrap() == sample, "{} was written incorrectly", path.display());
+        fs::remove_file(path).unwrap();
+    }
+
+    for device in 0..2 {
+        assert_eq!(
+            events(&log, device),
+            ["Phase(Writing)", "Phase(Syncing)", "Phase(Seeking)", "Phase(Verifying)", "Done"]
+        );
+    }
+}
+
+#[test]
+fn bmap_mismatch() {
+    for precheck in [true, false] {
+        let path = target(&format!("bmap-mismatch-{}", precheck), DEVICE);
+        let log = Log::default();
+
+        let mut bmap = Bmap::parse(include_str!("sample.img.bmap")).unwrap();
+        bmap.ranges[3].checksum = Some([0; 32]);
+
+        let mut image = Image::new(File::open("tests/sample.img.xz").unwrap()).unwrap();
+        image.set_bmap(bmap).unwrap();
+        let mut task = Task::new(image, false);
+        task.precheck = precheck;
+        task.reread = true;
+        let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        task.subscribe(file.into(), 0, Recorder { log: log.clone() });
+        assert!(executor::block_on(task.process(&mut [0u8; 4096])).is_err());
+
+        // When the image is checked before anything is written, the device is untouched.
+        // Otherwise it fails as soon as the range which does not match has been written,
+        // and it is never finished.
+        let written = fs::read(&path).unwrap().iter().any(|&byte| byte != 0xFF);
+        assert_eq!(written, !precheck);
+        fs::remove_file(path).unwrap();
+
+        let events = events(&log, 0);
+        let phase = if precheck { "Phase(Checking)" } else { "Phase(Writing)" };
+        assert_eq!(events[0], phase);
+        assert!(events[1].contains("block map checksum"), "{:?}", events);
+        assert_eq!(events.len(), 2);
+    }
+}
+
+#[test]
+fn hash() {
+    let digest = "631b84027d6b9e52b539c4e8373622d23032dfadc64d60af87339c9037e4f769";
+    let (checksum, expected) = Checksum::parse(digest).unwrap();
+    assert_eq!(checksum, Checksum::Sha256);
+
+    let path = target("hash", DEVICE);
+    let log = Log::default();
+
+    let mut flash = task(&[&path], &log, true);
+    flash.validation = Validation::Hash { checksum, expected: Some(expected.clone()) };
+    executor::block_on(flash.process(&mut [0u8; 4096])).unwrap();
+    assert_eq!(events(&log, 0).last().unwrap(), "Done");
+
+    let mut wrong = expected;
+    wrong[0] ^= 1;
+    let log = Log::default();
+
+    let mut flash = task(&[&path], &log, true);
+    flash.validation = Validation::Hash { checksum, expected: Some(wrong) };
+    let why = executor::block_on(flash.process(&mut [0u8; 4096])).unwrap_err();
+    assert!(matches!(why.downcast_ref::<ImageError>(), Some(ImageError::Checksum { .. })));
+    assert!(events(&log, 0).last().unwrap().starts_with("Failed(Image(Checksum"));
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn hash_bmap() {
+    use sha2::{Digest, Sha256};
+
+    // The published digest is of the whole image, where the unmapped blocks are zeroes.
+    let bmap = Bmap::parse(include_str!("sample.img.bmap")).unwrap();
+    let mut image = vec![0; 1024 * 1024];
+    for range in &bmap.ranges {
+        for i in range.start * bmap.block_size..(range.end + 1) * bmap.block_size {
+            image[i as usize] = (i % 251) as u8;
+        }
+    }
+
+    let expected = Sha256::digest(&image).to_vec();
+    let path = target("hash-bmap", DEVICE);
+    let log = Log::default();
+
+    let mut sample = Image::new(File::open("tests/sample.img.xz").unwrap()).unwrap();
+    sample.set_bmap(bmap).unwrap();
+    let mut flash = Task::new(sample, true);
+    flash.validation = Validation::Hash { checksum: Checksum::Sha256, expected: Some(expected) };
+    let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+    flash.subscribe(file.into(), 0, Recorder { log: log.clone() });
+    executor::block_on(flash.process(&mut [0u8; 4096])).unwrap();
+    assert_eq!(events(&log, 0).last().unwrap(), "Done");
+
+    // Only the mapped blocks were written, and they are what the device was checked against.
+    let data = fs::read(&path).unwrap();
+    assert!(data.iter().zip(&image).all(|(&device, &image)| device == image || device == 0xFF));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn stream() {
+    let path = target("stream", DEVICE);
+
+    let image = Image::new(File::open("tests/sample.img.xz").unwrap()).unwrap();
+    let mut task = Task::new(image, false);
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    let progress = task.event_progress();
+    task.subscribe(file.into(), 7, progress);
+
+    let stream = task.events().unwrap();
+    assert!(task.events().is_none());
+
+    let (result, events) =
+        executor::block_on(join(task.process(&mut [0u8; 4096]), stream.collect::<Vec<_>>()));
+    result.unwrap();
+    fs::remove_file(path).unwrap();
+
+    assert!(events.iter().all(|(device, _)| *device == 7));
+    assert!(matches!(events[0].1, FlashEvent::Phase(Phase::Writing)));
+    assert!(matches!(events.last().unwrap().1, FlashEvent::Done));
+
+    let last = events.iter().rev().find_map(|(_, event)| match event {
+        FlashEvent::Progress { position, .. } => Some(*position),
+        _ => None,
+    });
+    assert_eq!(last, Some(1024 * 1024));
+}
+
+#[test]
+fn cancel() {
+    let path = target("cancel", DEVICE);
+    let log = Log::default();
+
+    let task = task(&[&path], &log, false);
+    task.cancel.cancel();
+
+    let why = executor::block_on(task.process(&mut [0u8; 4096])).unwrap_err();
+    assert!(matches!(why.downcast_ref::<DiskError>(), Some(DiskError::Killed)));
+    assert_eq!(events(&log, 0), ["Cancelled"]);
+
+    assert!(fs::read(&path).unwrap().iter().all(|&byte| byte == 0xFF));
+    fs::remove_file(path).unwrap();
+}
+
+/// Flashes the device at `path`, resuming from `offset`.
+fn resume_from(path: &PathBuf, journal: &PathBuf, offset: u64, log: &Log) -> anyhow::Result<()> {
+    let image = ImageId::new("tests/sample.img.xz".as_ref()).unwrap();
+    let device = DeviceId { serial: "popsicle".into(), size: 1024 * 1024 };
+
+    let mut recorded = Journal::open(journal).unwrap();
+    recorded.record(&image, &device, offset).unwrap();
+
+    let mut flash = task(&[], log, true);
+    let file = fs::OpenOptions::new().read(true).write(true).open(path).unwrap();
+    flash.subscribe_identified(file.into(), 0, Recorder { log: log.clone() }, device.clone());
+    flash.journal(recorded, image.clone());
+    flash.resume = true;
+
+    let result = executor::block_on(flash.process(&mut [0u8; 4096]));
+    assert_eq!(Journal::open(journal).unwrap().offset(&image, &device), None);
+    result
+}
+
+#[test]
+fn resume() {
+    let sample: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let (path, journal) = (target("resume", DEVICE), target("resume.ron", DEVICE));
+    fs::remove_file(&journal).unwrap();
+
+    // The first half of the device was written before the task was interrupted.
+    let mut data = fs::read(&path).unwrap();
+    data[..512 * 1024].copy_from_slice(&sample[..512 * 1024]);
+    fs::write(&path, data).unwrap();
+
+    let log = Log::default();
+    resume_from(&path, &journal, 512 * 1024, &log).unwrap();
+    assert!(fs::read(&path).unwrap() == sample, "{} was resumed incorrectly", path.display());
+    assert_eq!(events(&log, 0).last().unwrap(), "Done");
+
+    // The device only holds the first quarter of what was recorded, so it is written again
+    // from the last block which was read back before that.
+    let mut data = vec![0xFF; 1024 * 1024];
+    data[..256 * 1024].copy_from_slice(&sample[..256 * 1024]);
+    fs::write(&path, data).unwrap();
+    let log = Log::default();
+    resume_from(&path, &journal, 512 * 1024, &log).unwrap();
+    assert!(fs::read(&path).unwrap() == sample, "{} was resumed incorrectly", path.display());
+    assert_eq!(events(&log, 0).last().unwrap(), "Done");
+
+    // It no longer holds any of it, so it is written from the start.
+    fs::write(&path, vec![0xFF; 1024 * 1024]).unwrap();
+    let log = Log::default();
+    resume_from(&path, &journal, 512 * 1024, &log).unwrap();
+    assert!(fs::read(&path).unwrap() == sample, "{} was resumed incorrectly", path.display());
+
+    fs::remove_file(path).unwrap();
+    fs::remove_file(journal.with_extension("ron.lock")).unwrap();
+    fs::remove_file(journal).unwrap();
+}
+
+#[test]
+fn journal_shared() {
+    let journal = target("shared.ron", DEVICE);
+    fs::remove_file(&journal).unwrap();
+
+    let image = ImageId::new("tests/sample.img.xz".as_ref()).unwrap();
+    let a = DeviceId { serial: "a".into(), size: 1024 * 1024 };
+    let b = DeviceId { serial: "b".into(), size: 1024 * 1024 };
+
+    // Each process keeps the checkpoints that the others recorded since it read the journal.
+    let (mut first, mut second) =
+        (Journal::open(&journal).unwrap(), Journal::open(&journal).unwrap());
+    first.record(&image, &a, 4096).unwrap();
+    second.record(&image, &b, 8192).unwrap();
+    first.remove(&image, &a).unwrap();
+
+    let recorded = Journal::open(&journal).unwrap();
+    assert_eq!(recorded.offset(&image, &a), None);
+    assert_eq!(recorded.offset(&image, &b), Some(8192));
+
+    fs::remove_file(journal.with_extension("ron.lock")).unwrap();
+    fs::remove_file(journal).unwrap();
+}
+
+/// Cancels the task once the device is written, before it is verified.
+struct Interrupter(CancelHandle);
+
+impl Progress for Interrupter {
+    type Device = usize;
+
+    fn event(&mut self, _device: &usize, event: FlashEvent) {
+        if let FlashEvent::Phase(Phase::Seeking) = event {
+            self.0.cancel();
+        }
+    }
+}
+
+#[test]
+fn checkpoints() {
+    let sample: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let (path, journal) = (target("checkpoints", DEVICE), target("checkpoints.ron", DEVICE));
+    fs::remove_file(&journal).unwrap();
+
+    let image = ImageId::new("tests/sample.img.xz".as_ref()).unwrap();
+    let device = DeviceId { serial: "popsicle".into(), size: 1024 * 1024 };
+
+    // A flash which is not resumed still records how far it got before it was interrupted.
+    let image_file = Image::new(File::open("tests/sample.img.xz").unwrap()).unwrap();
+    let mut flash = Task::new(image_file, true);
+    let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+    let interrupter = Interrupter(flash.cancel.clone());
+    flash.subscribe_identified(file.into(), 0, interrupter, device.clone());
+    flash.journal(Journal::open(&journal).unwrap(), image.clone());
+    assert!(executor::block_on(flash.process(&mut [0u8; 4096])).is_err());
+    assert_eq!(Journal::open(&journal).unwrap().offset(&image, &device), Some(1024 * 1024));
+
+    // Which the next flash resumes from, and forgets once it is done.
+    let log = Log::default();
+    let mut flash = task(&[], &log, true);
+    let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+    flash.subscribe_identified(file.into(), 0, Recorder { log: log.clone() }, device.clone());
+    flash.journal(Journal::open(&journal).unwrap(), image.clone());
+    flash.resume = true;
+    executor::block_on(flash.process(&mut [0u8; 4096])).unwrap();
+    assert!(fs::read(&path).unwrap() == sample, "{} was resumed incorrectly", path.display());
+    assert_eq!(Journal::open(&journal).unwrap().offset(&image, &device), None);
+
+    fs::remove_file(path).unwrap();
+    fs::remove_file(journal.with_extension("ron.lock")).unwrap();
+    fs::remove_file(journal).unwrap();
+}
+
+#[test]
+fn delta() {
+    let sample: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let path = target("delta", DEVICE);
+
+    // A device which was flashed with an image which differs by a byte.
+    let mut data = sample.clone();
+    data[100_000] ^= 1;
+    fs::write(&path, data).unwrap();
+
+    let log = Log::default();
+    let mut flash = task(&[&path], &log, true);
+    flash.delta = true;
+    executor::block_on(flash.process(&mut [0u8; 4096])).unwrap();
+
+    assert!(fs::read(&path).unwrap() == sample, "{} was written incorrectly", path.display());
+    assert_eq!(events(&log, 0)[..3], ["Phase(Writing)", "Rewritten(4096)", "Phase(Syncing)"]);
+    assert_eq!(events(&log, 0).last().unwrap(), "Done");
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn skip_zeroes() {
+    // An image with a run of zeroes in the middle, flashed over a device of 0xFF.
+    let mut sample: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    sample[256 * 1024..768 * 1024].fill(0);
+    let image = target("skip-zeroes.img", DEVICE);
+    fs::write(&image, &sample).unwrap();
+
+    // The zeroes are only skipped when the device is erased beforehand. Otherwise they are
+    // written, so either way they read back as zeroes, and are validated.
+    for erase in [Some(Erase::ZeroFill), None] {
+        let path = target("skip-zeroes", DEVICE);
+        let log = Log::default();
+
+        let mut task = Task::new(Image::new(File::open(&image).unwrap()).unwrap(), true);
+        task.skip_zeroes = true;
+        task.erase = erase;
+        let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        task.subscribe(file.into(), 0, Recorder { log: log.clone() });
+        executor::block_on(task.process(&mut [0u8; 4096])).unwrap();
+
+        assert!(fs::read(&path).unwrap() == sample, "{} was written incorrectly", path.display());
+        fs::remove_file(path).unwrap();
+
+        let phases = ["Phase(Writing)", "Phase(Syncing)", "Phase(Seeking)", "Phase(Verifying)"];
+        let erasing = erase.map(|_| "Phase(Erasing)");
+        let expected: Vec<&str> = erasing.into_iter().chain(phases).chain(["Done"]).collect();
+        assert_eq!(events(&log, 0), expected);
+    }
+
+    fs::remove_file(image).unwrap();
+}
+
+#[test]
+fn resume_zeroes() {
+    // An image with a run of zeroes past where the device is resumed from.
+    let mut sample: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    sample[512 * 1024..768 * 1024].fill(0);
+    let image = target("resume-zeroes.img", DEVICE);
+    fs::write(&image, &sample).unwrap();
+
+    let (path, journal) = (target("resume-zeroes", DEVICE), target("resume-zeroes.ron", DEVICE));
+    fs::remove_file(&journal).unwrap();
+    let mut data = fs::read(&path).unwrap();
+    data[..256 * 1024].copy_from_slice(&sample[..256 * 1024]);
+    fs::write(&path, data).unwrap();
+
+    let image_id = ImageId::new(&image).unwrap();
+    let device = DeviceId { serial: "popsicle".into(), size: 1024 * 1024 };
+    let mut recorded = Journal::open(&journal).unwrap();
+    recorded.record(&image_id, &device, 256 * 1024).unwrap();
+
+    // The device is resumed rather than erased, so the zeroes are written to it all the
+    // same, even without validation to catch them.
+    let log = Log::default();
+    let mut task = Task::new(Image::new(File::open(&image).unwrap()).unwrap(), false);
+    task.erase = Some(Erase::ZeroFill);
+    task.skip_zeroes = true;
+    task.resume = true;
+    let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+    task.subscribe_identified(file.into(), 0, Recorder { log: log.clone() }, device);
+    task.journal(recorded, image_id);
+    executor::block_on(task.process(&mut [0u8; 4096])).unwrap();
+    assert!(fs::read(&path).unwrap() == sample, "{} was resumed incorrectly", path.display());
+
+    fs::remove_file(path).unwrap();
+    fs::remove_file(image).unwrap();
+    fs::remove_file(journal.with_extension("ron.lock")).unwrap();
+    fs::remove_file(journal).unwrap();
+}
+
+/// Records the disks which are powered off, and refuses to power off the second.
+struct Ejector(Arc<Mutex<Vec<PathBuf>>>);
+
+impl PowerOff for Ejector {
+    fn power_off(&self, disk: &Path) -> io::Result<()> {
+        let mut ejected = self.0.lock().unwrap();
+        if disk.to_string_lossy().ends_with("eject-b") {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+
+        ejected.push(disk.to_path_buf());
+        Ok(())
+    }
+}
+
+#[test]
+fn eject() {
+    let (a, b) = (target("eject-a", DEVICE), target("eject-b", DEVICE));
+    let log = Log::default();
+    let ejected = Arc::default();
+
+    let mut flash = task(&[&a, &b], &log, false);
+    flash.eject = Some(Arc::new(Ejector(Arc::clone(&ejected))));
+    executor::block_on(flash.process(&mut [0u8; 4096])).unwrap();
+
+    assert_eq!(*ejected.lock().unwrap(), [a.as_path()]);
+    assert_eq!(
+        events(&log, 0),
+        ["Phase(Writing)", "Phase(Syncing)", "Phase(Ejecting)", "Ejected", "Done"]
+    );
+    assert!(events(&log, 1)[3].starts_with("EjectFailed(Eject"));
+    assert_eq!(events(&log, 1).last().unwrap(), "Done");
+
+    for path in [a, b] {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn clone() {
+    let master = target("clone-master", DEVICE);
+    let sample: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    fs::write(&master, &sample).unwrap();
+    let a = target("clone-a", DEVICE);
+    let log = Log::default();
+
+    // The master is read as a raw image of its whole size, whatever it holds.
+    let image = Image::from_device(File::open(&master).unwrap()).unwrap();
+    assert_eq!(image.size(), Some(sample.len() as u64));
+
+    let mut task = Task::new(image, true);
+    for (device, path) in [&a, &master].into_iter().enumerate() {
+        let file = fs::OpenOptions::new().read(true).write(true).open(path).unwrap();
+        task.subscribe(file.into(), device, Recorder { log: log.clone() });
+    }
+
+    executor::block_on(task.process(&mut [0u8; 4096])).unwrap();
+
+    // The master is refused as a target, and is left as it was.
+    assert_eq!(events(&log, 1), ["Failed(Source)"]);
+    for path in [a, master] {
+        assert!(fs::read(&path).unwrap() == sample, "{} differs from the master", path.display());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use common::{events, target, Log, Recorder};
+use futures::executor;
+use popsicle::{Image, Task};
+use std::{
+    fs::{self, File},
+    io,
+    os::unix::{
+        fs::{FileExt, PermissionsExt},
+        io::AsRawFd,
+    },
+};
+
+/// Changes the user whose permissions the process is checked against, in every thread.
+fn seteuid(uid: libc::uid_t) {
+    assert_eq!(unsafe { libc::setresuid(libc::uid_t::MAX, uid, libc::uid_t::MAX) }, 0);
+}
+
+// This is the only test of its binary, as it changes the user of the whole process.
+#[test]
+fn verify_unopenable() {
+    let path = target("unopenable", 1024 * 1024);
+    let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+    let written = file.try_clone().unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o000)).unwrap();
+
+    let image = Image::new(File::open("tests/sample.img.xz").unwrap()).unwrap();
+
+    // Devices which udisks opened may not be opened again by the user it opened them for.
+    let root = unsafe { libc::geteuid() } == 0;
+    if root {
+        seteuid(65534);
+    }
+
+    let reopened = File::open(format!("/proc/self/fd/{}", file.as_raw_fd()));
+    let denied = reopened.map(drop).map_err(|why| why.kind());
+
+    let log = Log::default();
+    let mut task = Task::new(image, true);
+    task.subscribe(file.into(), 0, Recorder { log: log.clone() });
+    let result = executor::block_on(task.process(&mut [0u8; 4096]));
+
+    if root {
+        seteuid(0);
+    }
+
+    let mut read = vec![0; 1024 * 1024];
+    written.read_exact_at(&mut read, 0).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(denied, Err(io::ErrorKind::PermissionDenied));
+    result.unwrap();
+    assert_eq!(
+        events(&log, 0),
+        ["Phase(Writing)", "Phase(Syncing)", "Phase(Seeking)", "Phase(Verifying)", "Done"]
+    );
+
+    let sample: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    assert!(read == sample, "the device was written incorrectly");
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::{events, target, Log, Recorder};
+use futures::executor;
+use popsicle::{Wipe, WipeMethod};
+use std::{fs, path::PathBuf};
+
+/// Wipes each of the files, and returns what they were left holding.
+fn wipe(paths: &[&PathBuf], method: WipeMethod, log: &Log) -> Vec<Vec<u8>> {
+    let mut wipe = Wipe::new(method, true);
+    for (device, path) in paths.iter().enumerate() {
+        let file = fs::OpenOptions::new().read(true).write(true).open(path).unwrap();
+        wipe.subscribe(file.into(), device, Recorder { log: log.clone() });
+    }
+
+    executor::block_on(wipe.process()).unwrap();
+    paths.iter().map(|path| fs::read(path).unwrap()).collect()
+}
+
+#[test]
+fn wipe_devices() {
+    let (a, b) = (target("wipe-a", 6 * 1024 * 1024), target("wipe-b", 6 * 1024 * 1024));
+
+    // Files cannot be discarded, so they are written with zeroes instead.
+    let log = Log::default();
+    for data in wipe(&[&a, &b], WipeMethod::Discard, &log) {
+        assert!(data.len() == 6 * 1024 * 1024 && data.iter().all(|&byte| byte == 0));
+    }
+
+    for device in 0..2 {
+        assert_eq!(events(&log, device), ["Phase(Erasing)", "Phase(Verifying)", "Done"]);
+    }
+
+    // Each device is given different random data.
+    let log = Log::default();
+    let random = wipe(&[&a, &b], WipeMethod::Random, &log);
+    assert!(random[0] != random[1]);
+    assert!(random[0].iter().filter(|&&byte| byte == 0).count() < 64 * 1024);
+    assert_eq!(log.lock().unwrap().last().unwrap().1, "Done");
+
+    for path in [a, b] {
+        fs::remove_file(path).unwrap();
+    }
+}